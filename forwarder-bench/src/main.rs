@@ -1,5 +1,8 @@
 use anyhow::Context;
-use forwarder::uri::{Protocol, Uri};
+use forwarder::{
+    config::{ForwarderConfig, DEFAULT_BATCH_SIZE},
+    uri::{Protocol, Uri},
+};
 use socket2::{Domain, Type};
 use std::{
     mem::MaybeUninit,
@@ -29,41 +32,52 @@ const SERVER_THREAD_COUNT: usize = 5;
 const BENCHMARK_DURATION: Duration = Duration::from_secs(10);
 
 fn main() -> anyhow::Result<()> {
-    let args = std::env::args();
-    let protocol = if args.len() == 2 {
-        let protocol_name = args.last().unwrap();
-        Protocol::from_str(&protocol_name)
-            .with_context(|| format!("cannot parse protocol name '{protocol_name}'"))?
-    } else {
-        Protocol::Udp
+    let mut args = std::env::args().skip(1);
+    let protocol = match args.next() {
+        Some(protocol_name) => Protocol::from_str(&protocol_name)
+            .with_context(|| format!("cannot parse protocol name '{protocol_name}'"))?,
+        None => Protocol::Udp,
+    };
+    let workers = match args.next() {
+        Some(workers) => workers
+            .parse()
+            .with_context(|| format!("cannot parse workers count '{workers}'"))?,
+        None => 1,
+    };
+    let batch_size = match args.next() {
+        Some(batch_size) => batch_size
+            .parse()
+            .with_context(|| format!("cannot parse batch size '{batch_size}'"))?,
+        None => DEFAULT_BATCH_SIZE,
     };
 
     let forwarder_uri = Uri::from_str("127.0.0.1:38701/udp")?;
-    let second_forwarder_uri = Uri {
-        addr: "127.0.0.1:38702".parse()?,
-        protocol,
-    };
+    let second_forwarder_uri = Uri::new("127.0.0.1:38702".parse()?, protocol);
     let remote_uri = Uri::from_str("127.0.0.1:38703/udp")?;
-
-    std::thread::spawn(move || {
-        forwarder::run(forwarder_uri, second_forwarder_uri, None).unwrap();
-    });
-    std::thread::spawn(move || {
-        forwarder::run(second_forwarder_uri, remote_uri, None).unwrap();
-    });
+    let (forwarder_addr, remote_addr) = (forwarder_uri.addr, remote_uri.addr);
+
+    let first_config = ForwarderConfig::builder(forwarder_uri, second_forwarder_uri)
+        .workers(workers)
+        .batch_size(batch_size)
+        .build()?;
+    let second_config = ForwarderConfig::builder(second_forwarder_uri, remote_uri)
+        .workers(workers)
+        .batch_size(batch_size)
+        .build()?;
+    std::thread::spawn(move || forwarder::run_with_config(first_config).unwrap());
+    std::thread::spawn(move || forwarder::run_with_config(second_config).unwrap());
 
     let remote_received_packet_count = Arc::new(AtomicU32::new(0));
     for _ in 0..SERVER_THREAD_COUNT {
         let remote_received_packet_count = remote_received_packet_count.clone();
         std::thread::spawn(move || {
-            server_thread(remote_uri.addr, remote_received_packet_count);
+            server_thread(remote_addr, remote_received_packet_count);
         });
     }
 
     let client_sent_packet_count = Arc::new(AtomicU32::new(0));
     let client_received_packet_count = Arc::new(AtomicU32::new(0));
     for _ in 0..CLIENTS_COUNT {
-        let forwarder_addr = forwarder_uri.addr;
         let client_packet_count = client_sent_packet_count.clone();
         let client_received_packet_count = client_received_packet_count.clone();
         std::thread::spawn(move || {
@@ -75,12 +89,20 @@ fn main() -> anyhow::Result<()> {
         });
     }
 
-    println!("benchmarking {protocol} protocol...");
+    println!(
+        "benchmarking {protocol} protocol with {workers} workers and batch size of {batch_size}..."
+    );
     std::thread::sleep(BENCHMARK_DURATION);
     let client_sent_pc = client_sent_packet_count.load(Ordering::Relaxed);
     let client_received_pc = client_received_packet_count.load(Ordering::Relaxed);
     println!("packets client sent: {client_sent_pc}");
     println!("packets client received: {client_received_pc}");
+    let seconds = BENCHMARK_DURATION.as_secs_f32();
+    println!(
+        "packets per second: {:.0} sent, {:.0} received",
+        client_sent_pc as f32 / seconds,
+        client_received_pc as f32 / seconds
+    );
     let diff = client_sent_pc.abs_diff(client_received_pc);
     let packet_lost = diff as f32 * 100.0 / client_sent_pc as f32;
     println!("\ndiff: {diff}");
@@ -0,0 +1,154 @@
+use anyhow::{bail, Context};
+use clap::{Args, Subcommand};
+use serde::Deserialize;
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::SocketAddr,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// maximum time that forwarder can take to answer a command
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// sends a command to control socket of a running forwarder
+#[derive(Args)]
+pub struct CtlArgs {
+    /// Path of control socket that forwarder is started with by '--control-socket'
+    #[arg(short, long)]
+    pub socket: PathBuf,
+
+    /// Print the json response of forwarder instead of a table
+    #[arg(long)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Subcommand)]
+pub enum CtlCommand {
+    /// List clients with their local port, idle time and forwarded bytes
+    Peers,
+    /// Remove peer of a client, next packet of client creates a new peer
+    Kick { client_addr: SocketAddr },
+    /// Clean idle peers now instead of waiting for the next cleanup
+    Cleanup,
+}
+
+/// response of forwarder, fields that a command doesn't have are left empty
+#[derive(Deserialize)]
+struct Response {
+    ok: bool,
+    #[serde(default)]
+    error: String,
+    #[serde(default)]
+    peers: Vec<PeerInfo>,
+    #[serde(default)]
+    cleaned: usize,
+    #[serde(default)]
+    remaining: usize,
+}
+
+#[derive(Deserialize)]
+struct PeerInfo {
+    client_addr: String,
+    local_port: u16,
+    remote_index: usize,
+    idle_ms: u64,
+    bytes: ForwardedBytes,
+}
+
+#[derive(Deserialize)]
+struct ForwardedBytes {
+    client_to_remote: u64,
+    remote_to_client: u64,
+}
+
+pub fn run(args: CtlArgs) -> anyhow::Result<()> {
+    let command = match args.command {
+        CtlCommand::Peers => "peers".to_owned(),
+        CtlCommand::Kick { client_addr } => format!("kick {client_addr}"),
+        CtlCommand::Cleanup => "cleanup".to_owned(),
+    };
+    let line = request(&args.socket, &command).with_context(|| {
+        format!(
+            "couldn't talk to control socket '{}'",
+            args.socket.display()
+        )
+    })?;
+    let response: Response =
+        serde_json::from_str(&line).with_context(|| "invalid response of forwarder")?;
+    if !response.ok {
+        bail!("{}", response.error);
+    }
+    if args.json {
+        println!("{}", line.trim_end());
+        return Ok(());
+    }
+    match args.command {
+        CtlCommand::Peers => print!("{}", format_peers(&response.peers)),
+        CtlCommand::Kick { client_addr } => println!("kicked peer of '{client_addr}'"),
+        CtlCommand::Cleanup => println!(
+            "cleaned {} peers, {} remaining",
+            response.cleaned, response.remaining
+        ),
+    }
+    Ok(())
+}
+
+/// sends `command` and returns the line that forwarder answered with
+fn request(path: &Path, command: &str) -> anyhow::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    writeln!(stream, "{command}")?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    Ok(line)
+}
+
+fn format_peers(peers: &[PeerInfo]) -> String {
+    let mut output = format!(
+        "{:<47} {:>10} {:>6} {:>10} {:>16} {:>16}\n",
+        "CLIENT", "LOCAL PORT", "REMOTE", "IDLE", "CLIENT TO REMOTE", "REMOTE TO CLIENT"
+    );
+    for peer in peers {
+        let idle = format!("{:.1}s", peer.idle_ms as f64 / 1000.0);
+        // writing to `String` never fails
+        writeln!(
+            output,
+            "{:<47} {:>10} {:>6} {idle:>10} {:>16} {:>16}",
+            peer.client_addr,
+            peer.local_port,
+            peer.remote_index,
+            peer.bytes.client_to_remote,
+            peer.bytes.remote_to_client
+        )
+        .unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_printed_as_table() {
+        let response: Response = serde_json::from_str(
+            r#"{"ok":true,"peers":[{"client_addr":"127.0.0.1:1000","local_port":40000,"remote_index":0,"idle_ms":1500,"bytes":{"client_to_remote":10,"remote_to_client":20}}]}"#,
+        )
+        .unwrap();
+        let lines: Vec<Vec<String>> = format_peers(&response.peers)
+            .lines()
+            .map(|line| line.split_whitespace().map(str::to_owned).collect())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1],
+            ["127.0.0.1:1000", "40000", "0", "1.5s", "10", "20"]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use crate::rule::{DerivedKeys, RuleArgs};
+use anyhow::{ensure, Context};
+use forwarder::config::ForwarderConfig;
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::Path};
+
+/// config file that has many forwarders in it, each rule is a table with the same
+/// options as the long command line arguments:
+/// ```toml
+/// [rules.wireguard]
+/// listen-uri = "0.0.0.0:1001"
+/// remote-uri = ["127.0.0.1:1002"]
+/// passphrase = "some_secret"
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    rules: BTreeMap<String, toml::Value>,
+}
+
+/// rule of config file and the forwarder config that is built from it
+pub struct Rule {
+    pub name: String,
+    /// options of rule as they are written in file, so changed rules can be found on reload
+    pub source: toml::Value,
+    pub config: ForwarderConfig,
+}
+
+/// reads config file at `path` and returns its rules in order of their names, keys of
+/// rules are taken from `keys` when their passphrase and salt didn't change
+pub fn load(path: &Path, keys: &mut DerivedKeys) -> anyhow::Result<Vec<Rule>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("couldn't read config file '{}'", path.display()))?;
+    keys.start_load();
+    let rules = parse(&content, keys)
+        .with_context(|| format!("invalid config file '{}'", path.display()))?;
+    keys.finish_load();
+    Ok(rules)
+}
+
+fn parse(content: &str, keys: &mut DerivedKeys) -> anyhow::Result<Vec<Rule>> {
+    let file: ConfigFile = toml::from_str(content)?;
+    ensure!(!file.rules.is_empty(), "there is no rule");
+    file.rules
+        .into_iter()
+        .map(|(name, source)| {
+            let config = RuleArgs::deserialize(source.clone())
+                .map_err(anyhow::Error::from)
+                .and_then(|args| args.build_config(keys))
+                .with_context(|| format!("invalid rule '{name}'"))?;
+            Ok(Rule {
+                name,
+                source,
+                config,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn error_of(content: &str) -> String {
+        format!(
+            "{:#}",
+            parse(content, &mut DerivedKeys::default()).err().unwrap()
+        )
+    }
+
+    #[test]
+    fn rules_are_parsed() {
+        let rules = parse(
+            r#"
+            [rules.first]
+            listen-uri = "127.0.0.1:1001"
+            remote-uri = ["127.0.0.1:1002", "127.0.0.1:1003"]
+            passphrase = "some_secret"
+            cipher = "chacha20-poly1305"
+            handshake = true
+            max-peers = 10
+            peer-upload-bytes = "1000:2000"
+            allow = ["10.0.0.0/8"]
+
+            [rules.second]
+            listen-uri = "127.0.0.1:1004/tcp"
+            remote-uri = ["127.0.0.1:1005/tcp"]
+            "#,
+            &mut DerivedKeys::default(),
+        )
+        .unwrap();
+        let names: Vec<&str> = rules.iter().map(|rule| rule.name.as_str()).collect();
+        assert_eq!(names, ["first", "second"]);
+        let first = &rules[0].config;
+        assert_eq!(first.remote_uris().len(), 2);
+        assert_eq!(first.max_peers(), Some(10));
+        assert!(first.handshake_side().is_some());
+        assert_eq!(rules[1].config.workers(), 1);
+    }
+
+    #[test]
+    fn errors_name_the_rule_and_field() {
+        let error = error_of(
+            r#"
+            [rules.broken]
+            listen-uri = "127.0.0.1:1001"
+            remote-uri = ["127.0.0.1:1002"]
+            cipher = "rot13"
+            "#,
+        );
+        assert!(error.contains("rule 'broken'"), "{error}");
+        assert!(error.contains("cipher"), "{error}");
+
+        let error = error_of(
+            r#"
+            [rules.typo]
+            listen-uri = "127.0.0.1:1001"
+            remote-uri = ["127.0.0.1:1002"]
+            max-peer = 10
+            "#,
+        );
+        assert!(error.contains("rule 'typo'"), "{error}");
+        assert!(error.contains("max-peer"), "{error}");
+
+        let error = error_of(
+            r#"
+            [rules.no_remote]
+            listen-uri = "127.0.0.1:1001"
+            "#,
+        );
+        assert!(error.contains("rule 'no_remote'"), "{error}");
+        assert!(error.contains("remote-uri"), "{error}");
+
+        let error = error_of(
+            r#"
+            [rules.no_passphrase]
+            listen-uri = "127.0.0.1:1001"
+            remote-uri = ["127.0.0.1:1002"]
+            handshake = true
+            "#,
+        );
+        assert!(error.contains("'handshake' needs 'passphrase'"), "{error}");
+
+        assert!(error_of("").contains("rules"));
+        assert!(error_of("[rules]").contains("no rule"));
+    }
+
+    #[test]
+    fn options_need_the_options_they_depend_on() {
+        let rule = |options: &str| {
+            format!(
+                "[rules.dependent]\nlisten-uri = \"127.0.0.1:1001\"\nremote-uri = [\"127.0.0.1:1002\"]\n{options}"
+            )
+        };
+        for (options, message) in [
+            (
+                "cipher = \"chacha20-poly1305\"",
+                "'cipher' needs 'passphrase'",
+            ),
+            (
+                "encrypted-side = \"listen\"",
+                "'encrypted-side' needs 'passphrase'",
+            ),
+            (
+                "passphrase = \"some_secret\"\nsalt = \"other salt\"",
+                "'salt' needs 'kdf'",
+            ),
+            (
+                "passphrase = \"some_secret\"\npadding-mtu = 1400",
+                "'padding-mtu' needs 'padding'",
+            ),
+            (
+                "new-peer-burst = 10",
+                "'new-peer-burst' needs 'new-peer-rate'",
+            ),
+            (
+                "reconnect-peers = true",
+                "'reconnect-peers' needs 'resolve-interval'",
+            ),
+        ] {
+            let error = error_of(&rule(options));
+            assert!(error.contains(message), "{error}");
+        }
+        // command line arguments are checked the same way
+        let args = RuleArgs::parse_from([
+            "forwarder",
+            "-l",
+            "127.0.0.1:1001",
+            "-r",
+            "127.0.0.1:1002",
+            "--padding-distribution",
+            "exponential",
+        ]);
+        let error = format!(
+            "{:#}",
+            args.build_config(&mut DerivedKeys::default())
+                .err()
+                .unwrap()
+        );
+        assert!(
+            error.contains("'padding-distribution' needs 'padding'"),
+            "{error}"
+        );
+        let rule = rule("new-peer-rate = 5\nnew-peer-burst = 10");
+        assert!(parse(&rule, &mut DerivedKeys::default()).is_ok());
+    }
+}
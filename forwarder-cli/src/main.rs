@@ -1,34 +1,187 @@
+mod ctl;
+mod file;
+mod rule;
+
 use anyhow::Context;
-use clap::Parser;
-use log::{info, LevelFilter};
+use clap::{ArgGroup, Parser, Subcommand};
+use ctl::CtlArgs;
+use file::Rule;
+use forwarder::ForwarderHandle;
+use log::{error, info, LevelFilter};
+use rule::{DerivedKeys, RuleArgs};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
 use simple_logger::SimpleLogger;
-use std::{env, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashSet},
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// how often running rules are checked for SIGHUP and for the ones that stopped
+const RULES_CHECK_INTERVAL: Duration = Duration::from_millis(200);
 
-/// Lightweight UDP forwarder and UDP over ICMP
+/// Lightweight UDP forwarder and UDP over ICMP or TCP
 #[derive(Parser)]
-#[command(about)]
+#[command(
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    group(ArgGroup::new("rules").required(true).args(["listen_uri", "config"]))
+)]
 pub struct Args {
-    /// Address and protocol that forwarder will listen on
-    #[arg(short, long)]
-    pub listen_uri: forwarder::uri::Uri,
+    #[command(subcommand)]
+    pub command: Option<Command>,
 
-    /// Address and protocol of remote server that forwarder will forward to
-    #[arg(short, long)]
-    pub remote_uri: forwarder::uri::Uri,
+    /// Run all rules of this toml file in one process instead of the forwarder that is
+    /// described by the other arguments, the file is read again on SIGHUP
+    #[arg(long, conflicts_with_all = ["listen_uri", "remote_uri"])]
+    pub config: Option<PathBuf>,
 
-    /// The packets will get encrypted/decrypted by this passphrase
-    #[arg(short, long)]
-    pub passphrase: Option<String>,
+    #[command(flatten)]
+    pub rule: RuleArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Send a command to control socket of a running forwarder
+    Ctl(CtlArgs),
 }
 
 fn main() -> anyhow::Result<()> {
     let cli = Args::parse();
+    if let Some(Command::Ctl(args)) = cli.command {
+        return ctl::run(args);
+    }
     setup_logger().with_context(|| "couldn't setup logger")?;
     log_version();
-    forwarder::run(cli.listen_uri, cli.remote_uri, cli.passphrase)?;
+    let Some(path) = cli.config else {
+        let config = cli
+            .rule
+            .build_config(&mut DerivedKeys::default())
+            .with_context(|| "invalid config")?;
+        forwarder::run_with_config(config)?;
+        return Ok(());
+    };
+
+    run_rules(&path)
+}
+
+/// forwarder of a rule of config file that is running
+struct RunningRule {
+    source: toml::Value,
+    handle: ForwarderHandle,
+}
+
+/// runs rules of config file at `path` until all of them stop, config file is read
+/// again on SIGHUP and the rules that changed get applied
+fn run_rules(path: &Path) -> anyhow::Result<()> {
+    // registered before rules start so an early SIGHUP doesn't kill the process
+    let mut signals = Signals::new([SIGHUP]).with_context(|| "couldn't listen for SIGHUP")?;
+    let mut running = BTreeMap::new();
+    // passphrases are only stretched again when they or their salt change
+    let mut keys = DerivedKeys::default();
+    for rule in file::load(path, &mut keys)? {
+        let name = rule.name.clone();
+        if let Err(error) = start_rule(&mut running, rule) {
+            for (_, rule) in running {
+                stop_rule(rule);
+            }
+            return Err(error).with_context(|| format!("couldn't start rule '{name}'"));
+        }
+    }
+
+    let mut result = Ok(());
+    while !running.is_empty() {
+        if signals.pending().next().is_some() {
+            info!("reloading config file '{}'", path.display());
+            match file::load(path, &mut keys) {
+                Ok(rules) => reload_rules(&mut running, rules),
+                Err(error) => error!("couldn't reload config, old rules keep running: {error:?}"),
+            }
+        }
+        // rules run on their own, one of them stopping doesn't stop the others
+        let stopped: Vec<String> = running
+            .iter()
+            .filter(|(_, rule)| rule.handle.is_stopped())
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in stopped {
+            let rule = running.remove(&name).unwrap();
+            let rule_result = rule
+                .handle
+                .join()
+                .with_context(|| format!("rule '{name}' stopped"));
+            if result.is_ok() {
+                result = rule_result;
+            }
+        }
+        std::thread::sleep(RULES_CHECK_INTERVAL);
+    }
+    result
+}
+
+/// applies `rules` of config file that is read again, removed rules are stopped and
+/// new ones are started, changed rules are reloaded so their peers are kept unless
+/// they changed something that needs a restart
+fn reload_rules(running: &mut BTreeMap<String, RunningRule>, rules: Vec<Rule>) {
+    // stopped first so new rules can use their addresses
+    let names: HashSet<&str> = rules.iter().map(|rule| rule.name.as_str()).collect();
+    let removed: Vec<String> = running
+        .keys()
+        .filter(|name| !names.contains(name.as_str()))
+        .cloned()
+        .collect();
+    for name in removed {
+        info!("stopping rule '{name}', it's removed from config");
+        stop_rule(running.remove(&name).unwrap());
+    }
+
+    for rule in rules {
+        let name = rule.name.clone();
+        let Some(current) = running.get_mut(&name) else {
+            if let Err(error) = start_rule(running, rule) {
+                error!("couldn't start rule '{name}': {error:?}");
+            }
+            continue;
+        };
+        if current.source == rule.source {
+            continue;
+        }
+        if let Some(setting) = current.handle.needs_restart(&rule.config) {
+            info!("restarting rule '{name}', its {setting} changed");
+            stop_rule(running.remove(&name).unwrap());
+            if let Err(error) = start_rule(running, rule) {
+                error!("couldn't restart rule '{name}': {error:?}");
+            }
+            continue;
+        }
+        info!("reloading rule '{name}'");
+        match current.handle.reload(rule.config) {
+            Ok(()) => current.source = rule.source,
+            Err(error) => {
+                error!("couldn't reload rule '{name}', it keeps its old config: {error:?}")
+            }
+        }
+    }
+}
+
+fn start_rule(running: &mut BTreeMap<String, RunningRule>, rule: Rule) -> anyhow::Result<()> {
+    info!("starting rule '{}'", rule.name);
+    let handle = forwarder::start(rule.config)?;
+    let source = rule.source;
+    running.insert(rule.name, RunningRule { source, handle });
     Ok(())
 }
 
+fn stop_rule(rule: RunningRule) {
+    rule.handle.shutdown();
+    if let Err(error) = rule.handle.join() {
+        error!("rule stopped with error: {error:?}");
+    }
+}
+
 fn setup_logger() -> anyhow::Result<()> {
     let log_level = match env::var("RUST_LOG") {
         Ok(var) => LevelFilter::from_str(&var)?,
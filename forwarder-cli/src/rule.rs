@@ -0,0 +1,533 @@
+use anyhow::{ensure, Context};
+use clap::Parser;
+use forwarder::{
+    acl::Cidr,
+    config::ForwarderConfig,
+    encryption::{ChaCha20Poly1305, Cipher},
+    kdf::{MasterKey, DEFAULT_SALT},
+    limit::{MaxPeersPolicy, Rate, TrafficLimit},
+    padding::{self, Padding, PaddingDistribution},
+    remote::RemotePolicy,
+    socket::{IcmpMode, SocketOptions},
+    transform::{Side, Transform},
+    Direction,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
+
+/// options of one forwarder, they are either command line arguments or a rule of config
+/// file that has the same names as the long arguments
+#[derive(Parser, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RuleArgs {
+    /// Address and protocol that forwarder will listen on
+    #[arg(short, long, requires = "remote_uri")]
+    #[serde(deserialize_with = "from_str::option")]
+    pub listen_uri: Option<forwarder::uri::Uri>,
+
+    /// Address and protocol of remote server that forwarder will forward to, it can be
+    /// repeated to have backup remotes or to balance clients between them
+    #[arg(short, long)]
+    #[serde(deserialize_with = "from_str::vec")]
+    pub remote_uri: Vec<forwarder::uri::Uri>,
+
+    /// Policy that picks a remote for each new client when there are multiple remotes,
+    /// either 'failover', 'round-robin' or 'hash'
+    #[arg(long, default_value = "failover")]
+    #[serde(deserialize_with = "from_str::value")]
+    pub remote_policy: RemotePolicy,
+
+    /// Seconds that a remote can leave packets unanswered before it gets skipped for new clients
+    #[arg(long)]
+    pub remote_health_timeout: Option<u64>,
+
+    /// The packets will get encrypted/decrypted by this passphrase
+    #[arg(short, long)]
+    pub passphrase: Option<String>,
+
+    /// Cipher that is used for encrypting packets, either 'xor' or 'chacha20-poly1305'
+    #[arg(short, long, default_value = "xor")]
+    #[serde(deserialize_with = "from_str::value")]
+    pub cipher: Cipher,
+
+    /// Side that carries encrypted packets, either 'listen' or 'remote', the client side
+    /// forwarder uses 'remote' and the server side forwarder uses 'listen'
+    #[arg(short, long, default_value = "remote")]
+    #[serde(deserialize_with = "from_str::value")]
+    pub encrypted_side: Side,
+
+    /// Stretch passphrase with argon2 into the keys of cipher and handshake, xor also gets
+    /// a new keystream for each packet, it needs to be set on both forwarders
+    #[arg(long)]
+    pub kdf: bool,
+
+    /// Salt of key derivation, both forwarders need the same salt
+    #[arg(long, default_value = DEFAULT_SALT)]
+    pub salt: String,
+
+    /// Forwarders prove that they know the passphrase with a handshake before a client gets
+    /// a peer, it happens on encrypted side so it needs to be set on both forwarders
+    #[arg(long)]
+    pub handshake: bool,
+
+    /// Side that carries address of the original client in PROXY protocol v2 header, 'remote'
+    /// adds the header to packets that are sent to remote and 'listen' strips it from packets
+    /// of an upstream forwarder and checks the client in it with access list, it can be repeated
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::vec")]
+    pub proxy_protocol: Vec<Side>,
+
+    /// Put a sequence number in each encrypted packet so replayed packets get dropped, it
+    /// only works with 'chacha20-poly1305' cipher and needs to be set on both forwarders
+    #[arg(long)]
+    pub replay_protection: bool,
+
+    /// Add up to this many random bytes to each encrypted packet so packets can't be
+    /// recognized by their size, it needs to be set on both forwarders
+    #[arg(long)]
+    pub padding: Option<usize>,
+
+    /// How length of padding is picked, either 'uniform' or 'exponential' that prefers
+    /// short paddings
+    #[arg(long, default_value = "uniform")]
+    #[serde(deserialize_with = "from_str::value")]
+    pub padding_distribution: PaddingDistribution,
+
+    /// Packets are not padded to more than this mtu, headers of ip and the protocol of the
+    /// encrypted side and encryption overhead are subtracted from it
+    #[arg(long, default_value_t = 1500)]
+    pub padding_mtu: usize,
+
+    /// Seconds that a client can be idle before its peer gets cleaned
+    #[arg(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Seconds that packets of remote keep the peer alive, defaults to idle timeout
+    #[arg(long)]
+    pub remote_idle_timeout: Option<u64>,
+
+    /// Number of threads that receive packets of clients
+    #[arg(long, default_value_t = 1)]
+    pub workers: usize,
+
+    /// Maximum number of packets that are received or sent with one syscall
+    #[arg(long, default_value_t = forwarder::config::DEFAULT_BATCH_SIZE)]
+    pub batch_size: usize,
+
+    /// Let kernel coalesce udp packets with GSO and GRO, the parts that kernel doesn't
+    /// support stay disabled
+    #[arg(long)]
+    pub udp_offload: bool,
+
+    /// Maximum number of clients that can be served at the same time
+    #[arg(long)]
+    pub max_peers: Option<usize>,
+
+    /// What happens to new clients when max peers is reached, either 'drop-new' or
+    /// 'evict-lru' that removes the peer that has been idle the longest
+    #[arg(long, default_value = "drop-new")]
+    #[serde(deserialize_with = "from_str::value")]
+    pub max_peers_policy: MaxPeersPolicy,
+
+    /// Maximum number of peers that are created for new clients each second
+    #[arg(long)]
+    pub new_peer_rate: Option<u64>,
+
+    /// Number of peers that can be created at once before new peer rate kicks in,
+    /// defaults to new peer rate
+    #[arg(long)]
+    pub new_peer_burst: Option<u64>,
+
+    /// Maximum number of peers that are created for new clients of each ip each second
+    #[arg(long)]
+    pub new_peer_rate_per_ip: Option<u64>,
+
+    /// Same as new peer burst but for each ip, defaults to new peer rate per ip
+    #[arg(long)]
+    pub new_peer_burst_per_ip: Option<u64>,
+
+    /// Bytes per second that each client can send, like '125000' or '125000:250000'
+    /// that the second number is the burst
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::option")]
+    pub peer_upload_bytes: Option<Rate>,
+
+    /// Packets per second that each client can send, with optional burst like bytes
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::option")]
+    pub peer_upload_packets: Option<Rate>,
+
+    /// Bytes per second that each client can receive from remote
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::option")]
+    pub peer_download_bytes: Option<Rate>,
+
+    /// Packets per second that each client can receive from remote
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::option")]
+    pub peer_download_packets: Option<Rate>,
+
+    /// Bytes per second that all clients together can send
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::option")]
+    pub global_upload_bytes: Option<Rate>,
+
+    /// Packets per second that all clients together can send
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::option")]
+    pub global_upload_packets: Option<Rate>,
+
+    /// Bytes per second that all clients together can receive from remote
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::option")]
+    pub global_download_bytes: Option<Rate>,
+
+    /// Packets per second that all clients together can receive from remote
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::option")]
+    pub global_download_packets: Option<Rate>,
+
+    /// Only clients in this range like '10.0.0.0/8' or '::1' are forwarded, it can be
+    /// repeated and all clients are allowed if it's not set
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::vec")]
+    pub allow: Vec<Cidr>,
+
+    /// Clients in this range are never forwarded even if they are allowed, it can be repeated
+    #[arg(long)]
+    #[serde(deserialize_with = "from_str::vec")]
+    pub deny: Vec<Cidr>,
+
+    /// Log packets of denied clients, at most once a second
+    #[arg(long)]
+    pub log_denied: bool,
+
+    /// How icmp packets are exchanged, either 'request' or 'reply', use 'reply' on both
+    /// forwarders when client side is behind NAT
+    #[arg(long, default_value = "request")]
+    #[serde(deserialize_with = "from_str::value")]
+    pub icmp_mode: IcmpMode,
+    /// Seconds between resolving hostname of remote uri again, it's only resolved once if not set
+    #[arg(long)]
+    pub resolve_interval: Option<u64>,
+
+    /// Move existing clients to the new address of remote when its hostname resolves to it
+    #[arg(long)]
+    pub reconnect_peers: bool,
+
+    /// Address that metrics are served on in prometheus format at '/metrics'
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Path of unix socket that 'forwarder ctl' lists and kicks clients through
+    #[arg(long)]
+    pub control_socket: Option<PathBuf>,
+}
+
+impl Default for RuleArgs {
+    /// same defaults as the command line arguments
+    fn default() -> Self {
+        Self::parse_from(["forwarder"])
+    }
+}
+
+/// keys that are derived from passphrase and salt of rules, so rules that keep their
+/// passphrase don't stretch it again each time config file is reloaded
+#[derive(Default)]
+pub struct DerivedKeys {
+    keys: HashMap<(String, String), MasterKey>,
+    /// keys of the previous load, the ones that aren't used again are forgotten
+    previous: HashMap<(String, String), MasterKey>,
+}
+
+impl DerivedKeys {
+    /// starts a new load of config file, keys that the load doesn't use again are
+    /// forgotten when it finishes
+    pub fn start_load(&mut self) {
+        let keys = std::mem::take(&mut self.keys);
+        self.previous.extend(keys);
+    }
+
+    /// finishes a load that succeeded, keys that it didn't use belong to removed rules
+    pub fn finish_load(&mut self) {
+        self.previous.clear();
+    }
+
+    fn derive(&mut self, passphrase: &str, salt: &str) -> anyhow::Result<MasterKey> {
+        let id = (passphrase.to_owned(), salt.to_owned());
+        if let Some(key) = self.keys.get(&id) {
+            return Ok(key.clone());
+        }
+        let key = match self.previous.remove(&id) {
+            Some(key) => key,
+            None => MasterKey::derive(passphrase, salt)?,
+        };
+        self.keys.insert(id, key.clone());
+        Ok(key)
+    }
+}
+
+fn traffic_limit(bytes: Option<Rate>, packets: Option<Rate>) -> TrafficLimit {
+    TrafficLimit { bytes, packets }
+}
+
+impl RuleArgs {
+    /// checks that options which only matter with another option aren't set without it,
+    /// clap doesn't check rules of config files so both of them are checked here, options
+    /// that have a default value count as set when they are different from it
+    fn check_requirements(&self) -> anyhow::Result<()> {
+        let defaults = Self::default();
+        let passphrase = ("passphrase", self.passphrase.is_some());
+        let padding = ("padding", self.padding.is_some());
+        let requirements = [
+            ("cipher", self.cipher != defaults.cipher, passphrase),
+            (
+                "encrypted-side",
+                self.encrypted_side != defaults.encrypted_side,
+                passphrase,
+            ),
+            ("kdf", self.kdf, passphrase),
+            ("salt", self.salt != defaults.salt, ("kdf", self.kdf)),
+            ("handshake", self.handshake, passphrase),
+            ("replay-protection", self.replay_protection, passphrase),
+            ("padding", self.padding.is_some(), passphrase),
+            (
+                "padding-distribution",
+                self.padding_distribution != defaults.padding_distribution,
+                padding,
+            ),
+            (
+                "padding-mtu",
+                self.padding_mtu != defaults.padding_mtu,
+                padding,
+            ),
+            (
+                "new-peer-burst",
+                self.new_peer_burst.is_some(),
+                ("new-peer-rate", self.new_peer_rate.is_some()),
+            ),
+            (
+                "new-peer-burst-per-ip",
+                self.new_peer_burst_per_ip.is_some(),
+                ("new-peer-rate-per-ip", self.new_peer_rate_per_ip.is_some()),
+            ),
+            (
+                "reconnect-peers",
+                self.reconnect_peers,
+                ("resolve-interval", self.resolve_interval.is_some()),
+            ),
+        ];
+        for (name, is_set, (required, is_required_set)) in requirements {
+            ensure!(
+                !is_set || is_required_set,
+                "'{name}' needs '{required}' to be set"
+            );
+        }
+        Ok(())
+    }
+
+    pub fn build_config(self, keys: &mut DerivedKeys) -> anyhow::Result<ForwarderConfig> {
+        self.check_requirements()?;
+        let listen_uri = self
+            .listen_uri
+            .with_context(|| "'listen-uri' needs to be set")?;
+        let mut remote_uris = self.remote_uri.into_iter();
+        let remote_uri = remote_uris
+            .next()
+            .with_context(|| "'remote-uri' needs at least one uri")?;
+        // padding is only on the encrypted side so only its headers matter
+        let encrypted_uri = match self.encrypted_side {
+            Side::Listen => listen_uri,
+            Side::Remote => remote_uri,
+        };
+        let mut builder = ForwarderConfig::builder(listen_uri, remote_uri)
+            .remote_policy(self.remote_policy)
+            .workers(self.workers)
+            .batch_size(self.batch_size)
+            .log_denied(self.log_denied);
+        for remote_uri in remote_uris {
+            builder = builder.remote(remote_uri);
+        }
+        if let Some(remote_health_timeout) = self.remote_health_timeout {
+            builder = builder.remote_health_timeout(Duration::from_secs(remote_health_timeout));
+        }
+        if let Some(ref passphrase) = self.passphrase {
+            let key = match self.kdf {
+                true => Some(keys.derive(passphrase, &self.salt)?),
+                false => None,
+            };
+            let encryption: Box<dyn Transform> = if self.replay_protection {
+                ensure!(
+                    self.cipher == Cipher::ChaCha20Poly1305,
+                    "replay protection needs 'chacha20-poly1305' cipher"
+                );
+                let encryption = match key {
+                    Some(ref key) => ChaCha20Poly1305::with_key(key),
+                    None => ChaCha20Poly1305::new(passphrase),
+                };
+                Box::new(encryption.with_replay_protection())
+            } else {
+                match key {
+                    Some(ref key) => self.cipher.new_keyed_transform(key),
+                    None => self.cipher.new_transform(passphrase),
+                }
+            };
+            if let Some(max_padding) = self.padding {
+                // padding goes before encryption so its length gets encrypted too
+                let headers_len =
+                    padding::headers_len(encrypted_uri.protocol, encrypted_uri.addr.is_ipv6());
+                let max_packet_size = self
+                    .padding_mtu
+                    .saturating_sub(headers_len + encryption.max_overhead());
+                let padding = Padding::new(max_padding, self.padding_distribution)
+                    .with_max_packet_size(max_packet_size);
+                builder = builder.transform(self.encrypted_side, Box::new(padding));
+            }
+            builder = builder.transform(self.encrypted_side, encryption);
+            if self.handshake {
+                builder = match key {
+                    Some(ref key) => builder.handshake_with_key(self.encrypted_side, key),
+                    None => builder.handshake(self.encrypted_side, passphrase),
+                };
+            }
+        }
+        for side in self.proxy_protocol {
+            builder = builder.proxy_protocol(side);
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            builder = builder.idle_timeout(Duration::from_secs(idle_timeout));
+        }
+        if let Some(remote_idle_timeout) = self.remote_idle_timeout {
+            builder = builder.remote_idle_timeout(Duration::from_secs(remote_idle_timeout));
+        }
+        if let Some(max_peers) = self.max_peers {
+            builder = builder.max_peers(max_peers);
+        }
+        builder = builder.max_peers_policy(self.max_peers_policy);
+        if let Some(rate) = self.new_peer_rate {
+            let burst = self.new_peer_burst.unwrap_or(rate);
+            builder = builder.new_peer_rate(Rate::new(rate, burst));
+        }
+        if let Some(rate) = self.new_peer_rate_per_ip {
+            let burst = self.new_peer_burst_per_ip.unwrap_or(rate);
+            builder = builder.new_peer_rate_per_ip(Rate::new(rate, burst));
+        }
+        let upload = Direction::ClientToRemote;
+        let download = Direction::RemoteToClient;
+        builder = builder
+            .peer_limit(
+                upload,
+                traffic_limit(self.peer_upload_bytes, self.peer_upload_packets),
+            )
+            .peer_limit(
+                download,
+                traffic_limit(self.peer_download_bytes, self.peer_download_packets),
+            )
+            .global_limit(
+                upload,
+                traffic_limit(self.global_upload_bytes, self.global_upload_packets),
+            )
+            .global_limit(
+                download,
+                traffic_limit(self.global_download_bytes, self.global_download_packets),
+            );
+        for cidr in self.allow {
+            builder = builder.allow(cidr);
+        }
+        for cidr in self.deny {
+            builder = builder.deny(cidr);
+        }
+        if let Some(resolve_interval) = self.resolve_interval {
+            builder = builder
+                .resolve_interval(Duration::from_secs(resolve_interval))
+                .reconnect_peers(self.reconnect_peers);
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            builder = builder.metrics_addr(metrics_addr);
+        }
+        if let Some(control_socket) = self.control_socket {
+            builder = builder.control_socket(control_socket);
+        }
+        builder = builder.socket_options(SocketOptions {
+            icmp_mode: self.icmp_mode,
+            udp_offload: self.udp_offload,
+            ..Default::default()
+        });
+        builder.build()
+    }
+}
+
+/// deserializers of config file values that are parsed with `FromStr` like clap does
+mod from_str {
+    use serde::{de::Error, Deserialize, Deserializer};
+    use std::{fmt::Display, str::FromStr};
+
+    fn parse<T, E>(value: &str) -> Result<T, E>
+    where
+        T: FromStr,
+        T::Err: Display,
+        E: Error,
+    {
+        T::from_str(value).map_err(|error| E::custom(format!("invalid value '{value}': {error:#}")))
+    }
+
+    pub fn value<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        parse(&String::deserialize(deserializer)?)
+    }
+
+    pub fn option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        value(deserializer).map(Some)
+    }
+
+    pub fn vec<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|value| parse(value))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_derived_once_and_forgotten_when_unused() {
+        let mut keys = DerivedKeys::default();
+        keys.start_load();
+        keys.derive("first", DEFAULT_SALT).unwrap();
+        keys.derive("second", DEFAULT_SALT).unwrap();
+        keys.derive("first", DEFAULT_SALT).unwrap();
+        keys.finish_load();
+        assert_eq!(keys.keys.len(), 2);
+
+        // failed load keeps the keys of running rules
+        keys.start_load();
+        keys.derive("first", DEFAULT_SALT).unwrap();
+        assert_eq!(keys.previous.len(), 1);
+        keys.start_load();
+        assert_eq!(keys.previous.len(), 2);
+        keys.derive("first", DEFAULT_SALT).unwrap();
+        keys.finish_load();
+        assert!(keys
+            .keys
+            .contains_key(&("first".to_owned(), DEFAULT_SALT.to_owned())));
+        assert_eq!(keys.keys.len(), 1);
+        assert!(keys.previous.is_empty());
+    }
+}
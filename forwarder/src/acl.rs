@@ -0,0 +1,224 @@
+use crate::clock;
+use anyhow::{ensure, Context};
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// denied clients are logged at most once in this duration so a flood of
+/// packets doesn't flood the logs too
+const DENIED_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// range of ip addresses in CIDR notation, a plain address is a range with only itself
+///
+/// # Examples
+/// ```
+/// use forwarder::acl::Cidr;
+/// use std::str::FromStr;
+///
+/// let cidr = Cidr::from_str("10.0.0.0/8")?;
+/// assert!(cidr.contains(&"10.1.2.3".parse()?));
+/// assert!(!cidr.contains(&"11.0.0.1".parse()?));
+/// assert!(Cidr::from_str("::1")?.contains(&"::1".parse()?));
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// creates a range of addresses that their first `prefix_len` bits are same as `addr`
+    pub fn new(addr: IpAddr, prefix_len: u8) -> anyhow::Result<Self> {
+        let max_prefix_len = max_prefix_len(&addr);
+        ensure!(
+            prefix_len <= max_prefix_len,
+            "prefix length of '{addr}' needs to be at most {max_prefix_len}"
+        );
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // clients of dual stack sockets have ipv4 mapped addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => prefix_eq(
+                addr.to_bits().into(),
+                ip.to_bits().into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                prefix_eq(addr.to_bits(), ip.to_bits(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr).with_context(|| format!("invalid address '{addr}'"))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => u8::from_str(prefix_len)
+                .with_context(|| format!("invalid prefix length '{prefix_len}'"))?,
+            None => max_prefix_len(&addr),
+        };
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// checks that first `prefix_len` bits of two addresses of `bits` size are same
+fn prefix_eq(a: u128, b: u128, bits: u32, prefix_len: u8) -> bool {
+    let shift = bits - prefix_len as u32;
+    // shifting by the whole width overflows
+    shift == bits || a >> shift == b >> shift
+}
+
+/// decides which clients can make forwarder create a peer for them, clients in deny
+/// list are always denied and when allow list is not empty only clients in it are allowed
+#[derive(Debug, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    log_denied: bool,
+    /// last time in `clock::now_millis` that a denied client got logged
+    last_log: AtomicU64,
+    /// denied packets that weren't logged since the last log
+    unlogged: AtomicU64,
+}
+
+impl AccessList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(&mut self, cidr: Cidr) {
+        self.allow.push(cidr);
+    }
+
+    pub fn deny(&mut self, cidr: Cidr) {
+        self.deny.push(cidr);
+    }
+
+    /// logs denied clients, at most once every `DENIED_LOG_INTERVAL`
+    pub fn set_log_denied(&mut self, log_denied: bool) {
+        self.log_denied = log_denied;
+    }
+
+    pub fn allow_list(&self) -> &[Cidr] {
+        &self.allow
+    }
+
+    pub fn deny_list(&self) -> &[Cidr] {
+        &self.deny
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// same as `is_allowed` but also logs the denied clients if it's enabled
+    pub(crate) fn check_client(&self, client_addr: &SocketAddr) -> bool {
+        if self.is_allowed(&client_addr.ip()) {
+            return true;
+        }
+        if self.log_denied {
+            self.log_denied_client(client_addr);
+        }
+        false
+    }
+
+    fn log_denied_client(&self, client_addr: &SocketAddr) {
+        let now = clock::now_millis();
+        let last_log = self.last_log.load(Ordering::Relaxed);
+        // another worker may have stored a later time since `now` was taken
+        let is_due =
+            last_log == 0 || now.saturating_sub(last_log) >= DENIED_LOG_INTERVAL.as_millis() as u64;
+        // only one of the workers logs when the interval passes
+        if !is_due
+            || self
+                .last_log
+                .compare_exchange(last_log, now.max(1), Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            self.unlogged.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        match self.unlogged.swap(0, Ordering::Relaxed) {
+            0 => log::info!("denied packet of client '{client_addr}'"),
+            unlogged => log::info!(
+                "denied packet of client '{client_addr}', {unlogged} more denied packets weren't logged"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        Cidr::from_str(s).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn cidr_contains_addresses_in_its_prefix() {
+        assert!(cidr("192.168.1.0/24").contains(&ip("192.168.1.200")));
+        assert!(!cidr("192.168.1.0/24").contains(&ip("192.168.2.1")));
+        assert!(cidr("0.0.0.0/0").contains(&ip("8.8.8.8")));
+        assert!(!cidr("0.0.0.0/0").contains(&ip("::1")));
+        assert!(cidr("10.0.0.1").contains(&ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1").contains(&ip("10.0.0.2")));
+        assert!(cidr("10.0.0.0/8").contains(&ip("::ffff:10.1.1.1")));
+        assert!(cidr("fd00::/8").contains(&ip("fd12::1")));
+        assert!(!cidr("fd00::/8").contains(&ip("fe80::1")));
+        assert!(cidr("::/0").contains(&ip("2001:db8::1")));
+
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("::/129").is_err());
+        assert!(Cidr::from_str("10.0.0.0/").is_err());
+        assert!(Cidr::from_str("example.org/8").is_err());
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+    }
+
+    #[test]
+    fn deny_list_has_priority_over_allow_list() {
+        let mut access_list = AccessList::new();
+        assert!(access_list.is_allowed(&ip("1.1.1.1")));
+
+        access_list.allow(cidr("10.0.0.0/8"));
+        access_list.deny(cidr("10.0.0.0/24"));
+        assert!(access_list.is_allowed(&ip("10.1.0.1")));
+        assert!(!access_list.is_allowed(&ip("10.0.0.1")));
+        assert!(!access_list.is_allowed(&ip("1.1.1.1")));
+    }
+}
@@ -0,0 +1,586 @@
+use crate::{
+    acl::{AccessList, Cidr},
+    handshake::Handshake,
+    kdf::MasterKey,
+    limit::{MaxPeersPolicy, NewPeerLimiter, Rate, Shaper, TrafficLimit},
+    metrics::Direction,
+    remote::RemotePolicy,
+    resolver::{Resolver, SystemResolver},
+    socket::{SocketOptions, MAX_BATCH_SIZE},
+    transform::{Side, Transform, Transforms},
+    uri::Uri,
+    MAX_PACKET_SIZE,
+};
+use anyhow::ensure;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+/// peers that are not used for this duration get cleaned
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(7 * 60);
+
+/// remotes that don't answer for this duration are skipped for new peers
+pub const DEFAULT_REMOTE_HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// maximum number of poll events that are handled in one wake up
+pub const DEFAULT_POLL_EVENTS_CAPACITY: usize = 1024;
+
+/// maximum number of packets that are received or sent with one syscall
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+/// configuration of a forwarder, it's created by `ForwarderConfig::builder`
+///
+/// # Examples
+/// ```
+/// use forwarder::{config::ForwarderConfig, uri::Uri};
+/// use std::{str::FromStr, time::Duration};
+///
+/// let config = ForwarderConfig::builder(
+///     Uri::from_str("127.0.0.1:8000/udp")?,
+///     Uri::from_str("127.0.0.1:9000/udp")?,
+/// )
+/// .idle_timeout(Duration::from_secs(60))
+/// .max_peers(100)
+/// .build()?;
+/// assert_eq!(config.client_idle_timeout(), Duration::from_secs(60));
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct ForwarderConfig {
+    pub(crate) listen_uri: Uri,
+    /// remotes in order of priority, there is at least one remote
+    pub(crate) remote_uris: Vec<Uri>,
+    pub(crate) remote_policy: RemotePolicy,
+    pub(crate) remote_health_timeout: Duration,
+    pub(crate) transforms: Transforms,
+    pub(crate) handshake: Option<Handshake>,
+    /// packets of clients start with proxy protocol header of an upstream forwarder
+    pub(crate) proxy_protocol_listen: bool,
+    /// packets that are sent to remote start with proxy protocol header
+    pub(crate) proxy_protocol_remote: bool,
+    pub(crate) client_idle_timeout: Duration,
+    pub(crate) remote_idle_timeout: Duration,
+    pub(crate) buffer_size: usize,
+    pub(crate) poll_events_capacity: usize,
+    pub(crate) batch_size: usize,
+    /// number of threads that receive packets of clients
+    pub(crate) workers: usize,
+    pub(crate) max_peers: Option<usize>,
+    pub(crate) max_peers_policy: MaxPeersPolicy,
+    pub(crate) new_peer_limiter: NewPeerLimiter,
+    /// limits of traffic of each peer in each `Direction`
+    pub(crate) peer_limits: [TrafficLimit; 2],
+    /// limits of traffic of all peers together in each `Direction`
+    pub(crate) global_shapers: [Shaper; 2],
+    pub(crate) access_list: AccessList,
+    pub(crate) socket_options: SocketOptions,
+    pub(crate) resolver: Arc<dyn Resolver>,
+    pub(crate) resolve_interval: Option<Duration>,
+    pub(crate) reconnect_peers: bool,
+    pub(crate) metrics_addr: Option<SocketAddr>,
+    pub(crate) control_socket: Option<PathBuf>,
+}
+
+impl ForwarderConfig {
+    /// creates a builder for forwarder that listens on `listen_uri` and forwards to `remote_uri`
+    pub fn builder(listen_uri: Uri, remote_uri: Uri) -> ForwarderConfigBuilder {
+        ForwarderConfigBuilder {
+            config: ForwarderConfig {
+                listen_uri,
+                remote_uris: vec![remote_uri],
+                remote_policy: RemotePolicy::default(),
+                remote_health_timeout: DEFAULT_REMOTE_HEALTH_TIMEOUT,
+                transforms: Transforms::new(),
+                handshake: None,
+                proxy_protocol_listen: false,
+                proxy_protocol_remote: false,
+                client_idle_timeout: DEFAULT_IDLE_TIMEOUT,
+                remote_idle_timeout: DEFAULT_IDLE_TIMEOUT,
+                buffer_size: MAX_PACKET_SIZE,
+                poll_events_capacity: DEFAULT_POLL_EVENTS_CAPACITY,
+                batch_size: DEFAULT_BATCH_SIZE,
+                workers: 1,
+                max_peers: None,
+                max_peers_policy: MaxPeersPolicy::default(),
+                new_peer_limiter: NewPeerLimiter::new(),
+                peer_limits: Default::default(),
+                global_shapers: Default::default(),
+                access_list: AccessList::new(),
+                socket_options: SocketOptions::default(),
+                resolver: Arc::new(SystemResolver),
+                resolve_interval: None,
+                reconnect_peers: false,
+                metrics_addr: None,
+                control_socket: None,
+            },
+        }
+    }
+
+    pub fn listen_uri(&self) -> &Uri {
+        &self.listen_uri
+    }
+
+    /// returns the first remote
+    pub fn remote_uri(&self) -> &Uri {
+        &self.remote_uris[0]
+    }
+
+    pub fn remote_uris(&self) -> &[Uri] {
+        &self.remote_uris
+    }
+
+    pub fn remote_policy(&self) -> RemotePolicy {
+        self.remote_policy
+    }
+
+    /// returns the side that handshake happens on if it's enabled
+    pub fn handshake_side(&self) -> Option<Side> {
+        self.handshake.as_ref().map(Handshake::side)
+    }
+
+    /// returns whether packets of `side` carry proxy protocol header
+    pub fn proxy_protocol(&self, side: Side) -> bool {
+        match side {
+            Side::Listen => self.proxy_protocol_listen,
+            Side::Remote => self.proxy_protocol_remote,
+        }
+    }
+
+    pub fn client_idle_timeout(&self) -> Duration {
+        self.client_idle_timeout
+    }
+
+    pub fn remote_idle_timeout(&self) -> Duration {
+        self.remote_idle_timeout
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn max_peers(&self) -> Option<usize> {
+        self.max_peers
+    }
+
+    pub fn max_peers_policy(&self) -> MaxPeersPolicy {
+        self.max_peers_policy
+    }
+
+    pub fn new_peer_limiter(&self) -> &NewPeerLimiter {
+        &self.new_peer_limiter
+    }
+
+    pub fn peer_limit(&self, direction: Direction) -> TrafficLimit {
+        self.peer_limits[direction as usize]
+    }
+
+    pub fn global_limit(&self, direction: Direction) -> TrafficLimit {
+        self.global_shapers[direction as usize].limit()
+    }
+
+    pub fn access_list(&self) -> &AccessList {
+        &self.access_list
+    }
+
+    pub fn resolve_interval(&self) -> Option<Duration> {
+        self.resolve_interval
+    }
+
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    pub fn control_socket(&self) -> Option<&Path> {
+        self.control_socket.as_deref()
+    }
+}
+
+pub struct ForwarderConfigBuilder {
+    config: ForwarderConfig,
+}
+
+impl ForwarderConfigBuilder {
+    /// replaces all transforms with `transforms`
+    pub fn transforms(mut self, transforms: Transforms) -> Self {
+        self.config.transforms = transforms;
+        self
+    }
+
+    /// appends `transform` to the end of transform chain of `side`
+    pub fn transform(mut self, side: Side, transform: Box<dyn Transform>) -> Self {
+        self.config.transforms.push(side, transform);
+        self
+    }
+
+    /// forwarders prove that they know `passphrase` with a handshake before a peer is
+    /// created, on remote side peers send a hello to remote before their packets and
+    /// on listen side peers are only created for clients that send a valid hello
+    pub fn handshake(mut self, side: Side, passphrase: &str) -> Self {
+        self.config.handshake = Some(Handshake::new(side, passphrase));
+        self
+    }
+
+    /// same as `handshake` but the key is derived from `key`
+    pub fn handshake_with_key(mut self, side: Side, key: &MasterKey) -> Self {
+        self.config.handshake = Some(Handshake::with_key(side, key));
+        self
+    }
+
+    /// carries address of the original client with proxy protocol v2 header on `side`, on
+    /// remote side each packet that is sent to remote starts with the header, on listen
+    /// side the header is stripped from packets of clients and the client in it is
+    /// checked by access list and passed on, it can be set for both sides
+    pub fn proxy_protocol(mut self, side: Side) -> Self {
+        match side {
+            Side::Listen => self.config.proxy_protocol_listen = true,
+            Side::Remote => self.config.proxy_protocol_remote = true,
+        }
+        self
+    }
+
+    /// adds another remote after the existing ones, the order is
+    /// the priority of remotes in failover policy
+    pub fn remote(mut self, remote_uri: Uri) -> Self {
+        self.config.remote_uris.push(remote_uri);
+        self
+    }
+
+    /// policy that picks a remote for each new client
+    pub fn remote_policy(mut self, remote_policy: RemotePolicy) -> Self {
+        self.config.remote_policy = remote_policy;
+        self
+    }
+
+    /// remotes that packets are sent to them for `remote_health_timeout` without
+    /// getting anything back become unhealthy and get skipped for new peers
+    pub fn remote_health_timeout(mut self, remote_health_timeout: Duration) -> Self {
+        self.config.remote_health_timeout = remote_health_timeout;
+        self
+    }
+
+    /// peers that neither client nor remote sent anything to them for
+    /// `idle_timeout` get cleaned
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.config.client_idle_timeout = idle_timeout;
+        self.config.remote_idle_timeout = idle_timeout;
+        self
+    }
+
+    /// packets of client keep its peer alive for `client_idle_timeout`, peer
+    /// gets cleaned when both client and remote idle timeouts are passed
+    pub fn client_idle_timeout(mut self, client_idle_timeout: Duration) -> Self {
+        self.config.client_idle_timeout = client_idle_timeout;
+        self
+    }
+
+    /// packets of remote keep the peer alive for `remote_idle_timeout`, zero
+    /// means only packets of client keep the peer alive
+    pub fn remote_idle_timeout(mut self, remote_idle_timeout: Duration) -> Self {
+        self.config.remote_idle_timeout = remote_idle_timeout;
+        self
+    }
+
+    /// size of buffers that packets are received in, bigger packets get truncated
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.config.buffer_size = buffer_size;
+        self
+    }
+
+    /// maximum number of poll events that are handled in one wake up
+    pub fn poll_events_capacity(mut self, poll_events_capacity: usize) -> Self {
+        self.config.poll_events_capacity = poll_events_capacity;
+        self
+    }
+
+    /// maximum number of packets that are received or sent with one syscall, udp
+    /// sockets use `recvmmsg` and `sendmmsg` and raw icmp sockets use `recvmmsg`
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.config.batch_size = batch_size;
+        self
+    }
+
+    /// number of threads that receive packets of clients, on udp each of them gets its own
+    /// socket with `SO_REUSEPORT` and kernel spreads clients between them, other
+    /// protocols share one socket between workers
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers;
+        self
+    }
+
+    /// maximum number of peers that can exist at the same time, `max_peers_policy`
+    /// decides what happens to new clients when there are this much peers
+    pub fn max_peers(mut self, max_peers: usize) -> Self {
+        self.config.max_peers = Some(max_peers);
+        self
+    }
+
+    /// what happens to new clients when max peers is reached, by default their packets
+    /// get dropped
+    pub fn max_peers_policy(mut self, max_peers_policy: MaxPeersPolicy) -> Self {
+        self.config.max_peers_policy = max_peers_policy;
+        self
+    }
+
+    /// limits how fast peers can be created for new clients, packets of new clients
+    /// that exceed it get dropped
+    pub fn new_peer_rate(mut self, rate: Rate) -> Self {
+        self.config.new_peer_limiter.set_global_rate(rate);
+        self
+    }
+
+    /// same as `new_peer_rate` but each source ip has its own limit
+    pub fn new_peer_rate_per_ip(mut self, rate: Rate) -> Self {
+        self.config.new_peer_limiter.set_per_ip_rate(rate);
+        self
+    }
+
+    /// limits bytes and packets per second that each peer can pass in `direction`,
+    /// packets that exceed it get dropped
+    pub fn peer_limit(mut self, direction: Direction, limit: TrafficLimit) -> Self {
+        self.config.peer_limits[direction as usize] = limit;
+        self
+    }
+
+    /// same as `peer_limit` but it limits traffic of all peers together
+    pub fn global_limit(mut self, direction: Direction, limit: TrafficLimit) -> Self {
+        self.config.global_shapers[direction as usize] = Shaper::new(limit);
+        self
+    }
+
+    /// only clients in allowed ranges can make forwarder create a peer for them, all
+    /// clients are allowed if no range is allowed
+    pub fn allow(mut self, cidr: Cidr) -> Self {
+        self.config.access_list.allow(cidr);
+        self
+    }
+
+    /// packets of clients in denied ranges are dropped, even if they are in an allowed range
+    pub fn deny(mut self, cidr: Cidr) -> Self {
+        self.config.access_list.deny(cidr);
+        self
+    }
+
+    /// logs packets of denied clients, logs are rate limited so a denied client
+    /// that floods forwarder doesn't flood the logs
+    pub fn log_denied(mut self, log_denied: bool) -> Self {
+        self.config.access_list.set_log_denied(log_denied);
+        self
+    }
+
+    /// options that are applied on listen socket and peer sockets
+    pub fn socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.config.socket_options = socket_options;
+        self
+    }
+
+    /// resolver that hostnames of uris get resolved by when forwarder starts
+    /// and when remote hostname gets re-resolved
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.config.resolver = resolver;
+        self
+    }
+
+    /// hostnames of remote uris get resolved again every `resolve_interval` and
+    /// new peers connect to the new address if it changed
+    pub fn resolve_interval(mut self, resolve_interval: Duration) -> Self {
+        self.config.resolve_interval = Some(resolve_interval);
+        self
+    }
+
+    /// existing peers also get reconnected to the new address of remote when
+    /// it changes, peers that can't be reconnected get cleaned
+    pub fn reconnect_peers(mut self, reconnect_peers: bool) -> Self {
+        self.config.reconnect_peers = reconnect_peers;
+        self
+    }
+
+    /// serves metrics of forwarder in prometheus text format over http on `metrics_addr`
+    pub fn metrics_addr(mut self, metrics_addr: SocketAddr) -> Self {
+        self.config.metrics_addr = Some(metrics_addr);
+        self
+    }
+
+    /// serves commands that list and kick peers or clean them on a unix socket at `path`,
+    /// see `forwarder-cli ctl`
+    pub fn control_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.control_socket = Some(path.into());
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<ForwarderConfig> {
+        let config = self.config;
+        let first_remote = &config.remote_uris[0];
+        ensure!(
+            config
+                .remote_uris
+                .iter()
+                .all(|uri| uri.protocol == first_remote.protocol
+                    && uri.addr.is_ipv6() == first_remote.addr.is_ipv6()),
+            "all remotes need to have the same protocol and ip version"
+        );
+        ensure!(
+            !config.client_idle_timeout.is_zero() || !config.remote_idle_timeout.is_zero(),
+            "at least one of client or remote idle timeouts needs to be more than zero"
+        );
+        ensure!(
+            (1..=MAX_PACKET_SIZE).contains(&config.buffer_size),
+            "buffer size needs to be between 1 and {MAX_PACKET_SIZE}"
+        );
+        ensure!(
+            config.poll_events_capacity > 0,
+            "poll events capacity needs to be more than zero"
+        );
+        ensure!(
+            (1..=MAX_BATCH_SIZE).contains(&config.batch_size),
+            "batch size needs to be between 1 and {MAX_BATCH_SIZE}"
+        );
+        ensure!(config.workers > 0, "workers needs to be more than zero");
+        ensure!(
+            config.max_peers != Some(0),
+            "max peers needs to be more than zero"
+        );
+        let limiter = &config.new_peer_limiter;
+        for rate in [limiter.global_rate(), limiter.per_ip_rate()]
+            .into_iter()
+            .flatten()
+        {
+            ensure!(
+                rate.per_second > 0 && rate.burst > 0,
+                "new peer rate and its burst need to be more than zero"
+            );
+        }
+        let global_limits = config.global_shapers.iter().map(Shaper::limit);
+        for limit in config.peer_limits.iter().copied().chain(global_limits) {
+            for rate in [limit.bytes, limit.packets].into_iter().flatten() {
+                ensure!(
+                    rate.per_second > 0 && rate.burst > 0,
+                    "traffic limits and their burst need to be more than zero"
+                );
+            }
+        }
+        for (side, chain) in [
+            ("listen", &config.transforms.listen),
+            ("remote", &config.transforms.remote),
+        ] {
+            ensure!(
+                !chain.pads_after_encryption(),
+                "padding needs to come before encryption in {side} transforms so its length gets encrypted"
+            );
+        }
+        ensure!(
+            config.resolve_interval != Some(Duration::ZERO),
+            "resolve interval needs to be more than zero"
+        );
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encryption::Cipher,
+        padding::{Padding, PaddingDistribution},
+    };
+    use std::str::FromStr;
+
+    #[test]
+    fn invalid_config_should_fail() {
+        let builder = || {
+            ForwarderConfig::builder(
+                Uri::from_str("127.0.0.1:8000").unwrap(),
+                Uri::from_str("127.0.0.1:9000").unwrap(),
+            )
+        };
+        assert!(builder().build().is_ok());
+        assert!(builder().buffer_size(0).build().is_err());
+        assert!(builder().buffer_size(MAX_PACKET_SIZE + 1).build().is_err());
+        assert!(builder().idle_timeout(Duration::ZERO).build().is_err());
+        assert!(builder()
+            .remote_idle_timeout(Duration::ZERO)
+            .build()
+            .is_ok());
+        assert!(builder().poll_events_capacity(0).build().is_err());
+        assert!(builder().max_peers(0).build().is_err());
+        assert!(builder().new_peer_rate(Rate::new(0, 10)).build().is_err());
+        assert!(builder()
+            .new_peer_rate_per_ip(Rate::new(10, 0))
+            .build()
+            .is_err());
+        assert!(builder().new_peer_rate(Rate::new(10, 10)).build().is_ok());
+        let limit = |per_second, burst| TrafficLimit {
+            bytes: Some(Rate::new(per_second, burst)),
+            packets: None,
+        };
+        assert!(builder()
+            .peer_limit(Direction::ClientToRemote, limit(0, 10))
+            .build()
+            .is_err());
+        assert!(builder()
+            .global_limit(Direction::RemoteToClient, limit(10, 0))
+            .build()
+            .is_err());
+        let config = builder()
+            .peer_limit(Direction::RemoteToClient, limit(10, 10))
+            .build()
+            .unwrap();
+        assert_eq!(config.peer_limit(Direction::RemoteToClient), limit(10, 10));
+        assert!(config.peer_limit(Direction::ClientToRemote).is_unlimited());
+        assert!(builder().workers(0).build().is_err());
+        assert!(builder().batch_size(0).build().is_err());
+        assert!(builder().batch_size(MAX_BATCH_SIZE + 1).build().is_err());
+        assert!(builder().resolve_interval(Duration::ZERO).build().is_err());
+        let cidr = |cidr| Cidr::from_str(cidr).unwrap();
+        let config = builder()
+            .allow(cidr("10.0.0.0/8"))
+            .deny(cidr("10.0.0.1"))
+            .build()
+            .unwrap();
+        assert_eq!(config.access_list().allow_list(), &[cidr("10.0.0.0/8")]);
+        assert_eq!(config.access_list().deny_list(), &[cidr("10.0.0.1")]);
+        let remote = |uri| Uri::from_str(uri).unwrap();
+        assert!(builder().remote(remote("127.0.0.2:9000")).build().is_ok());
+        assert!(builder()
+            .remote(remote("127.0.0.2:9000/tcp"))
+            .build()
+            .is_err());
+        assert!(builder().remote(remote("[::1]:9000")).build().is_err());
+    }
+
+    #[test]
+    fn padding_after_encryption_should_fail() {
+        let padding = || Box::new(Padding::new(64, PaddingDistribution::Uniform));
+        let encryption = || Cipher::ChaCha20Poly1305.new_transform("some_password");
+        let builder = ForwarderConfig::builder(
+            Uri::from_str("127.0.0.1:8000").unwrap(),
+            Uri::from_str("127.0.0.1:9000").unwrap(),
+        );
+        assert!(builder
+            .transform(Side::Listen, padding())
+            .transform(Side::Listen, encryption())
+            .transform(Side::Remote, encryption())
+            .transform(Side::Remote, padding())
+            .build()
+            .is_err());
+        let builder = ForwarderConfig::builder(
+            Uri::from_str("127.0.0.1:8000").unwrap(),
+            Uri::from_str("127.0.0.1:9000").unwrap(),
+        );
+        assert!(builder
+            .transform(Side::Remote, padding())
+            .transform(Side::Remote, encryption())
+            .build()
+            .is_ok());
+    }
+}
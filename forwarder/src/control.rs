@@ -0,0 +1,238 @@
+use crate::{
+    kick_peer,
+    live::LiveConfig,
+    metrics::{Direction, Metrics},
+    peer::{PeerManager, PeerStats},
+    shutdown::{Shutdown, SHUTDOWN_CHECK_INTERVAL},
+    try_cleanup,
+};
+use anyhow::{bail, Context};
+use parking_lot::RwLock;
+use socket2::SockRef;
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    net::SocketAddr,
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+/// maximum time that a client of control socket can take to send its command
+const CONTROL_CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// command that is sent to control socket as one line, it's answered with one line of json
+/// that has `"ok": true` and the result or `"ok": false` and an `"error"`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// lists peers with their client address, proxy address, local port, idle times and traffic counters
+    Peers,
+    /// removes peer of a client, next packet of client creates a new peer
+    Kick(SocketAddr),
+    /// cleans idle peers now instead of waiting for the next cleanup
+    Cleanup,
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (Some("peers"), None) => Command::Peers,
+            (Some("kick"), Some(client_addr)) => {
+                let client_addr = client_addr
+                    .parse()
+                    .with_context(|| format!("invalid client address '{client_addr}'"))?;
+                Command::Kick(client_addr)
+            }
+            (Some("cleanup"), None) => Command::Cleanup,
+            _ => bail!(
+                "invalid command '{}', valid commands are 'peers', 'kick <client address>' and 'cleanup'",
+                s.trim()
+            ),
+        };
+        if words.next().is_some() {
+            bail!("command '{}' has too many arguments", s.trim());
+        }
+        Ok(command)
+    }
+}
+
+/// creates listener of control socket, it's created before threads of forwarder so
+/// errors like path being in use are returned early, socket that is left from a
+/// forwarder that didn't stop cleanly is replaced
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                "another forwarder is serving on it",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // commands can kick clients so only the owner can use it
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    // accept respects receive timeout in linux so it doesn't block forever
+    SockRef::from(&listener).set_read_timeout(Some(SHUTDOWN_CHECK_INTERVAL))?;
+    Ok(listener)
+}
+
+/// answers commands on `listener` until shutdown is requested and then removes
+/// the socket at `path`
+pub fn serve(
+    listener: UnixListener,
+    path: &Path,
+    peer_manager: &RwLock<PeerManager>,
+    metrics: &Metrics,
+    live: &LiveConfig,
+    shutdown: &Shutdown,
+) {
+    while !shutdown.is_requested() {
+        let Ok((stream, _)) = listener.accept() else {
+            continue;
+        };
+        let execute = |command| execute(command, peer_manager, metrics, live);
+        if let Err(error) = respond(stream, execute) {
+            log::debug!("couldn't answer command of control socket: {error}");
+        }
+    }
+    if let Err(error) = fs::remove_file(path) {
+        log::warn!(
+            "couldn't remove control socket '{}': {error}",
+            path.display()
+        );
+    }
+}
+
+fn respond(stream: UnixStream, execute: impl FnOnce(Command) -> String) -> io::Result<()> {
+    stream.set_read_timeout(Some(CONTROL_CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONTROL_CLIENT_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response = match Command::from_str(&line) {
+        Ok(command) => execute(command),
+        Err(error) => error_response(&format!("{error:#}")),
+    };
+    let mut stream = &stream;
+    writeln!(stream, "{response}")?;
+    stream.flush()
+}
+
+/// runs `command` and returns its json response
+fn execute(
+    command: Command,
+    peer_manager: &RwLock<PeerManager>,
+    metrics: &Metrics,
+    live: &LiveConfig,
+) -> String {
+    match command {
+        Command::Peers => {
+            let peers: Vec<String> = peer_manager
+                .read()
+                .get_all()
+                .iter()
+                .map(|peer| peer_json(&peer.stats()))
+                .collect();
+            format!("{{\"ok\":true,\"peers\":[{}]}}", peers.join(","))
+        }
+        Command::Kick(client_addr) => {
+            if kick_peer(peer_manager, metrics, &client_addr) {
+                "{\"ok\":true}".to_owned()
+            } else {
+                error_response(&format!("client '{client_addr}' doesn't have any peer"))
+            }
+        }
+        Command::Cleanup => {
+            let (_, cleaned) = try_cleanup(peer_manager, metrics, &live.load().config);
+            let remaining = peer_manager.read().len();
+            format!("{{\"ok\":true,\"cleaned\":{cleaned},\"remaining\":{remaining}}}")
+        }
+    }
+}
+
+fn peer_json(stats: &PeerStats) -> String {
+    let by_direction = |counter: fn(&PeerStats, Direction) -> u64| {
+        let values: Vec<String> = Direction::ALL
+            .iter()
+            .map(|direction| format!("\"{}\":{}", direction.label(), counter(stats, *direction)))
+            .collect();
+        format!("{{{}}}", values.join(","))
+    };
+    format!(
+        "{{\"client_addr\":{},\"proxy_addr\":{},\"local_port\":{},\"remote_index\":{},\"age_ms\":{},\"idle_ms\":{},\"client_idle_ms\":{},\"remote_idle_ms\":{},\"packets\":{},\"bytes\":{},\"send_errors\":{},\"dropped_packets\":{}}}",
+        json_string(&stats.client_addr().to_string()),
+        stats
+            .proxy_addr()
+            .map_or("null".to_owned(), |addr| json_string(&addr.to_string())),
+        stats.local_port(),
+        stats.remote_index(),
+        stats.age().as_millis(),
+        stats.idle().as_millis(),
+        stats.client_idle().as_millis(),
+        stats.remote_idle().as_millis(),
+        by_direction(PeerStats::packets),
+        by_direction(PeerStats::bytes),
+        by_direction(PeerStats::send_errors),
+        by_direction(PeerStats::dropped_packets),
+    )
+}
+
+fn error_response(error: &str) -> String {
+    format!("{{\"ok\":false,\"error\":{}}}", json_string(error))
+}
+
+/// quotes `value` as a json string
+fn json_string(value: &str) -> String {
+    let mut output = String::with_capacity(value.len() + 2);
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            // writing to `String` never fails
+            c if c.is_control() => write!(output, "\\u{:04x}", c as u32).unwrap(),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(Command::from_str("peers\n").unwrap(), Command::Peers);
+        assert_eq!(Command::from_str(" cleanup ").unwrap(), Command::Cleanup);
+        assert_eq!(
+            Command::from_str("kick 127.0.0.1:1000").unwrap(),
+            Command::Kick("127.0.0.1:1000".parse().unwrap())
+        );
+        assert!(Command::from_str("kick").is_err());
+        assert!(Command::from_str("kick localhost").is_err());
+        assert!(Command::from_str("peers all").is_err());
+        assert!(Command::from_str("").is_err());
+    }
+
+    #[test]
+    fn errors_are_valid_json_strings() {
+        assert_eq!(
+            error_response("invalid command 'a\"b\\\n'"),
+            r#"{"ok":false,"error":"invalid command 'a\"b\\\n'"}"#
+        );
+        assert_eq!(json_string("\u{1}"), r#""\u0001""#);
+    }
+}
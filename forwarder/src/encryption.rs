@@ -1,3 +1,368 @@
+use crate::{
+    clock,
+    kdf::MasterKey,
+    transform::{Sequence, Transform},
+};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, AeadCore, AeadInPlace, KeyInit, OsRng},
+    ChaCha20Poly1305 as ChaCha20Poly1305Cipher, Nonce, Tag,
+};
+use parking_lot::{Mutex, RwLock};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// size of random nonce that is prepended to each packet in `ChaCha20Poly1305`
+const NONCE_LEN: usize = 12;
+
+/// size of random nonce that is prepended to each packet in `Xor` when it has a key
+const XOR_NONCE_LEN: usize = 12;
+
+/// size of poly1305 tag that is appended to each packet in `ChaCha20Poly1305`
+const TAG_LEN: usize = 16;
+
+/// size of session, counter and send time of `Sequence` that are encrypted with payload
+/// when replay protection is on
+const SEQUENCE_LEN: usize = 12;
+
+/// number of 64 bit blocks of `ReplayWindow`, one of them is kept free for
+/// moving the window so it remembers `(REPLAY_WINDOW_BLOCKS - 1) * 64` packets
+const REPLAY_WINDOW_BLOCKS: usize = 16;
+
+/// packets that are sent longer than this ago are dropped by `ReplayWindows`, clocks
+/// of both forwarders need to be closer than this
+pub const MAX_PACKET_AGE: Duration = Duration::from_secs(60);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Cipher {
+    /// fast but weak encryption, only good for confusing DPI
+    Xor,
+    /// authenticated encryption, packets that are tampered with get dropped
+    ChaCha20Poly1305,
+}
+
+impl FromStr for Cipher {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "xor" => Ok(Cipher::Xor),
+            "chacha20-poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            _ => anyhow::bail!(
+                "invalid cipher name, valid ciphers are: 'xor' and 'chacha20-poly1305'"
+            ),
+        }
+    }
+}
+
+impl Cipher {
+    /// creates encryption `Transform` of this cipher with a key made from `passphrase`
+    pub fn new_transform(self, passphrase: &str) -> Box<dyn Transform> {
+        match self {
+            Cipher::Xor => Box::new(Xor::new(passphrase)),
+            Cipher::ChaCha20Poly1305 => Box::new(ChaCha20Poly1305::new(passphrase)),
+        }
+    }
+
+    /// same as `new_transform` but the key is derived from `key`
+    pub fn new_keyed_transform(self, key: &MasterKey) -> Box<dyn Transform> {
+        match self {
+            Cipher::Xor => Box::new(Xor::with_key(key)),
+            Cipher::ChaCha20Poly1305 => Box::new(ChaCha20Poly1305::with_key(key)),
+        }
+    }
+}
+
+/// xor encryption, it's symmetric so it doesn't matter on which side it's used
+pub struct Xor {
+    key: XorKey,
+}
+
+enum XorKey {
+    /// bytes of passphrase are repeated as keystream
+    Passphrase(String),
+    /// each packet gets its own keystream from chacha20 with a random nonce, so
+    /// packets of different clients never share a keystream
+    Derived([u8; 32]),
+}
+
+impl Xor {
+    pub fn new(passphrase: &str) -> Self {
+        Self {
+            key: XorKey::Passphrase(passphrase.to_owned()),
+        }
+    }
+
+    /// xor with a keystream that is derived from `key` and a random nonce that is
+    /// prepended to each packet, forwarder on the other side needs the same key
+    pub fn with_key(key: &MasterKey) -> Self {
+        Self {
+            key: XorKey::Derived(key.subkey(b"forwarder xor")),
+        }
+    }
+}
+
+impl Debug for Xor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // don't leak the passphrase in logs
+        f.debug_struct("Xor").finish_non_exhaustive()
+    }
+}
+
+impl Transform for Xor {
+    fn encode(&self, buffer: &mut [u8], size: usize) -> Option<usize> {
+        let key = match self.key {
+            XorKey::Passphrase(ref passphrase) => {
+                xor_encrypt(&mut buffer[..size], passphrase);
+                return Some(size);
+            }
+            XorKey::Derived(ref key) => key,
+        };
+        if buffer.len() < size + XOR_NONCE_LEN {
+            return None;
+        }
+        buffer.copy_within(..size, XOR_NONCE_LEN);
+        OsRng.fill_bytes(&mut buffer[..XOR_NONCE_LEN]);
+        let (nonce, payload) = buffer.split_at_mut(XOR_NONCE_LEN);
+        ChaCha20::new(key.into(), (&*nonce).into()).apply_keystream(&mut payload[..size]);
+        Some(size + XOR_NONCE_LEN)
+    }
+
+    fn decode(&self, buffer: &mut [u8], size: usize) -> Option<usize> {
+        let key = match self.key {
+            XorKey::Passphrase(_) => return self.encode(buffer, size),
+            XorKey::Derived(ref key) => key,
+        };
+        let payload_len = size.checked_sub(XOR_NONCE_LEN)?;
+        let (nonce, payload) = buffer.split_at_mut(XOR_NONCE_LEN);
+        ChaCha20::new(key.into(), (&*nonce).into()).apply_keystream(&mut payload[..payload_len]);
+        buffer.copy_within(XOR_NONCE_LEN..size, 0);
+        Some(payload_len)
+    }
+
+    fn max_overhead(&self) -> usize {
+        match self.key {
+            XorKey::Passphrase(_) => 0,
+            XorKey::Derived(_) => XOR_NONCE_LEN,
+        }
+    }
+
+    fn encrypts(&self) -> bool {
+        true
+    }
+}
+
+/// authenticated encryption, `encode` encrypts packets and `decode` decrypts
+/// them and drops the ones that are not authentic
+pub struct ChaCha20Poly1305 {
+    cipher: ChaCha20Poly1305Cipher,
+    /// session in upper 32 bits and counter of the next packet in lower 32 bits, counter
+    /// overflows into a new session, `None` if replay protection is off
+    next_sequence: Option<AtomicU64>,
+}
+
+impl ChaCha20Poly1305 {
+    pub fn new(passphrase: &str) -> Self {
+        let hash = Sha256::digest(passphrase.as_bytes());
+        Self {
+            cipher: ChaCha20Poly1305Cipher::new(&hash),
+            next_sequence: None,
+        }
+    }
+
+    /// same as `new` but the key is derived from `key` instead of a hash of passphrase
+    pub fn with_key(key: &MasterKey) -> Self {
+        let key = key.subkey(b"forwarder chacha20-poly1305");
+        Self {
+            cipher: ChaCha20Poly1305Cipher::new(&key.into()),
+            next_sequence: None,
+        }
+    }
+
+    /// puts a sequence number inside each encrypted packet so forwarder on the other side
+    /// drops the packets that are replayed, both forwarders need to have it
+    pub fn with_replay_protection(mut self) -> Self {
+        // sessions are random so restarted forwarders don't reuse the sequences of old runs
+        let session = OsRng.next_u32() as u64;
+        self.next_sequence = Some(AtomicU64::new(session << 32));
+        self
+    }
+}
+
+impl Debug for ChaCha20Poly1305 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChaCha20Poly1305").finish_non_exhaustive()
+    }
+}
+
+impl Transform for ChaCha20Poly1305 {
+    fn encode(&self, buffer: &mut [u8], size: usize) -> Option<usize> {
+        let Some(ref next_sequence) = self.next_sequence else {
+            return chacha20_poly1305_encrypt(&self.cipher, buffer, size);
+        };
+        if buffer.len() < size + SEQUENCE_LEN {
+            return None;
+        }
+        let next = next_sequence.fetch_add(1, Ordering::Relaxed);
+        let sequence = Sequence {
+            session: (next >> 32) as u32,
+            counter: next as u32,
+            sent_at: clock::unix_time() as u32,
+        };
+        buffer.copy_within(..size, SEQUENCE_LEN);
+        buffer[..4].copy_from_slice(&sequence.session.to_be_bytes());
+        buffer[4..8].copy_from_slice(&sequence.counter.to_be_bytes());
+        buffer[8..SEQUENCE_LEN].copy_from_slice(&sequence.sent_at.to_be_bytes());
+        chacha20_poly1305_encrypt(&self.cipher, buffer, size + SEQUENCE_LEN)
+    }
+
+    fn decode(&self, buffer: &mut [u8], size: usize) -> Option<usize> {
+        self.decode_sequenced(buffer, size).map(|(size, _)| size)
+    }
+
+    fn decode_sequenced(
+        &self,
+        buffer: &mut [u8],
+        size: usize,
+    ) -> Option<(usize, Option<Sequence>)> {
+        let size = chacha20_poly1305_decrypt(&self.cipher, buffer, size)?;
+        if self.next_sequence.is_none() {
+            return Some((size, None));
+        }
+        if size < SEQUENCE_LEN {
+            return None;
+        }
+        let read_u32 =
+            |range: std::ops::Range<usize>| buffer[range].try_into().ok().map(u32::from_be_bytes);
+        let sequence = Sequence {
+            session: read_u32(0..4)?,
+            counter: read_u32(4..8)?,
+            sent_at: read_u32(8..SEQUENCE_LEN)?,
+        };
+        buffer.copy_within(SEQUENCE_LEN..size, 0);
+        Some((size - SEQUENCE_LEN, Some(sequence)))
+    }
+
+    fn max_overhead(&self) -> usize {
+        let sequence_len = match self.next_sequence {
+            Some(_) => SEQUENCE_LEN,
+            None => 0,
+        };
+        NONCE_LEN + TAG_LEN + sequence_len
+    }
+
+    fn encrypts(&self) -> bool {
+        true
+    }
+}
+
+/// remembers counters of recent packets of a session so replayed packets and packets
+/// that are older than the window can be dropped, packets may arrive out of order
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    /// biggest counter that is seen, `None` before the first packet
+    highest: Option<u64>,
+    /// bit of each counter is at `counter % (REPLAY_WINDOW_BLOCKS * 64)`
+    bitmap: [u64; REPLAY_WINDOW_BLOCKS],
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns false if packet with `counter` was seen before or is too old, otherwise
+    /// remembers it and returns true, it should only be called for authentic packets
+    pub fn check(&mut self, counter: u64) -> bool {
+        let block_of = |counter: u64| counter / 64;
+        let index_of = |block: u64| (block % REPLAY_WINDOW_BLOCKS as u64) as usize;
+        match self.highest {
+            Some(highest) if counter > highest => {
+                // blocks that the window moves over are for new counters
+                let moved_blocks = block_of(counter) - block_of(highest);
+                for block in 1..=moved_blocks.min(REPLAY_WINDOW_BLOCKS as u64) {
+                    self.bitmap[index_of(block_of(highest) + block)] = 0;
+                }
+                self.highest = Some(counter);
+            }
+            Some(highest) => {
+                if highest - counter >= ((REPLAY_WINDOW_BLOCKS - 1) * 64) as u64 {
+                    return false;
+                }
+            }
+            None => self.highest = Some(counter),
+        }
+        let block = &mut self.bitmap[index_of(block_of(counter))];
+        let bit = 1 << (counter % 64);
+        if *block & bit != 0 {
+            return false;
+        }
+        *block |= bit;
+        true
+    }
+}
+
+/// `ReplayWindow` of each session of the forwarders on the other side, they are shared
+/// by all peers so packets that are replayed from another address or after their peer
+/// is cleaned are dropped too, only authentic senders can start a session
+#[derive(Debug, Default)]
+pub struct ReplayWindows {
+    sessions: RwLock<HashMap<u32, ReplaySession>>,
+}
+
+#[derive(Debug, Default)]
+struct ReplaySession {
+    window: Mutex<ReplayWindow>,
+    /// last time in `clock::unix_time` that a packet of session was accepted
+    last_seen: AtomicU64,
+}
+
+impl ReplaySession {
+    fn check(&self, counter: u32, now: u64) -> bool {
+        if !self.window.lock().check(counter as u64) {
+            return false;
+        }
+        self.last_seen.store(now, Ordering::Relaxed);
+        true
+    }
+}
+
+impl ReplayWindows {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns false if packet with `sequence` was seen before or is sent more than
+    /// `MAX_PACKET_AGE` before `now` in `clock::unix_time`, otherwise remembers it and
+    /// returns true, it should only be called for authentic packets
+    pub fn check(&self, sequence: Sequence, now: u64) -> bool {
+        if (sequence.sent_at as u64) + MAX_PACKET_AGE.as_secs() < now {
+            return false;
+        }
+        // packets of known sessions only share the read lock
+        if let Some(session) = self.sessions.read().get(&sequence.session) {
+            return session.check(sequence.counter, now);
+        }
+        let mut sessions = self.sessions.write();
+        // packets of sessions that are quiet for so long are too old anyway
+        sessions.retain(|_, session| {
+            session.last_seen.load(Ordering::Relaxed) + MAX_PACKET_AGE.as_secs() >= now
+        });
+        sessions
+            .entry(sequence.session)
+            .or_default()
+            .check(sequence.counter, now)
+    }
+}
+
 pub fn xor_encrypt(data: &mut [u8], passphrase: &str) {
     let passphrase = passphrase.as_bytes();
     for (index, byte) in data.iter_mut().enumerate() {
@@ -6,6 +371,55 @@ pub fn xor_encrypt(data: &mut [u8], passphrase: &str) {
     }
 }
 
+/// encrypts `buffer[..size]` in place and turns it to `nonce + ciphertext + tag`
+/// and returns the new size or `None` if there is not enough space in `buffer`
+fn chacha20_poly1305_encrypt(
+    cipher: &ChaCha20Poly1305Cipher,
+    buffer: &mut [u8],
+    size: usize,
+) -> Option<usize> {
+    if buffer.len() < size + NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let nonce = ChaCha20Poly1305Cipher::generate_nonce(&mut OsRng);
+    buffer.copy_within(..size, NONCE_LEN);
+    buffer[..NONCE_LEN].copy_from_slice(&nonce);
+
+    let payload_end = NONCE_LEN + size;
+    let tag = cipher
+        .encrypt_in_place_detached(&nonce, &[], &mut buffer[NONCE_LEN..payload_end])
+        // only fails when payload is bigger than what chacha20 can handle (256GiB)
+        .expect("packet is too big to encrypt");
+    buffer[payload_end..payload_end + TAG_LEN].copy_from_slice(&tag);
+    Some(payload_end + TAG_LEN)
+}
+
+/// decrypts `nonce + ciphertext + tag` in `buffer[..size]` in place and moves
+/// the plain payload to the start of `buffer`, returns `None` if the packet is
+/// not authentic
+fn chacha20_poly1305_decrypt(
+    cipher: &ChaCha20Poly1305Cipher,
+    buffer: &mut [u8],
+    size: usize,
+) -> Option<usize> {
+    if size < NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let payload_end = size - TAG_LEN;
+    let (header, rest) = buffer.split_at_mut(NONCE_LEN);
+    let (payload, tag) = rest[..size - NONCE_LEN].split_at_mut(payload_end - NONCE_LEN);
+    cipher
+        .decrypt_in_place_detached(
+            Nonce::from_slice(header),
+            &[],
+            payload,
+            Tag::from_slice(tag),
+        )
+        .ok()?;
+    buffer.copy_within(NONCE_LEN..payload_end, 0);
+    Some(payload_end - NONCE_LEN)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -29,4 +443,148 @@ mod tests {
         xor_encrypt(&mut buffer, "another_password");
         assert_ne!(input, buffer);
     }
+
+    #[test]
+    fn xor_with_key_never_reuses_keystream() {
+        let key = MasterKey::derive("pass", crate::kdf::DEFAULT_SALT).unwrap();
+        let encryption = Xor::with_key(&key);
+        let encrypt = || {
+            let mut buffer = [0u8; 16 + XOR_NONCE_LEN];
+            let size = encryption.encode(&mut buffer, 16).unwrap();
+            assert_eq!(size, 16 + encryption.max_overhead());
+            buffer
+        };
+        let (first, second) = (encrypt(), encrypt());
+        assert_ne!(first[XOR_NONCE_LEN..], second[XOR_NONCE_LEN..]);
+        // short passphrase doesn't repeat in keystream
+        assert_ne!(first[XOR_NONCE_LEN..][..4], first[XOR_NONCE_LEN..][4..8]);
+
+        let mut buffer = first;
+        assert_eq!(encryption.decode(&mut buffer, 16 + XOR_NONCE_LEN), Some(16));
+        assert_eq!(buffer[..16], [0u8; 16]);
+        assert!(encryption.decode(&mut buffer, XOR_NONCE_LEN - 1).is_none());
+    }
+
+    #[test]
+    fn chacha20_poly1305_encryption_test() {
+        let encryption = ChaCha20Poly1305::new("some_password");
+        let mut buffer = [0u8; 5 + NONCE_LEN + TAG_LEN];
+        buffer[..5].copy_from_slice(b"hello");
+        let size = encryption.encode(&mut buffer, 5).unwrap();
+        assert_eq!(size, 5 + encryption.max_overhead());
+        assert!(!buffer.windows(5).any(|window| window == b"hello"));
+
+        let size = encryption.decode(&mut buffer, size).unwrap();
+        assert_eq!(&buffer[..size], b"hello");
+    }
+
+    #[test]
+    fn replay_protection_puts_sequence_in_packet() {
+        let encryption = ChaCha20Poly1305::new("some_password").with_replay_protection();
+        let mut buffer = [0u8; 5 + NONCE_LEN + TAG_LEN + SEQUENCE_LEN];
+        let encode = |buffer: &mut [u8]| {
+            buffer[..5].copy_from_slice(b"hello");
+            encryption.encode(buffer, 5).unwrap()
+        };
+        let size = encode(&mut buffer);
+        assert_eq!(size, 5 + encryption.max_overhead());
+        let (size, first_sequence) = encryption.decode_sequenced(&mut buffer, size).unwrap();
+        assert_eq!(&buffer[..size], b"hello");
+        let first_sequence = first_sequence.unwrap();
+        assert_eq!(first_sequence.counter, 0);
+        assert!(first_sequence.sent_at as u64 >= clock::unix_time() - 1);
+
+        let size = encode(&mut buffer);
+        let (_, second_sequence) = encryption.decode_sequenced(&mut buffer, size).unwrap();
+        let second_sequence = second_sequence.unwrap();
+        assert_eq!(second_sequence.session, first_sequence.session);
+        assert_eq!(second_sequence.counter, 1);
+
+        // packets of a forwarder without replay protection are too short to have a sequence
+        let without_replay_protection = ChaCha20Poly1305::new("some_password");
+        buffer[..5].copy_from_slice(b"hello");
+        let size = without_replay_protection.encode(&mut buffer, 5).unwrap();
+        assert!(encryption.decode(&mut buffer, size).is_none());
+    }
+
+    #[test]
+    fn counter_overflows_into_a_new_session() {
+        let encryption = ChaCha20Poly1305::new("some_password").with_replay_protection();
+        let next_sequence = encryption.next_sequence.as_ref().unwrap();
+        next_sequence.store(u32::MAX as u64, Ordering::Relaxed);
+        let mut buffer = [0u8; 5 + NONCE_LEN + TAG_LEN + SEQUENCE_LEN];
+        let mut sequences = Vec::new();
+        for _ in 0..2 {
+            let size = encryption.encode(&mut buffer, 5).unwrap();
+            sequences.push(encryption.decode_sequenced(&mut buffer, size).unwrap().1);
+        }
+        let [first, second] = [sequences[0].unwrap(), sequences[1].unwrap()];
+        assert_eq!((first.session, first.counter), (0, u32::MAX));
+        assert_eq!((second.session, second.counter), (1, 0));
+    }
+
+    #[test]
+    fn replay_window_drops_replayed_and_old_packets() {
+        let mut window = ReplayWindow::new();
+        assert!(window.check(1000));
+        assert!(!window.check(1000));
+        // out of order packets are fine
+        assert!(window.check(1002));
+        assert!(window.check(1001));
+        assert!(!window.check(1001));
+
+        let window_len = ((REPLAY_WINDOW_BLOCKS - 1) * 64) as u64;
+        assert!(window.check(1002 + window_len));
+        assert!(!window.check(1002));
+        assert!(window.check(1003));
+        assert!(!window.check(1003));
+        // window moves further than its whole size
+        assert!(window.check(1_000_000));
+        assert!(window.check(1_000_000 - 1));
+        assert!(!window.check(1_000_000 - window_len));
+    }
+
+    #[test]
+    fn sessions_have_their_own_window_and_old_packets_are_dropped() {
+        let now = 1_000_000;
+        let sequence = |session, counter, sent_at| Sequence {
+            session,
+            counter,
+            sent_at,
+        };
+        let windows = ReplayWindows::new();
+        assert!(windows.check(sequence(1, 5, now as u32), now));
+        assert!(!windows.check(sequence(1, 5, now as u32), now));
+        // another forwarder may send the same counter at the same time
+        assert!(windows.check(sequence(2, 5, now as u32), now));
+        // clocks of forwarders may be a bit apart
+        assert!(windows.check(sequence(1, 6, now as u32 - 30), now));
+        assert!(windows.check(sequence(1, 7, now as u32 + 30), now));
+        let max_age = MAX_PACKET_AGE.as_secs() as u32;
+        assert!(!windows.check(sequence(1, 8, now as u32 - max_age - 1), now));
+        assert!(!windows.check(sequence(3, 0, now as u32 - max_age - 1), now));
+
+        // sessions that are quiet for too long are forgotten when a new one starts
+        let later = now + MAX_PACKET_AGE.as_secs() + 1;
+        assert!(windows.check(sequence(2, 6, later as u32), later - 1));
+        assert!(windows.check(sequence(3, 0, later as u32), later));
+        let sessions = windows.sessions.read();
+        assert!(!sessions.contains_key(&1));
+        assert!(sessions.contains_key(&2));
+    }
+
+    #[test]
+    fn chacha20_poly1305_tampered_packet_should_be_dropped() {
+        let encryption = ChaCha20Poly1305::new("some_password");
+        let another_encryption = ChaCha20Poly1305::new("another_password");
+        let mut buffer = [0u8; 5 + NONCE_LEN + TAG_LEN];
+        buffer[..5].copy_from_slice(b"hello");
+        let size = encryption.encode(&mut buffer, 5).unwrap();
+
+        let mut tampered = buffer;
+        tampered[NONCE_LEN] ^= 1;
+        assert!(encryption.decode(&mut tampered, size).is_none());
+        assert!(another_encryption.decode(&mut buffer, size).is_none());
+        assert!(encryption.decode(&mut buffer, 3).is_none());
+    }
 }
@@ -0,0 +1,211 @@
+use crate::{clock::unix_time, kdf::MasterKey, transform::Side};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fmt::Debug, time::Duration};
+
+// handshake packets don't have any fixed marker that can be spotted on wire, they go
+// through the same transforms as the other packets and are told apart by their mac
+const HELLO_KIND: u8 = 1;
+const ACK_KIND: u8 = 2;
+
+const NONCE_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+
+const TIMESTAMP_START: usize = 1;
+const NONCE_START: usize = TIMESTAMP_START + 8;
+
+/// `kind + timestamp + nonce + mac`
+const HELLO_LEN: usize = NONCE_START + NONCE_LEN + MAC_LEN;
+
+/// `kind + nonce of hello + mac`
+const ACK_LEN: usize = 1 + NONCE_LEN + MAC_LEN;
+
+/// hellos that their timestamp is further than this from now are rejected, so
+/// clocks of both forwarders need to be at least this close to each other
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// how long a peer waits for the ack of its first hello before it sends hello again,
+/// the wait doubles after each hello that remote doesn't answer with a packet
+pub const HELLO_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// peers never wait longer than this between hellos
+pub const MAX_HELLO_RETRY_INTERVAL: Duration = Duration::from_secs(32);
+
+/// acknowledged peers send hello again when remote didn't send anything for this long,
+/// remote may have lost their peer, e.g. it restarted, and it drops their packets
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// proves that two forwarders know the same passphrase before a peer is created
+///
+/// the forwarder that has handshake on its remote side sends a hello with a timestamp and
+/// a random nonce that are signed by the key, the forwarder that has handshake on its
+/// listen side only creates a peer for a client after it sends a valid hello that
+/// wasn't seen before and answers it with a signed ack, both of them are transformed
+/// like the other packets so they are encrypted and padded too
+pub struct Handshake {
+    side: Side,
+    key: [u8; 32],
+    /// nonces of accepted hellos and their timestamp, so each hello is only accepted once
+    seen_nonces: Mutex<HashMap<[u8; NONCE_LEN], u64>>,
+}
+
+impl Handshake {
+    pub fn new(side: Side, passphrase: &str) -> Self {
+        // separate the key from the one that encryption makes from the same passphrase
+        let key = Sha256::new()
+            .chain_update(b"forwarder handshake")
+            .chain_update(passphrase.as_bytes())
+            .finalize();
+        Self {
+            side,
+            key: key.into(),
+            seen_nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// same as `new` but the key is derived from `key`
+    pub fn with_key(side: Side, key: &MasterKey) -> Self {
+        Self {
+            side,
+            key: key.subkey(b"forwarder handshake"),
+            seen_nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    /// creates a new hello packet with a random nonce
+    pub fn hello(&self) -> [u8; HELLO_LEN] {
+        let mut packet = [0u8; HELLO_LEN];
+        packet[0] = HELLO_KIND;
+        packet[TIMESTAMP_START..NONCE_START].copy_from_slice(&unix_time().to_be_bytes());
+        OsRng.fill_bytes(&mut packet[NONCE_START..NONCE_START + NONCE_LEN]);
+        self.sign(&mut packet);
+        packet
+    }
+
+    /// returns whether `packet` is a hello that is signed by the same key, other
+    /// packets that happen to have the same size and kind aren't hellos
+    pub fn is_hello(&self, packet: &[u8]) -> bool {
+        packet.len() == HELLO_LEN && packet[0] == HELLO_KIND && self.verify(packet)
+    }
+
+    /// checks a hello that `is_hello` and returns its ack if its timestamp is close
+    /// to now and its nonce wasn't accepted before
+    pub fn accept_hello(&self, packet: &[u8]) -> Option<[u8; ACK_LEN]> {
+        if !self.is_hello(packet) {
+            return None;
+        }
+        let timestamp = u64::from_be_bytes(packet[TIMESTAMP_START..NONCE_START].try_into().ok()?);
+        let now = unix_time();
+        let max_skew = MAX_CLOCK_SKEW.as_secs();
+        if timestamp.abs_diff(now) > max_skew {
+            return None;
+        }
+        let nonce: [u8; NONCE_LEN] = packet[NONCE_START..NONCE_START + NONCE_LEN]
+            .try_into()
+            .ok()?;
+        {
+            let mut seen_nonces = self.seen_nonces.lock();
+            // older hellos are rejected by their timestamp anyway
+            seen_nonces.retain(|_, timestamp| timestamp.abs_diff(now) <= max_skew);
+            if seen_nonces.insert(nonce, timestamp).is_some() {
+                return None;
+            }
+        }
+
+        let mut ack = [0u8; ACK_LEN];
+        ack[0] = ACK_KIND;
+        ack[1..1 + NONCE_LEN].copy_from_slice(&nonce);
+        self.sign(&mut ack);
+        Some(ack)
+    }
+
+    /// returns whether `packet` is an ack that is signed by the same key
+    pub fn verify_ack(&self, packet: &[u8]) -> bool {
+        packet.len() == ACK_LEN && packet[0] == ACK_KIND && self.verify(packet)
+    }
+
+    fn mac(&self, data: &[u8]) -> HmacSha256 {
+        // hmac accepts keys of any size
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        mac.update(data);
+        mac
+    }
+
+    /// writes mac of the rest of `packet` at its end
+    fn sign(&self, packet: &mut [u8]) {
+        let data_len = packet.len() - MAC_LEN;
+        let tag = self.mac(&packet[..data_len]).finalize().into_bytes();
+        packet[data_len..].copy_from_slice(&tag);
+    }
+
+    fn verify(&self, packet: &[u8]) -> bool {
+        let (data, tag) = packet.split_at(packet.len() - MAC_LEN);
+        self.mac(data).verify_slice(tag).is_ok()
+    }
+}
+
+impl Debug for Handshake {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // don't leak the key in logs
+        f.debug_struct("Handshake")
+            .field("side", &self.side)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_is_accepted_once_and_acked() {
+        let client = Handshake::new(Side::Remote, "password");
+        let server = Handshake::new(Side::Listen, "password");
+        let hello = client.hello();
+        assert!(server.is_hello(&hello));
+        assert!(!server.is_hello(b"hello"));
+        // packets of the same size and kind that aren't signed are left to be forwarded
+        let mut unsigned = hello;
+        unsigned[HELLO_LEN - 1] ^= 1;
+        assert!(!server.is_hello(&unsigned));
+        assert!(!Handshake::new(Side::Listen, "another_password").is_hello(&hello));
+
+        let ack = server.accept_hello(&hello).unwrap();
+        assert!(client.verify_ack(&ack));
+        // replayed hello
+        assert!(server.accept_hello(&hello).is_none());
+        assert!(server.accept_hello(&client.hello()).is_some());
+    }
+
+    #[test]
+    fn hello_with_another_key_or_old_timestamp_is_rejected() {
+        let client = Handshake::new(Side::Remote, "password");
+        let server = Handshake::new(Side::Listen, "another_password");
+        let hello = client.hello();
+        assert!(server.accept_hello(&hello).is_none());
+
+        let server = Handshake::new(Side::Listen, "password");
+        let mut tampered = client.hello();
+        tampered[TIMESTAMP_START] ^= 1;
+        assert!(server.accept_hello(&tampered).is_none());
+
+        let mut old_hello = client.hello();
+        let old_timestamp = unix_time() - MAX_CLOCK_SKEW.as_secs() - 10;
+        old_hello[TIMESTAMP_START..NONCE_START].copy_from_slice(&old_timestamp.to_be_bytes());
+        client.sign(&mut old_hello);
+        assert!(server.accept_hello(&old_hello).is_none());
+
+        let ack = Handshake::new(Side::Listen, "password")
+            .accept_hello(&client.hello())
+            .unwrap();
+        assert!(!Handshake::new(Side::Remote, "another_password").verify_ack(&ack));
+    }
+}
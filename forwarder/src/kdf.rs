@@ -0,0 +1,76 @@
+use anyhow::{anyhow, ensure};
+use argon2::Argon2;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt::Debug;
+
+/// salt that is used when none is set, it's better to pick another one so keys of the
+/// same passphrase are different from the keys of other setups
+pub const DEFAULT_SALT: &str = "forwarder default salt";
+
+/// argon2 needs salts of at least this many bytes
+const MIN_SALT_LEN: usize = argon2::MIN_SALT_LEN;
+
+/// key that is stretched from a passphrase and a salt with argon2id, so short passphrases
+/// don't end up as short or guessable keys, keys of ciphers and handshake are derived
+/// from it with hkdf so none of them are the same
+///
+/// both forwarders need to use the same passphrase and salt
+#[derive(Clone)]
+pub struct MasterKey([u8; 32]);
+
+impl MasterKey {
+    /// stretches `passphrase` with `salt`, it's slow on purpose so it should only be done
+    /// once when forwarder starts
+    pub fn derive(passphrase: &str, salt: &str) -> anyhow::Result<Self> {
+        ensure!(
+            salt.len() >= MIN_SALT_LEN,
+            "salt needs to be at least {MIN_SALT_LEN} bytes"
+        );
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+            .map_err(|error| anyhow!("couldn't derive key from passphrase: {error}"))?;
+        Ok(Self(key))
+    }
+
+    /// derives a key for `purpose`, different purposes get unrelated keys
+    pub(crate) fn subkey(&self, purpose: &[u8]) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand(purpose, &mut key)
+            // only fails when output is longer than 255 hashes
+            .expect("subkey is too long");
+        key
+    }
+}
+
+impl Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // don't leak the key in logs
+        f.debug_struct("MasterKey").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_depend_on_passphrase_salt_and_purpose() {
+        let key = MasterKey::derive("password", DEFAULT_SALT).unwrap();
+        assert_eq!(
+            key.subkey(b"xor"),
+            MasterKey::derive("password", DEFAULT_SALT)
+                .unwrap()
+                .subkey(b"xor")
+        );
+        assert_ne!(key.subkey(b"xor"), key.subkey(b"handshake"));
+        let another_salt = MasterKey::derive("password", "another salt").unwrap();
+        assert_ne!(key.subkey(b"xor"), another_salt.subkey(b"xor"));
+        let another_passphrase = MasterKey::derive("passw0rd", DEFAULT_SALT).unwrap();
+        assert_ne!(key.subkey(b"xor"), another_passphrase.subkey(b"xor"));
+
+        assert!(MasterKey::derive("password", "short").is_err());
+    }
+}
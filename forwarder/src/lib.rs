@@ -1,163 +1,1111 @@
-mod encryption;
+pub mod acl;
+mod clock;
+pub mod config;
+mod control;
+pub mod encryption;
+pub mod handshake;
+pub mod kdf;
+pub mod limit;
+mod live;
+mod metrics;
+pub mod padding;
 mod peer;
 mod poll;
+mod proxy;
+pub mod remote;
+pub mod resolver;
+mod shutdown;
 pub mod socket;
+pub mod transform;
 pub mod uri;
 
 use anyhow::Context;
-use parking_lot::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard};
-use poll::Poll;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use config::ForwarderConfig;
+use parking_lot::{RwLock, RwLockWriteGuard};
+use poll::{PeerHandler, Poll};
+use std::{net::SocketAddr, sync::Arc, thread::JoinHandle, time::Duration};
 use {
-    peer::{Peer, PeerManager},
-    socket::Socket,
-    uri::Uri,
+    encryption::ReplayWindows,
+    handshake::Handshake,
+    limit::MaxPeersPolicy,
+    live::{LiveConfig, Snapshot},
+    metrics::{DropReason, Metrics},
+    peer::{Hold, Peer, PeerManager},
+    proxy::ProxyAddrs,
+    remote::Remotes,
+    shutdown::{Shutdown, SHUTDOWN_CHECK_INTERVAL},
+    socket::{PacketBatch, Socket},
+    transform::{Sequence, Side, TransformChain},
+    uri::{Protocol, Uri},
 };
 
-// all buffers that are used for receiving and sending packet will use this size
+pub use {metrics::Direction, peer::PeerStats};
+
+/// maximum size of a packet, default size of buffers that are used for receiving packets
 const MAX_PACKET_SIZE: usize = 65535;
 
-/// interval that cleanup happens, lowering this result in lower allowed unused time
-const CLEANUP_INTERVAL: Duration = Duration::from_secs(7 * 60);
+/// minimum time between two cleanups, so peers that become idle close to
+/// each other get cleaned together
+const MIN_CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
 
 /// blocks current thread and runs a forwarder server that listens on `listen_uri` and forwards
 /// all incoming packets to `remote_uri` and also forwards all packets returned by `remote_uri`
-/// to the client that initiated the connection
+/// to the client that initiated the connection, packets get xor encrypted if `passphrase` is set
 ///
 /// # Error
-/// this function only returns early errors, such as being unable to listen on `listen_uri` or
-/// failing to create server `Poll` and ... it will panic on other late errors
+/// this function returns early errors, such as being unable to listen on `listen_uri` or
+/// failing to create server `Poll` and also the late errors that stopped forwarder
 pub fn run(listen_uri: Uri, remote_uri: Uri, passphrase: Option<String>) -> anyhow::Result<()> {
-    let listen_addr = &listen_uri.addr;
-    let socket =
-        Socket::bind(listen_uri.protocol, listen_addr).with_context(|| "couldn't create server")?;
-    let socket = Arc::new(socket);
-    log::info!("listen on '{listen_addr}'");
-
-    let poll = poll::new(remote_uri.protocol, remote_uri.addr.is_ipv6())
-        .with_context(|| "couldn't create poll")?;
+    let mut builder = ForwarderConfig::builder(listen_uri, remote_uri);
+    if let Some(passphrase) = passphrase {
+        // xor is symmetric so it doesn't matter which side it's on
+        let xor = encryption::Xor::new(&passphrase);
+        builder = builder.transform(Side::Remote, Box::new(xor));
+    }
+    run_with_config(builder.build()?)
+}
+
+/// same as `run` but forwarder is configured by `config`
+pub fn run_with_config(config: ForwarderConfig) -> anyhow::Result<()> {
+    start(config)?.join()
+}
+
+/// same as `run_with_config` but doesn't block current thread, forwarder runs on
+/// background threads and can be stopped via returned `ForwarderHandle`
+pub fn start(mut config: ForwarderConfig) -> anyhow::Result<ForwarderHandle> {
+    resolve_uris(&mut config)?;
+    let sockets = bind_server_sockets(&config)?;
+    // replies of remotes are sent from the first socket, all of them have the same address
+    let socket = sockets[0].clone();
+    log::info!("listen on '{}'", config.listen_uri.addr);
+    let metrics_listener = match config.metrics_addr {
+        Some(metrics_addr) => {
+            let listener = metrics::bind(&metrics_addr)
+                .with_context(|| format!("couldn't listen on metrics address '{metrics_addr}'"))?;
+            log::info!("serving metrics on 'http://{metrics_addr}/metrics'");
+            Some(listener)
+        }
+        None => None,
+    };
+    let control_listener = match &config.control_socket {
+        Some(path) => {
+            let listener = control::bind(path).with_context(|| {
+                format!("couldn't listen on control socket '{}'", path.display())
+            })?;
+            log::info!("serving control socket on '{}'", path.display());
+            Some((listener, path.clone()))
+        }
+        None => None,
+    };
+
+    // all remotes have the same protocol and ip version
+    let remote_uri = &config.remote_uris[0];
+    let poll = poll::new(
+        remote_uri.protocol,
+        remote_uri.addr.is_ipv6(),
+        config.buffer_size,
+        config.batch_size,
+        config.poll_events_capacity,
+        config.socket_options.icmp_mode,
+    )
+    .with_context(|| "couldn't create poll")?;
     let registry = poll
         .get_registry()
         .with_context(|| "couldn't get registry of poll")?;
-    let peer_manager = Arc::new(RwLock::new(PeerManager::new(registry)));
+    let peer_manager = Arc::new(RwLock::new(PeerManager::new(registry, socket.clone())));
+    let remotes = Arc::new(new_remotes(&config));
+    let resolve_interval = resolve_interval(&config);
+    let live = Arc::new(LiveConfig::new(Arc::new(config), remotes));
+
+    let metrics = Arc::new(Metrics::new());
+    let replay_windows: Arc<[ReplayWindows; 2]> = Arc::default();
+    let mut handle = ForwarderHandle {
+        shutdown: Arc::new(Shutdown::new()),
+        threads: Vec::new(),
+        peer_manager: peer_manager.clone(),
+        metrics: metrics.clone(),
+        live: live.clone(),
+    };
+
+    {
+        let (peer_manager, live, metrics, replay_windows, socket) = (
+            peer_manager.clone(),
+            live.clone(),
+            metrics.clone(),
+            replay_windows.clone(),
+            socket.clone(),
+        );
+        handle.spawn_thread("peers", move |shutdown| {
+            peers_thread(
+                poll,
+                peer_manager,
+                live,
+                metrics,
+                replay_windows,
+                socket,
+                shutdown,
+            )
+        })?;
+    }
+    {
+        let (peer_manager, metrics, live) = (peer_manager.clone(), metrics.clone(), live.clone());
+        handle.spawn_thread("cleanup", move |shutdown| {
+            cleanup_thread(&peer_manager, &metrics, &live, shutdown);
+            Ok(())
+        })?;
+    }
+    if let Some(listener) = metrics_listener {
+        let (peer_manager, metrics) = (peer_manager.clone(), metrics.clone());
+        handle.spawn_thread("metrics", move |shutdown| {
+            metrics::serve(listener, &metrics, &peer_manager, shutdown);
+            Ok(())
+        })?;
+    }
+    if let Some((listener, path)) = control_listener {
+        let (peer_manager, metrics, live) = (peer_manager.clone(), metrics.clone(), live.clone());
+        handle.spawn_thread("control", move |shutdown| {
+            control::serve(listener, &path, &peer_manager, &metrics, &live, shutdown);
+            Ok(())
+        })?;
+    }
+    if let Some(interval) = resolve_interval {
+        let (peer_manager, metrics, live) = (peer_manager.clone(), metrics.clone(), live.clone());
+        handle.spawn_thread("resolver", move |shutdown| {
+            resolver_thread(&peer_manager, &metrics, &live, interval, shutdown);
+            Ok(())
+        })?;
+    }
+    for socket in sockets {
+        let (peer_manager, live, metrics, replay_windows) = (
+            peer_manager.clone(),
+            live.clone(),
+            metrics.clone(),
+            replay_windows.clone(),
+        );
+        handle.spawn_thread("server", move |shutdown| {
+            run_server(
+                socket,
+                peer_manager,
+                live,
+                metrics,
+                replay_windows,
+                shutdown,
+            );
+            Ok(())
+        })?;
+    }
+    Ok(handle)
+}
 
-    spawn_peers_thread(poll, peer_manager.clone(), socket.clone(), &passphrase);
-    spawn_cleanup_thread(peer_manager.clone());
-    run_server(socket, peer_manager, passphrase, remote_uri);
+/// resolves hostnames of uris of `config` again, they may point to somewhere
+/// else since uris got parsed
+fn resolve_uris(config: &mut ForwarderConfig) -> anyhow::Result<()> {
+    let resolver = config.resolver.as_ref();
+    config
+        .listen_uri
+        .resolve(resolver)
+        .with_context(|| "couldn't resolve listen uri")?;
+    for remote_uri in &mut config.remote_uris {
+        remote_uri
+            .resolve(resolver)
+            .with_context(|| format!("couldn't resolve remote uri '{remote_uri}'"))?;
+    }
     Ok(())
 }
 
-/// runs server in current thread
+fn new_remotes(config: &ForwarderConfig) -> Remotes {
+    let remote_addrs: Vec<SocketAddr> = config.remote_uris.iter().map(|uri| uri.addr).collect();
+    Remotes::new(
+        &remote_addrs,
+        config.remote_policy,
+        config.remote_health_timeout,
+    )
+}
+
+/// returns how often hostnames of remotes are resolved again, `None` if they aren't
+fn resolve_interval(config: &ForwarderConfig) -> Option<Duration> {
+    let has_hostname = config.remote_uris.iter().any(|uri| uri.host.is_some());
+    config.resolve_interval.filter(|_| has_hostname)
+}
+
+/// creates batch that has enough free space after each packet for transforms of `config`
+fn new_batch(config: &ForwarderConfig) -> PacketBatch {
+    // transforms and proxy header may make the packet bigger so we need some free space at the end
+    let proxy_overhead = match config.proxy_protocol_remote {
+        true => proxy::MAX_HEADER_SIZE,
+        false => 0,
+    };
+    let slot_size = config.buffer_size + config.transforms.max_overhead() + proxy_overhead;
+    PacketBatch::new(config.batch_size, config.buffer_size, slot_size)
+}
+
+/// creates one listen socket for each worker, on udp each worker gets its own socket
+/// with `SO_REUSEPORT` so kernel spreads clients between them, other protocols
+/// share one socket between workers
+fn bind_server_sockets(config: &ForwarderConfig) -> anyhow::Result<Vec<Arc<Socket>>> {
+    let Uri { addr, protocol, .. } = config.listen_uri;
+    let options = &config.socket_options;
+    let sockets = if protocol != Protocol::Udp || config.workers == 1 {
+        let socket =
+            Socket::bind(protocol, &addr, options).with_context(|| "couldn't create server")?;
+        let socket = Arc::new(socket);
+        vec![socket; config.workers]
+    } else {
+        let first_socket = Socket::bind_reuse_port(protocol, &addr, options)
+            .with_context(|| "couldn't create server")?;
+        // listen port may be zero, so the other sockets use the port that first one got
+        let addr = first_socket
+            .local_addr()
+            .with_context(|| "couldn't get address of server")?;
+        let mut sockets = vec![Arc::new(first_socket)];
+        for _ in 1..config.workers {
+            let socket = Socket::bind_reuse_port(protocol, &addr, options)
+                .with_context(|| "couldn't create socket of server worker")?;
+            sockets.push(Arc::new(socket));
+        }
+        sockets
+    };
+    for socket in &sockets {
+        socket
+            .set_read_timeout(Some(SHUTDOWN_CHECK_INTERVAL))
+            .with_context(|| "couldn't set read timeout of server")?;
+    }
+    Ok(sockets)
+}
+
+/// handle of a forwarder that is started by `start`
+///
+/// dropping the handle doesn't stop the forwarder, use `shutdown` for that
+pub struct ForwarderHandle {
+    shutdown: Arc<Shutdown>,
+    threads: Vec<JoinHandle<anyhow::Result<()>>>,
+    peer_manager: Arc<RwLock<PeerManager>>,
+    metrics: Arc<Metrics>,
+    live: Arc<LiveConfig>,
+}
+
+impl ForwarderHandle {
+    /// asks all threads of forwarder to stop, it doesn't wait for them to
+    /// actually stop so `join` needs to be called after it
+    pub fn shutdown(&self) {
+        self.shutdown.request();
+    }
+
+    /// returns whether all threads of forwarder have stopped, e.g. after an error
+    pub fn is_stopped(&self) -> bool {
+        self.threads.iter().all(JoinHandle::is_finished)
+    }
+
+    /// returns the first setting that differs between `config` and the config that
+    /// forwarder runs with and can't be changed by `reload`, `None` if it can be reloaded
+    pub fn needs_restart(&self, config: &ForwarderConfig) -> Option<&'static str> {
+        let current = self.live.load().config;
+        let (listen_uri, current_listen_uri) = (&config.listen_uri, &current.listen_uri);
+        // poll of peers is made for protocol and ip version of remotes
+        let (remote_uri, current_remote_uri) = (&config.remote_uris[0], &current.remote_uris[0]);
+        if listen_uri.addr != current_listen_uri.addr
+            || listen_uri.protocol != current_listen_uri.protocol
+        {
+            Some("listen uri")
+        } else if remote_uri.protocol != current_remote_uri.protocol
+            || remote_uri.addr.is_ipv6() != current_remote_uri.addr.is_ipv6()
+        {
+            Some("protocol of remotes")
+        } else if config.workers != current.workers {
+            Some("workers")
+        } else if config.batch_size != current.batch_size {
+            Some("batch size")
+        } else if config.buffer_size != current.buffer_size {
+            Some("buffer size")
+        } else if config.poll_events_capacity != current.poll_events_capacity {
+            Some("poll events capacity")
+        } else if config.socket_options != current.socket_options {
+            Some("socket options")
+        } else if config.metrics_addr != current.metrics_addr {
+            Some("metrics address")
+        } else if config.control_socket != current.control_socket {
+            Some("control socket")
+        } else if resolve_interval(config) != resolve_interval(&current) {
+            Some("resolve interval")
+        } else {
+            None
+        }
+    }
+
+    /// replaces config of forwarder while it runs, peers are kept unless the remote
+    /// that they are connected to changed or their client isn't allowed anymore, so
+    /// next packet of their client creates a new peer, limits of the kept peers
+    /// are updated and the other settings apply to the next packets
+    ///
+    /// # Error
+    /// returns error if one of the settings that `needs_restart` checks changed or
+    /// uris can't be resolved, forwarder keeps running with its old config then
+    pub fn reload(&self, mut config: ForwarderConfig) -> anyhow::Result<()> {
+        if let Some(setting) = self.needs_restart(&config) {
+            anyhow::bail!("{setting} can't be changed without restarting forwarder");
+        }
+        resolve_uris(&mut config)?;
+        let current = self.live.load();
+        let current_addrs: Vec<SocketAddr> = (0..current.config.remote_uris.len())
+            .map(|index| current.remotes.addr(index))
+            .collect();
+        let remote_addrs: Vec<SocketAddr> = config.remote_uris.iter().map(|uri| uri.addr).collect();
+        let remotes_changed = remote_addrs != current_addrs
+            || config.remote_policy != current.config.remote_policy
+            || config.remote_health_timeout != current.config.remote_health_timeout;
+        let remotes = match remotes_changed {
+            true => Arc::new(new_remotes(&config)),
+            false => current.remotes.clone(),
+        };
+
+        // new peers can't be created with the old config while peers are checked
+        let mut peers = self.peer_manager.write();
+        for peer in peers.get_all() {
+            let remote_index = peer.remote_index();
+            // proxied clients are checked by the address that their proxy header carries
+            let reason = if !config.access_list.is_allowed(&peer.source_addr().ip()) {
+                "its client isn't allowed anymore"
+            } else if remote_addrs.get(remote_index) != Some(&current_addrs[remote_index]) {
+                "its remote changed"
+            } else {
+                peer.set_limits(config.peer_limits);
+                continue;
+            };
+            clean_peer(&mut peers, &self.metrics, peer, reason);
+        }
+        self.live.store(Arc::new(config), remotes);
+        self.shutdown.wake_sleepers();
+        log::info!("reloaded config, {} clients kept", peers.len());
+        Ok(())
+    }
+
+    /// returns counters of each peer that exists at this moment
+    pub fn peers(&self) -> Vec<PeerStats> {
+        // peers are only read under the lock, cleanup needs to be their only owner
+        let peers = self.peer_manager.read();
+        peers.get_all().iter().map(|peer| peer.stats()).collect()
+    }
+
+    /// blocks current thread until forwarder stops and then deregisters all peers,
+    /// returns the first error that caused forwarder to stop
+    pub fn join(self) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for thread in self.threads {
+            let thread_result = thread
+                .join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("forwarder thread panicked")));
+            if result.is_ok() {
+                result = thread_result;
+            }
+        }
+        remove_all_peers(&self.peer_manager);
+        result
+    }
+
+    /// spawns a thread that runs `f` and requests shutdown if `f` returns error so the
+    /// other threads of forwarder stop too, if the thread can't be spawned the already
+    /// spawned threads get stopped
+    fn spawn_thread<F>(&mut self, name: &'static str, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&Shutdown) -> anyhow::Result<()> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        let spawn_result = std::thread::Builder::new()
+            .name(format!("forwarder-{name}"))
+            .spawn(move || {
+                let result = f(&shutdown);
+                if let Err(ref error) = result {
+                    log::error!("{name} thread exited with error: {error:?}");
+                    shutdown.request();
+                }
+                result
+            });
+        match spawn_result {
+            Ok(thread) => {
+                self.threads.push(thread);
+                Ok(())
+            }
+            Err(error) => {
+                self.shutdown.request();
+                for thread in self.threads.drain(..) {
+                    thread.join().ok();
+                }
+                Err(error).with_context(|| format!("couldn't spawn {name} thread"))
+            }
+        }
+    }
+}
+
+/// runs server in current thread until shutdown is requested
 fn run_server(
     socket: Arc<Socket>,
     peer_manager: Arc<RwLock<PeerManager>>,
-    passphrase: Option<String>,
-    remote_uri: Uri,
+    live: Arc<LiveConfig>,
+    metrics: Arc<Metrics>,
+    replay_windows: Arc<[ReplayWindows; 2]>,
+    shutdown: &Shutdown,
 ) {
-    let mut buffer = [0u8; MAX_PACKET_SIZE];
-    loop {
-        let Ok((size, from_addr)) = socket.recv_from(&mut buffer) else {
+    let snapshot = live.load();
+    let mut batch = new_batch(&snapshot.config);
+    let mut handler = ClientPacketHandler {
+        socket,
+        peer_manager,
+        metrics,
+        replay_windows,
+        live,
+        snapshot,
+    };
+    while !shutdown.is_requested() {
+        // socket has read timeout so it doesn't block forever
+        let Ok(count) = handler.socket.recv_from_batch(&mut batch) else {
             continue;
         };
-        if let Some(ref passphrase) = passphrase {
-            encryption::xor_encrypt(&mut buffer[..size], passphrase)
-        }
-        // lock needs to be upgrdable so when new peer appeared
-        // it be able to append it to the peers list
-        let peers = peer_manager.upgradable_read();
-        match peers.find_peer_with_client_addr(&from_addr) {
-            Some(peer) => {
-                peer.set_used();
-                // client ---> server socket ---peer socket----> remote
-                peer.socket.send(&buffer[..size]).ok();
-            }
-            None => {
-                log::info!("new client '{from_addr}'");
-                let peers = RwLockUpgradableReadGuard::upgrade(peers);
-                let peer = match add_new_peer(&remote_uri, from_addr, peers) {
-                    Ok(peer) => peer,
-                    Err(error) => {
-                        log::error!("couldn't add new peer: {error:?}");
-                        continue;
-                    }
-                };
-                // peer is just created so the `used` is true
-                // and doesn't need to set it
-                peer.socket.send(&buffer[..size]).ok();
+        // config may have been reloaded while waiting for packets
+        let reloaded = handler.live.refresh(&mut handler.snapshot);
+        for index in 0..count {
+            let from_addr = batch.packet(index).1;
+            let (buffer, size) = batch.packet_mut(index);
+            handler.on_recv(buffer, size, from_addr);
+        }
+        if reloaded {
+            // transforms of new config may need more space
+            batch = new_batch(&handler.snapshot.config);
+        }
+    }
+}
+
+/// forwards packets that server socket receives from clients to their peers
+struct ClientPacketHandler {
+    socket: Arc<Socket>,
+    peer_manager: Arc<RwLock<PeerManager>>,
+    metrics: Arc<Metrics>,
+    replay_windows: Arc<[ReplayWindows; 2]>,
+    live: Arc<LiveConfig>,
+    /// config and remotes that the current batch of packets is handled with
+    snapshot: Snapshot,
+}
+
+impl ClientPacketHandler {
+    /// forwards packet of `size` bytes at the start of `buffer` to the peer of client
+    /// and creates the peer if it's a new client, packets of clients that are denied
+    /// by access list are dropped before anything is done for them
+    fn on_recv(&self, buffer: &mut [u8], size: usize, from_addr: SocketAddr) {
+        let (metrics, config) = (&self.metrics, &self.snapshot.config);
+        metrics.on_packet(Direction::ClientToRemote, size);
+        // original client of proxied packets is only known after they are decoded
+        if !config.proxy_protocol_listen && !config.access_list.check_client(&from_addr) {
+            metrics.on_drop(DropReason::Denied);
+            // denied clients of tcp don't get to keep their connection
+            self.socket.disconnect(&from_addr);
+            return;
+        }
+        let handshake = config
+            .handshake
+            .as_ref()
+            .filter(|handshake| handshake.side() == Side::Listen);
+        // remote side transforms are applied after proxy header is added
+        let Some((mut size, sequence)) = config.transforms.listen.decode_sequenced(buffer, size)
+        else {
+            metrics.on_drop(DropReason::Transform);
+            return;
+        };
+        // replayed packets from new addresses shouldn't get a peer
+        let direction = Direction::ClientToRemote;
+        if !check_replay(&self.replay_windows, direction, sequence, metrics) {
+            return;
+        }
+        if let Some(handshake) = handshake {
+            // hellos are transformed like the other packets but don't have a proxy header
+            if handshake.is_hello(&buffer[..size]) {
+                self.on_hello(handshake, &buffer[..size], from_addr);
+                return;
             }
+        }
+        let mut addrs = ProxyAddrs {
+            source: from_addr,
+            destination: config.listen_uri.addr,
+        };
+        if config.proxy_protocol_listen {
+            let Some((proxied_addrs, header_size)) = proxy::parse(&buffer[..size]) else {
+                log::debug!("dropped packet of '{from_addr}', it didn't have a valid proxy header");
+                metrics.on_drop(DropReason::ProxyHeader);
+                return;
+            };
+            // local headers are sent by the upstream forwarder itself
+            addrs = proxied_addrs.unwrap_or(addrs);
+            if !config.access_list.check_client(&addrs.source) {
+                metrics.on_drop(DropReason::Denied);
+                return;
+            }
+            buffer.copy_within(header_size..size, 0);
+            size -= header_size;
+        }
+        // workers only share the read lock for packets of existing clients
+        if let Some(peer) = self
+            .peer_manager
+            .read()
+            .find_peer_with_client_addr(&from_addr)
+        {
+            peer.touch_client();
+            if config.proxy_protocol_listen {
+                // peers that are created by a hello don't know the original client yet
+                peer.set_source_addr(addrs.source);
+            }
+            // client ---> server socket ---peer socket----> remote
+            self.send_to_remote(peer, buffer, size, &addrs);
+            return;
+        }
+        if handshake.is_some() {
+            log::debug!("dropped packet of client '{from_addr}', it didn't send a valid hello");
+            metrics.on_drop(DropReason::Unauthenticated);
+            return;
+        }
+        self.with_peer(from_addr, addrs.source, |peer| {
+            self.send_to_remote(peer, buffer, size, &addrs)
+        });
+    }
+
+    /// creates peer of client if `packet` is a valid hello and acknowledges it
+    fn on_hello(&self, handshake: &Handshake, packet: &[u8], from_addr: SocketAddr) {
+        let Some(ack) = handshake.accept_hello(packet) else {
+            log::debug!("dropped invalid hello of client '{from_addr}'");
+            self.metrics.on_drop(DropReason::Unauthenticated);
+            return;
+        };
+        let Some(ack) = encode_handshake(&self.snapshot.config.transforms.listen, &ack) else {
+            self.metrics.on_drop(DropReason::Transform);
+            return;
+        };
+        // client sends hello again if it didn't get the ack, so peer may exist
+        self.with_peer(from_addr, from_addr, |_| {
+            if let Err(error) = self.socket.send_to(&ack, &from_addr) {
+                log::debug!("couldn't send handshake ack to '{from_addr}': {error}");
+                self.metrics.on_send_error(Direction::RemoteToClient);
+            }
+        });
+    }
+
+    /// runs `f` on peer of client, peer is created if it doesn't exist and limits of new
+    /// peers allow it, `source_addr` is the original client if it's proxied and limits
+    /// are applied to it
+    fn with_peer(&self, from_addr: SocketAddr, source_addr: SocketAddr, f: impl FnOnce(&Peer)) {
+        let mut peers = self.peer_manager.write();
+        // reload may have replaced config before the lock was taken, peers
+        // need to be created for the remotes of the new config
+        let Snapshot {
+            config, remotes, ..
+        } = self.live.load();
+        let (metrics, config) = (&self.metrics, &*config);
+        // another worker may have added peer of client while no lock was held
+        if let Some(peer) = peers.find_peer_with_client_addr(&from_addr) {
+            peer.touch_client();
+            if source_addr != from_addr {
+                peer.set_source_addr(source_addr);
+            }
+            f(peer);
+            return;
+        }
+        let max_peers_reached = config
+            .max_peers
+            .is_some_and(|max_peers| peers.len() >= max_peers);
+        // packets that get dropped anyway shouldn't use up tokens of new peers
+        if max_peers_reached && config.max_peers_policy == MaxPeersPolicy::DropNew {
+            log::debug!("dropped packet of new client '{source_addr}', max peers reached");
+            metrics.on_drop(DropReason::MaxPeers);
+            return;
+        }
+        if !config.new_peer_limiter.try_acquire(source_addr.ip()) {
+            log::debug!("dropped packet of new client '{source_addr}', new peer rate exceeded");
+            metrics.on_drop(DropReason::NewPeerRate);
+            return;
+        }
+        if max_peers_reached {
+            evict_least_recently_used(&mut peers, metrics);
+        }
+        log::info!("new client '{source_addr}'");
+        let peer = match add_new_peer(config, &remotes, from_addr, source_addr, peers) {
+            Ok(peer) => peer,
+            Err(error) => {
+                log::error!("couldn't add new peer: {error:?}");
+                metrics.on_drop(DropReason::PeerCreation);
+                return;
+            }
+        };
+        metrics.on_peer_created();
+        // peer is just created so it doesn't need to be touched
+        f(&peer);
+    }
+
+    /// encodes packet of `size` bytes at the start of `buffer` for remote side and sends
+    /// it to remote of `peer`, `addrs` are the original addresses of packet that are put
+    /// in its proxy header
+    fn send_to_remote(&self, peer: &Peer, buffer: &mut [u8], mut size: usize, addrs: &ProxyAddrs) {
+        let (metrics, config) = (&self.metrics, &self.snapshot.config);
+        if config.proxy_protocol_remote {
+            // batch that is made before a reload may not have space for the header
+            let Some(new_size) = proxy::prepend(addrs, buffer, size) else {
+                metrics.on_drop(DropReason::Transform);
+                return;
+            };
+            size = new_size;
+        }
+        let Some(size) = config.transforms.remote.encode(buffer, size) else {
+            metrics.on_drop(DropReason::Transform);
+            return;
         };
+        let packet = &buffer[..size];
+        if !shape(
+            peer,
+            Direction::ClientToRemote,
+            packet.len(),
+            metrics,
+            config,
+        ) {
+            return;
+        }
+        let handshake = config
+            .handshake
+            .as_ref()
+            .filter(|handshake| handshake.side() == Side::Remote);
+        if let Some(handshake) = handshake {
+            let hello = peer
+                .should_send_hello()
+                .then(|| encode_handshake(&config.transforms.remote, &handshake.hello()))
+                .flatten();
+            if let Some(hello) = hello {
+                if peer.socket.send(&hello).is_err() {
+                    peer.on_send_error(Direction::ClientToRemote);
+                    metrics.on_send_error(Direction::ClientToRemote);
+                }
+            }
+            // remote drops the packets of peer until it accepts the hello
+            match peer.hold_until_acked(packet) {
+                Hold::Acked => {}
+                Hold::Held => return,
+                Hold::Full => {
+                    metrics.on_drop(DropReason::Handshake);
+                    return;
+                }
+            }
+        }
+        match peer.socket.send(packet) {
+            Ok(_) => peer.on_forward(Direction::ClientToRemote, packet.len()),
+            Err(_) => {
+                peer.on_send_error(Direction::ClientToRemote);
+                metrics.on_send_error(Direction::ClientToRemote);
+            }
+        }
+        self.snapshot.remotes.on_sent(peer.remote_index());
+    }
+}
+
+/// removes the peer that has been idle the longest to make room for a new peer
+fn evict_least_recently_used(peers: &mut PeerManager, metrics: &Metrics) {
+    let Some(peer) = peers.least_recently_used() else {
+        return;
+    };
+    let client_addr = peer.source_addr();
+    log::info!("evicting peer that handled '{client_addr}', max peers reached");
+    log_peer_stats(&peer);
+    if let Err(error) = peers.remove_peer(peer) {
+        log::warn!("couldn't remove peer of '{client_addr}': {error:?}");
+    }
+    metrics.on_peer_evicted();
+}
+
+/// checks that packet with `sequence` in `direction` is not replayed or too old, packets
+/// that don't have any sequence are never replays
+fn check_replay(
+    windows: &[ReplayWindows; 2],
+    direction: Direction,
+    sequence: Option<Sequence>,
+    metrics: &Metrics,
+) -> bool {
+    let Some(sequence) = sequence else {
+        return true;
+    };
+    if windows[direction as usize].check(sequence, clock::unix_time()) {
+        return true;
+    }
+    metrics.on_drop(DropReason::Replay);
+    false
+}
+
+/// transforms handshake `packet` with `chain` so it looks like the other packets on wire
+fn encode_handshake(chain: &TransformChain, packet: &[u8]) -> Option<Vec<u8>> {
+    let mut buffer = vec![0u8; packet.len() + chain.max_overhead()];
+    buffer[..packet.len()].copy_from_slice(packet);
+    let size = chain.encode(&mut buffer, packet.len())?;
+    buffer.truncate(size);
+    Some(buffer)
+}
+
+/// checks that a packet of `size` bytes in `direction` is within limits of `peer` and
+/// the global limits, packets that exceed them are counted as dropped, `size` is the
+/// size that forwarder sends in both directions so limits match the traffic on wire
+fn shape(
+    peer: &Peer,
+    direction: Direction,
+    size: usize,
+    metrics: &Metrics,
+    config: &ForwarderConfig,
+) -> bool {
+    if peer.try_pass(direction, size) {
+        if config.global_shapers[direction as usize].try_pass(size) {
+            return true;
+        }
+        // dropped packets don't use up the limits of peer
+        peer.refund(direction, size);
     }
+    peer.on_drop(direction);
+    metrics.on_drop(DropReason::Shaping);
+    false
 }
 
-/// creates new `Peer` and appends it to the `PeerManager`
+/// creates new `Peer` that is connected to the remote that `remotes` picks
+/// for client and appends it to the `PeerManager`
 fn add_new_peer(
-    remote_uri: &Uri,
+    config: &ForwarderConfig,
+    remotes: &Remotes,
     from_addr: SocketAddr,
+    source_addr: SocketAddr,
     mut peers: RwLockWriteGuard<PeerManager>,
 ) -> anyhow::Result<Arc<Peer>> {
-    let new_peer = Peer::new(remote_uri, from_addr)?;
+    let remote_index = remotes.select(&source_addr);
+    let new_peer = Peer::new(
+        config.remote_uris[remote_index].protocol,
+        remote_index,
+        &remotes.addr(remote_index),
+        from_addr,
+        &config.socket_options,
+        config.peer_limits,
+    )?;
+    new_peer.set_source_addr(source_addr);
     let peer = peers.add_peer(new_peer)?;
     Ok(peer)
 }
 
-/// spawns peers_thread and panics if it exits
-fn spawn_peers_thread(
-    poll: Box<dyn Poll>,
-    peers: Arc<RwLock<PeerManager>>,
-    server_socket: Arc<Socket>,
-    passphrase: &Option<String>,
-) {
-    let passphrase = passphrase.clone();
-    std::thread::spawn(|| {
-        if let Err(error) = peers_thread(poll, peers, server_socket, passphrase) {
-            log::error!("peers thread exited with error: {error:?}");
-            panic!("peers thread exited")
-        }
-    });
-}
-
 /// blocks current thread and handles all incoming packets to each `Peer`
+/// until shutdown is requested
 fn peers_thread(
     mut poll: Box<dyn Poll>,
     peers: Arc<RwLock<PeerManager>>,
+    live: Arc<LiveConfig>,
+    metrics: Arc<Metrics>,
+    replay_windows: Arc<[ReplayWindows; 2]>,
     server_socket: Arc<Socket>,
-    passphrase: Option<String>,
+    shutdown: &Shutdown,
 ) -> anyhow::Result<()> {
-    let on_peer_recv = Box::new(move |peer: &Peer, buffer: &mut [u8]| {
-        peer.set_used();
-        if let Some(ref passphrase) = passphrase {
-            encryption::xor_encrypt(buffer, passphrase)
+    let snapshot = live.load();
+    let mut handler = RemotePacketHandler {
+        batch: new_batch(&snapshot.config),
+        server_socket,
+        peer_manager: peers.clone(),
+        metrics,
+        replay_windows,
+        live,
+        snapshot,
+    };
+    poll.poll(peers, &mut handler, shutdown)?;
+    Ok(())
+}
+
+/// sends packets that peers receive from remote back to their clients, packets
+/// are queued and sent together with one syscall when possible
+struct RemotePacketHandler {
+    batch: PacketBatch,
+    server_socket: Arc<Socket>,
+    peer_manager: Arc<RwLock<PeerManager>>,
+    metrics: Arc<Metrics>,
+    replay_windows: Arc<[ReplayWindows; 2]>,
+    live: Arc<LiveConfig>,
+    /// config and remotes that packets are handled with
+    snapshot: Snapshot,
+}
+
+impl RemotePacketHandler {
+    /// sends the packets that `peer` held until its hello got acknowledged to remote
+    fn send_held_packets(&self, peer: &Peer, held_packets: Vec<Vec<u8>>) {
+        let direction = Direction::ClientToRemote;
+        for packet in held_packets {
+            match peer.socket.send(&packet) {
+                Ok(_) => peer.on_forward(direction, packet.len()),
+                Err(_) => {
+                    peer.on_send_error(direction);
+                    self.metrics.on_send_error(direction);
+                }
+            }
+        }
+        self.snapshot.remotes.on_sent(peer.remote_index());
+    }
+}
+
+impl PeerHandler for RemotePacketHandler {
+    fn on_recv(&mut self, peer: &Peer, packet: &mut [u8]) {
+        if self.live.refresh(&mut self.snapshot) {
+            // queued packets are already transformed with the old config
+            self.flush();
+            self.batch = new_batch(&self.snapshot.config);
+        }
+        peer.touch_remote();
+        self.snapshot.remotes.on_reply(peer.remote_index());
+        self.metrics
+            .on_packet(Direction::RemoteToClient, packet.len());
+        let direction = Direction::RemoteToClient;
+        if self.batch.is_full() {
+            self.flush();
+        }
+        // batch is not full anymore
+        let slot = self.batch.free_slot().unwrap();
+        // packet may not have enough free space after it for transforms
+        let size = packet.len();
+        slot[..size].copy_from_slice(packet);
+        let config = &self.snapshot.config;
+        let Some((size, sequence)) = config.transforms.remote.decode_sequenced(slot, size) else {
+            self.metrics.on_drop(DropReason::Transform);
+            return;
+        };
+        if !check_replay(&self.replay_windows, direction, sequence, &self.metrics) {
+            return;
+        }
+        // acks are transformed like the other packets of remote
+        let handshake = config.handshake.as_ref();
+        if handshake.is_some_and(|handshake| {
+            handshake.side() == Side::Remote && handshake.verify_ack(&slot[..size])
+        }) {
+            let held_packets = peer.on_handshake_ack();
+            if !held_packets.is_empty() {
+                self.send_held_packets(peer, held_packets);
+            }
+            return;
+        }
+        peer.on_remote_packet();
+        let Some(size) = config.transforms.listen.encode(slot, size) else {
+            self.metrics.on_drop(DropReason::Transform);
+            return;
+        };
+        if !shape(peer, direction, size, &self.metrics, &self.snapshot.config) {
+            return;
         }
         // client <--server socket--- peer <----- remote
-        server_socket.send_to(buffer, peer.get_client_addr()).ok();
-    });
-    poll.poll(peers, on_peer_recv)?;
-    Ok(())
+        self.batch.push(size, *peer.get_client_addr());
+        peer.on_forward(direction, size);
+    }
+
+    fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let failed = self.server_socket.send_batch(&mut self.batch);
+        if !failed.is_empty() {
+            // flush may be called by `on_recv` while poll holds the read lock
+            let peers = self.peer_manager.read_recursive();
+            for index in failed {
+                let client_addr = self.batch.packet(index).1;
+                if let Some(peer) = peers.find_peer_with_client_addr(&client_addr) {
+                    peer.on_send_error(Direction::RemoteToClient);
+                }
+                self.metrics.on_send_error(Direction::RemoteToClient);
+            }
+        }
+        self.batch.clear();
+    }
+
+    fn on_closed(&mut self, peers: &mut PeerManager, port: u16) {
+        if let Some(peer) = peers.get_with_port(&port) {
+            clean_peer(peers, &self.metrics, peer, "its connection closed");
+        }
+    }
 }
 
-/// spawns cleanup thread
-fn spawn_cleanup_thread(peer_manager: Arc<RwLock<PeerManager>>) {
-    std::thread::spawn(move || loop {
-        std::thread::sleep(CLEANUP_INTERVAL);
-        try_cleanup(&peer_manager);
-    });
+/// cleans each peer right after it becomes idle until shutdown is requested
+fn cleanup_thread(
+    peer_manager: &RwLock<PeerManager>,
+    metrics: &Metrics,
+    live: &LiveConfig,
+    shutdown: &Shutdown,
+) {
+    let mut snapshot = live.load();
+    let mut next_cleanup = max_idle_timeout(&snapshot.config);
+    // reload may shorten idle timeouts so it wakes up the thread to apply them right away
+    while shutdown.sleep_or(next_cleanup.max(MIN_CLEANUP_INTERVAL), || {
+        live.is_outdated(&snapshot)
+    }) {
+        live.refresh(&mut snapshot);
+        (next_cleanup, _) = try_cleanup(peer_manager, metrics, &snapshot.config);
+    }
 }
 
-/// tries to clean peers that has not been used for about `CLEANUP_INTERVAL` duration
-fn try_cleanup(peer_manager: &RwLock<PeerManager>) {
+/// cleans peers that are idle, returns the duration until the next peer may become
+/// idle and the number of cleaned peers
+fn try_cleanup(
+    peer_manager: &RwLock<PeerManager>,
+    metrics: &Metrics,
+    config: &ForwarderConfig,
+) -> (Duration, usize) {
+    let now = clock::now_millis();
+    // new peers can't become idle sooner than this
+    let mut next_deadline = now + max_idle_timeout(config).as_millis() as u64;
+    let mut cleaned_count = 0;
+
     let mut peers = peer_manager.write();
-    let mut used_client_count = 0;
     for peer in peers.get_all() {
-        let used = peer.reset_used();
-        if !used {
-            let client_addr = *peer.get_client_addr();
-            log::info!("cleaning peer that handled '{client_addr}'");
-            if let Err(error) = peers.remove_peer(peer) {
-                log::warn!("couldn't remove peer of '{client_addr}': {error:?}");
+        let deadline = peer.idle_deadline(config.client_idle_timeout, config.remote_idle_timeout);
+        if deadline > now {
+            next_deadline = next_deadline.min(deadline);
+            continue;
+        }
+        clean_peer(&mut peers, metrics, peer, "it's idle");
+        cleaned_count += 1;
+    }
+    if cleaned_count > 0 {
+        log::info!("{} clients remaining after cleanup", peers.len());
+    }
+    (Duration::from_millis(next_deadline - now), cleaned_count)
+}
+
+/// removes peer of `client_addr` so next packet of client creates a new peer, proxied
+/// clients can be kicked by their original address too, returns false if client
+/// doesn't have any peer
+fn kick_peer(
+    peer_manager: &RwLock<PeerManager>,
+    metrics: &Metrics,
+    client_addr: &SocketAddr,
+) -> bool {
+    let mut peers = peer_manager.write();
+    let Some(peer) = peers
+        .get_all()
+        .into_iter()
+        .find(|peer| peer.get_client_addr() == client_addr || peer.source_addr() == *client_addr)
+    else {
+        return false;
+    };
+    clean_peer(&mut peers, metrics, peer, "it's kicked");
+    true
+}
+
+/// removes `peer` and logs why and its stats, it's counted as cleaned in `metrics`
+fn clean_peer(peers: &mut PeerManager, metrics: &Metrics, peer: Arc<Peer>, reason: &str) {
+    let client_addr = peer.source_addr();
+    log::info!("cleaning peer that handled '{client_addr}', {reason}");
+    log_peer_stats(&peer);
+    if let Err(error) = peers.remove_peer(peer) {
+        log::warn!("couldn't remove peer of '{client_addr}': {error:?}");
+    }
+    metrics.on_peer_cleaned();
+}
+
+/// resolves hostnames of remotes every `interval` until shutdown is requested and
+/// moves peers to the new address of remote when it changes
+fn resolver_thread(
+    peer_manager: &RwLock<PeerManager>,
+    metrics: &Metrics,
+    live: &LiveConfig,
+    interval: Duration,
+    shutdown: &Shutdown,
+) {
+    while shutdown.sleep(interval) {
+        // remotes may change by reload
+        let Snapshot {
+            config, remotes, ..
+        } = live.load();
+        for (index, remote_uri) in config.remote_uris.iter().enumerate() {
+            // compared with the address that remote got the last time
+            let mut remote_uri = *remote_uri;
+            remote_uri.addr = remotes.addr(index);
+            match remote_uri.resolve(config.resolver.as_ref()) {
+                Ok(true) => {
+                    log::info!(
+                        "remote '{remote_uri}' resolved to new address '{}'",
+                        remote_uri.addr
+                    );
+                    move_peers(
+                        peer_manager,
+                        metrics,
+                        &remotes,
+                        &config,
+                        index,
+                        remote_uri.addr,
+                    );
+                }
+                Ok(false) => (),
+                Err(error) => log::warn!("couldn't resolve remote '{remote_uri}': {error:?}"),
             }
-        } else {
-            used_client_count += 1;
         }
     }
-    log::info!("{used_client_count} clients remaining after cleanup");
+}
+
+/// makes new peers of remote at `remote_index` connect to `remote_addr` and reconnects
+/// the existing ones if `reconnect_peers` is set, peers that can't be reconnected get removed
+fn move_peers(
+    peer_manager: &RwLock<PeerManager>,
+    metrics: &Metrics,
+    remotes: &Remotes,
+    config: &ForwarderConfig,
+    remote_index: usize,
+    remote_addr: SocketAddr,
+) {
+    let mut peers = peer_manager.write();
+    remotes.set_addr(remote_index, remote_addr);
+    if !config.reconnect_peers {
+        return;
+    }
+    for peer in peers.get_all() {
+        if peer.remote_index() != remote_index {
+            continue;
+        }
+        let Err(error) = peer.socket.connect(&remote_addr) else {
+            continue;
+        };
+        // e.g. tcp sockets can't connect again, next packet of client creates a new peer
+        let reason = format!("couldn't reconnect it: {error}");
+        clean_peer(&mut peers, metrics, peer, &reason);
+    }
+}
+
+/// logs the traffic that `peer` forwarded in its lifetime and the packets that it
+/// couldn't send or dropped because they exceeded the limits
+fn log_peer_stats(peer: &Peer) {
+    let stats = peer.stats();
+    let (client, remote) = (Direction::ClientToRemote, Direction::RemoteToClient);
+    log::info!(
+        "peer of '{}' lived {:.1?} and forwarded {} packets ({} bytes) of client and {} packets ({} bytes) of remote",
+        stats.client_addr(),
+        stats.age(),
+        stats.packets(client),
+        stats.bytes(client),
+        stats.packets(remote),
+        stats.bytes(remote)
+    );
+    let (client_errors, remote_errors) = (stats.send_errors(client), stats.send_errors(remote));
+    if client_errors > 0 || remote_errors > 0 {
+        log::info!(
+            "peer of '{}' couldn't send {client_errors} packets of client and {remote_errors} packets of remote",
+            stats.client_addr()
+        );
+    }
+    let (client_dropped, remote_dropped) =
+        (stats.dropped_packets(client), stats.dropped_packets(remote));
+    if client_dropped > 0 || remote_dropped > 0 {
+        log::info!(
+            "peer of '{}' dropped {client_dropped} packets of client and {remote_dropped} packets of remote that exceeded the limits",
+            stats.client_addr()
+        );
+    }
+}
+
+fn max_idle_timeout(config: &ForwarderConfig) -> Duration {
+    config.client_idle_timeout.max(config.remote_idle_timeout)
+}
+
+/// deregisters and removes all peers, only works when other threads
+/// of forwarder are stopped
+fn remove_all_peers(peer_manager: &RwLock<PeerManager>) {
+    let mut peers = peer_manager.write();
+    for peer in peers.get_all() {
+        let client_addr = *peer.get_client_addr();
+        if let Err(error) = peers.remove_peer(peer) {
+            log::warn!("couldn't remove peer of '{client_addr}': {error:?}");
+        }
+    }
 }
@@ -0,0 +1,423 @@
+use crate::clock;
+use anyhow::Context;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// buckets of source ips are checked this often and the full ones are removed,
+/// so spoofed addresses don't stay in memory
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// maximum number of source ips that have a bucket, the ip that is tracked the longest
+/// is forgotten for a new one while it's full so a flood of spoofed addresses can't grow
+/// memory and prune time forever
+const MAX_TRACKED_IPS: usize = 65536;
+
+/// what happens to a new client when max peers is reached
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MaxPeersPolicy {
+    /// packets of new clients are dropped until a peer gets cleaned
+    #[default]
+    DropNew,
+    /// peer that has been idle the longest is removed to make room for new client
+    EvictLru,
+}
+
+impl FromStr for MaxPeersPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "drop-new" => Ok(MaxPeersPolicy::DropNew),
+            "evict-lru" => Ok(MaxPeersPolicy::EvictLru),
+            _ => anyhow::bail!(
+                "invalid max peers policy, valid policies are: 'drop-new' and 'evict-lru'"
+            ),
+        }
+    }
+}
+
+/// rate of a token bucket, `per_second` tokens are added each second and at
+/// most `burst` tokens can be saved
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub per_second: u64,
+    pub burst: u64,
+}
+
+impl Rate {
+    pub fn new(per_second: u64, burst: u64) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+/// parses rates like '1000' or '1000:5000' that the second number is the
+/// burst, burst is same as rate if it's not set
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (per_second, burst) = s.split_once(':').unwrap_or((s, s));
+        let per_second =
+            u64::from_str(per_second).with_context(|| format!("invalid rate '{per_second}'"))?;
+        let burst = u64::from_str(burst).with_context(|| format!("invalid burst '{burst}'"))?;
+        Ok(Self::new(per_second, burst))
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    /// last time in `clock::now_millis` that tokens got added
+    last_refill: u64,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, now: u64) -> Self {
+        Self {
+            tokens: rate.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: u64) {
+        let elapsed = now.saturating_sub(self.last_refill) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * rate.per_second as f64).min(rate.burst as f64);
+        self.last_refill = now;
+    }
+
+    fn has_token(&self) -> bool {
+        self.tokens >= 1.0
+    }
+}
+
+/// limits of traffic that passes in one direction, both bytes and packets are
+/// token buckets and packets that exceed either of them get dropped
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TrafficLimit {
+    /// bytes per second
+    pub bytes: Option<Rate>,
+    /// packets per second
+    pub packets: Option<Rate>,
+}
+
+impl TrafficLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes.is_none() && self.packets.is_none()
+    }
+}
+
+/// drops packets that exceed a `TrafficLimit`
+#[derive(Debug)]
+pub(crate) struct Shaper {
+    /// whether limit is unlimited, so packets don't need to take the lock then
+    unlimited: AtomicBool,
+    state: Mutex<ShaperState>,
+}
+
+#[derive(Debug)]
+struct ShaperState {
+    limit: TrafficLimit,
+    /// buckets of bytes and packets, they are created when first packet passes
+    buckets: Option<(TokenBucket, TokenBucket)>,
+}
+
+impl Default for Shaper {
+    fn default() -> Self {
+        Self::new(TrafficLimit::default())
+    }
+}
+
+impl Shaper {
+    pub fn new(limit: TrafficLimit) -> Self {
+        Self {
+            unlimited: AtomicBool::new(limit.is_unlimited()),
+            state: Mutex::new(ShaperState {
+                limit,
+                buckets: None,
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> TrafficLimit {
+        self.state.lock().limit
+    }
+
+    /// replaces the limit, buckets start full again if it changed
+    pub fn set_limit(&self, limit: TrafficLimit) {
+        let mut state = self.state.lock();
+        if state.limit != limit {
+            *state = ShaperState {
+                limit,
+                buckets: None,
+            };
+            self.unlimited
+                .store(limit.is_unlimited(), Ordering::Relaxed);
+        }
+    }
+
+    /// takes tokens for a packet of `size` bytes, returns false if packet
+    /// exceeds the limit and needs to be dropped
+    pub fn try_pass(&self, size: usize) -> bool {
+        if self.unlimited.load(Ordering::Relaxed) {
+            return true;
+        }
+        let now = clock::now_millis();
+        let mut state = self.state.lock();
+        let ShaperState { limit, buckets } = &mut *state;
+        let unlimited = Rate::new(0, 0);
+        let (bytes_rate, packets_rate) = (
+            limit.bytes.unwrap_or(unlimited),
+            limit.packets.unwrap_or(unlimited),
+        );
+        let (bytes, packets) = buckets.get_or_insert_with(|| {
+            (
+                TokenBucket::new(bytes_rate, now),
+                TokenBucket::new(packets_rate, now),
+            )
+        });
+        if limit.bytes.is_some() {
+            bytes.refill(bytes_rate, now);
+            // packets can be bigger than burst, so bytes are allowed to go in debt
+            if bytes.tokens <= 0.0 {
+                return false;
+            }
+        }
+        if limit.packets.is_some() {
+            packets.refill(packets_rate, now);
+            if !packets.has_token() {
+                return false;
+            }
+            packets.tokens -= 1.0;
+        }
+        if limit.bytes.is_some() {
+            bytes.tokens -= size as f64;
+        }
+        true
+    }
+
+    /// gives back the tokens that `try_pass` took for a packet of `size` bytes,
+    /// e.g. when the packet got dropped by another limit after all
+    pub fn refund(&self, size: usize) {
+        if self.unlimited.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut state = self.state.lock();
+        let ShaperState { limit, buckets } = &mut *state;
+        let Some((bytes, packets)) = buckets else {
+            return;
+        };
+        if let Some(rate) = limit.bytes {
+            bytes.tokens = (bytes.tokens + size as f64).min(rate.burst as f64);
+        }
+        if let Some(rate) = limit.packets {
+            packets.tokens = (packets.tokens + 1.0).min(rate.burst as f64);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    global: Option<TokenBucket>,
+    per_ip: HashMap<IpAddr, TokenBucket>,
+    /// ips of `per_ip` in the order that they got their bucket
+    tracked_order: VecDeque<IpAddr>,
+    /// last time in `clock::now_millis` that full buckets of `per_ip` got removed
+    last_prune: u64,
+}
+
+/// limits how fast new peers can be created, both in total and for each source ip
+#[derive(Debug)]
+pub struct NewPeerLimiter {
+    global_rate: Option<Rate>,
+    per_ip_rate: Option<Rate>,
+    state: Mutex<LimiterState>,
+}
+
+impl Default for NewPeerLimiter {
+    fn default() -> Self {
+        Self {
+            global_rate: None,
+            per_ip_rate: None,
+            state: Mutex::new(LimiterState {
+                global: None,
+                per_ip: HashMap::new(),
+                tracked_order: VecDeque::new(),
+                last_prune: 0,
+            }),
+        }
+    }
+}
+
+impl NewPeerLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_global_rate(&mut self, rate: Rate) {
+        self.global_rate = Some(rate);
+    }
+
+    pub fn set_per_ip_rate(&mut self, rate: Rate) {
+        self.per_ip_rate = Some(rate);
+    }
+
+    pub fn global_rate(&self) -> Option<Rate> {
+        self.global_rate
+    }
+
+    pub fn per_ip_rate(&self) -> Option<Rate> {
+        self.per_ip_rate
+    }
+
+    /// takes a token for a new peer of client with `ip`, returns false if either
+    /// global or per ip rate is exceeded, no token is taken in that case
+    pub(crate) fn try_acquire(&self, ip: IpAddr) -> bool {
+        if self.global_rate.is_none() && self.per_ip_rate.is_none() {
+            return true;
+        }
+        let now = clock::now_millis();
+        let mut state = self.state.lock();
+        let LimiterState {
+            global,
+            per_ip,
+            tracked_order,
+            last_prune,
+        } = &mut *state;
+
+        let global = match self.global_rate {
+            Some(rate) => {
+                let bucket = global.get_or_insert_with(|| TokenBucket::new(rate, now));
+                bucket.refill(rate, now);
+                if !bucket.has_token() {
+                    return false;
+                }
+                Some(bucket)
+            }
+            None => None,
+        };
+        if let Some(rate) = self.per_ip_rate {
+            if now.saturating_sub(*last_prune) >= PRUNE_INTERVAL.as_millis() as u64 {
+                per_ip.retain(|_, bucket| {
+                    bucket.refill(rate, now);
+                    bucket.tokens < rate.burst as f64
+                });
+                tracked_order.retain(|ip| per_ip.contains_key(ip));
+                *last_prune = now;
+            }
+            // clients of dual stack sockets have ipv4 mapped addresses
+            let ip = ip.to_canonical();
+            if !per_ip.contains_key(&ip) {
+                if per_ip.len() >= MAX_TRACKED_IPS {
+                    // oldest ip is most likely a spoofed one that is not coming back
+                    if let Some(oldest) = tracked_order.pop_front() {
+                        per_ip.remove(&oldest);
+                    }
+                }
+                tracked_order.push_back(ip);
+            }
+            let bucket = per_ip
+                .entry(ip)
+                .or_insert_with(|| TokenBucket::new(rate, now));
+            bucket.refill(rate, now);
+            if !bucket.has_token() {
+                return false;
+            }
+            bucket.tokens -= 1.0;
+        }
+        if let Some(bucket) = global {
+            bucket.tokens -= 1.0;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn new_peers_are_limited_globally_and_per_ip() {
+        let ip = |s| IpAddr::from_str(s).unwrap();
+        let mut limiter = NewPeerLimiter::new();
+        limiter.set_global_rate(Rate::new(1, 3));
+        limiter.set_per_ip_rate(Rate::new(1, 2));
+
+        assert!(limiter.try_acquire(ip("10.0.0.1")));
+        assert!(limiter.try_acquire(ip("10.0.0.1")));
+        // burst of ip is used
+        assert!(!limiter.try_acquire(ip("10.0.0.1")));
+        assert!(limiter.try_acquire(ip("10.0.0.2")));
+        // global burst is used, rejected ip didn't take global token
+        assert!(!limiter.try_acquire(ip("10.0.0.3")));
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(limiter.try_acquire(ip("10.0.0.1")));
+        assert!(!limiter.try_acquire(ip("10.0.0.3")));
+    }
+
+    #[test]
+    fn oldest_ip_is_forgotten_when_too_many_are_tracked() {
+        let ip = |bits| IpAddr::from(Ipv4Addr::from_bits(bits));
+        let mut limiter = NewPeerLimiter::new();
+        limiter.set_per_ip_rate(Rate::new(1, 1));
+        for bits in 0..MAX_TRACKED_IPS as u32 {
+            assert!(limiter.try_acquire(ip(bits)));
+        }
+        assert!(!limiter.try_acquire(ip(1)));
+        // new ips still get a peer when the table is full
+        assert!(limiter.try_acquire(ip(u32::MAX)));
+        assert!(!limiter.try_acquire(ip(u32::MAX)));
+        let state = limiter.state.lock();
+        assert_eq!(state.per_ip.len(), MAX_TRACKED_IPS);
+        assert_eq!(state.tracked_order.len(), MAX_TRACKED_IPS);
+        assert!(!state.per_ip.contains_key(&ip(0)));
+        assert!(state.per_ip.contains_key(&ip(1)));
+    }
+
+    #[test]
+    fn rate_is_parsed_with_optional_burst() {
+        assert_eq!(Rate::from_str("100").unwrap(), Rate::new(100, 100));
+        assert_eq!(Rate::from_str("100:500").unwrap(), Rate::new(100, 500));
+        assert!(Rate::from_str("100:").is_err());
+        assert!(Rate::from_str("fast").is_err());
+    }
+
+    #[test]
+    fn shaper_drops_packets_that_exceed_limit() {
+        let shaper = Shaper::new(TrafficLimit {
+            bytes: Some(Rate::new(1000, 1000)),
+            packets: None,
+        });
+        // packets bigger than burst still pass when there are tokens left
+        assert!(shaper.try_pass(1500));
+        assert!(!shaper.try_pass(10));
+
+        let shaper = Shaper::new(TrafficLimit {
+            bytes: None,
+            packets: Some(Rate::new(100, 2)),
+        });
+        assert!(shaper.try_pass(10));
+        assert!(shaper.try_pass(10));
+        assert!(!shaper.try_pass(10));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(shaper.try_pass(10));
+
+        assert!(Shaper::new(TrafficLimit::default()).try_pass(usize::MAX));
+
+        shaper.set_limit(TrafficLimit::default());
+        assert!(shaper.try_pass(10));
+        shaper.set_limit(TrafficLimit {
+            bytes: None,
+            packets: Some(Rate::new(1, 1)),
+        });
+        assert!(shaper.try_pass(10));
+        assert!(!shaper.try_pass(10));
+        shaper.refund(10);
+        assert!(shaper.try_pass(10));
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{config::ForwarderConfig, remote::Remotes};
+use parking_lot::RwLock;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// config and remotes that threads of forwarder use at one moment
+#[derive(Clone)]
+pub(crate) struct Snapshot {
+    version: u64,
+    pub config: Arc<ForwarderConfig>,
+    pub remotes: Arc<Remotes>,
+}
+
+/// config of a running forwarder that can be replaced while it runs, threads keep a
+/// `Snapshot` of it and only take the lock again after a new config is stored
+pub(crate) struct LiveConfig {
+    version: AtomicU64,
+    current: RwLock<Snapshot>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Arc<ForwarderConfig>, remotes: Arc<Remotes>) -> Self {
+        Self {
+            version: AtomicU64::new(0),
+            current: RwLock::new(Snapshot {
+                version: 0,
+                config,
+                remotes,
+            }),
+        }
+    }
+
+    pub fn load(&self) -> Snapshot {
+        self.current.read().clone()
+    }
+
+    /// replaces `snapshot` with the current one if a new config is stored since
+    /// it was loaded, returns whether it got replaced
+    pub fn refresh(&self, snapshot: &mut Snapshot) -> bool {
+        if !self.is_outdated(snapshot) {
+            return false;
+        }
+        *snapshot = self.load();
+        true
+    }
+
+    /// returns whether a new config is stored since `snapshot` was loaded
+    pub fn is_outdated(&self, snapshot: &Snapshot) -> bool {
+        self.version.load(Ordering::Acquire) != snapshot.version
+    }
+
+    pub fn store(&self, config: Arc<ForwarderConfig>, remotes: Arc<Remotes>) {
+        let mut current = self.current.write();
+        let version = current.version + 1;
+        *current = Snapshot {
+            version,
+            config,
+            remotes,
+        };
+        self.version.store(version, Ordering::Release);
+    }
+}
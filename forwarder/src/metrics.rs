@@ -0,0 +1,297 @@
+use crate::{
+    peer::PeerManager,
+    shutdown::{Shutdown, SHUTDOWN_CHECK_INTERVAL},
+};
+use parking_lot::RwLock;
+use socket2::SockRef;
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// maximum time that a client of metrics endpoint can take to send its request
+const METRICS_CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// direction of packets that pass through forwarder
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    ClientToRemote,
+    RemoteToClient,
+}
+
+impl Direction {
+    pub(crate) const ALL: [Direction; 2] = [Direction::ClientToRemote, Direction::RemoteToClient];
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Direction::ClientToRemote => "client_to_remote",
+            Direction::RemoteToClient => "remote_to_client",
+        }
+    }
+}
+
+/// reason of dropping a packet
+#[derive(Clone, Copy, Debug)]
+pub enum DropReason {
+    /// a transform rejected the packet, e.g. decryption failed
+    Transform,
+    /// client is not allowed by access list
+    Denied,
+    /// packet was replayed or is older than the replay window
+    Replay,
+    /// client didn't prove that it knows the key with a valid handshake
+    Unauthenticated,
+    /// packet waited for the handshake of its peer but the peer held too many packets
+    Handshake,
+    /// packet exceeded the traffic limits of its peer or the global limits
+    Shaping,
+    /// packet was from a new client and new peers are created faster than the limit
+    NewPeerRate,
+    /// packet was from a new client and max peers is reached
+    MaxPeers,
+    /// packet was from a new client and its peer couldn't be created
+    PeerCreation,
+    /// packet of client didn't start with a valid proxy protocol header
+    ProxyHeader,
+}
+
+impl DropReason {
+    const ALL: [DropReason; 10] = [
+        DropReason::Transform,
+        DropReason::Replay,
+        DropReason::Denied,
+        DropReason::Unauthenticated,
+        DropReason::Handshake,
+        DropReason::Shaping,
+        DropReason::NewPeerRate,
+        DropReason::MaxPeers,
+        DropReason::PeerCreation,
+        DropReason::ProxyHeader,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            DropReason::Transform => "transform",
+            DropReason::Replay => "replay",
+            DropReason::Denied => "denied",
+            DropReason::Unauthenticated => "unauthenticated",
+            DropReason::Handshake => "handshake",
+            DropReason::Shaping => "shaping",
+            DropReason::NewPeerRate => "new_peer_rate",
+            DropReason::MaxPeers => "max_peers",
+            DropReason::PeerCreation => "peer_creation",
+            DropReason::ProxyHeader => "proxy_header",
+        }
+    }
+}
+
+/// counters of forwarder traffic, they are updated by forwarder threads and
+/// are exposed in prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    packets: [AtomicU64; Direction::ALL.len()],
+    bytes: [AtomicU64; Direction::ALL.len()],
+    send_errors: [AtomicU64; Direction::ALL.len()],
+    dropped_packets: [AtomicU64; DropReason::ALL.len()],
+    peers_created: AtomicU64,
+    peers_cleaned: AtomicU64,
+    peers_evicted: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// records a packet of `size` bytes that is received in `direction`
+    pub fn on_packet(&self, direction: Direction, size: usize) {
+        self.packets[direction as usize].fetch_add(1, Ordering::Relaxed);
+        self.bytes[direction as usize].fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_send_error(&self, direction: Direction) {
+        self.send_errors[direction as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_drop(&self, reason: DropReason) {
+        self.dropped_packets[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_peer_created(&self) {
+        self.peers_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_peer_cleaned(&self) {
+        self.peers_cleaned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_peer_evicted(&self) {
+        self.peers_evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// renders metrics in prometheus text format
+    pub fn render(&self, active_peers: usize) -> String {
+        let mut output = String::new();
+        let mut write_metric = |name: &str, kind: &str, help: &str, values: &[(String, u64)]| {
+            // writing to `String` never fails
+            writeln!(output, "# HELP {name} {help}").unwrap();
+            writeln!(output, "# TYPE {name} {kind}").unwrap();
+            for (labels, value) in values {
+                writeln!(output, "{name}{labels} {value}").unwrap();
+            }
+        };
+        let by_direction = |counters: &[AtomicU64]| -> Vec<(String, u64)> {
+            Direction::ALL
+                .iter()
+                .map(|direction| {
+                    let labels = format!("{{direction=\"{}\"}}", direction.label());
+                    (
+                        labels,
+                        counters[*direction as usize].load(Ordering::Relaxed),
+                    )
+                })
+                .collect()
+        };
+        let no_labels = |value: u64| vec![(String::new(), value)];
+
+        write_metric(
+            "forwarder_packets_total",
+            "counter",
+            "Packets that are received by forwarder",
+            &by_direction(&self.packets),
+        );
+        write_metric(
+            "forwarder_bytes_total",
+            "counter",
+            "Bytes of packets that are received by forwarder",
+            &by_direction(&self.bytes),
+        );
+        write_metric(
+            "forwarder_send_errors_total",
+            "counter",
+            "Packets that couldn't be sent",
+            &by_direction(&self.send_errors),
+        );
+        let dropped_packets: Vec<(String, u64)> = DropReason::ALL
+            .iter()
+            .map(|reason| {
+                let labels = format!("{{reason=\"{}\"}}", reason.label());
+                let value = self.dropped_packets[*reason as usize].load(Ordering::Relaxed);
+                (labels, value)
+            })
+            .collect();
+        write_metric(
+            "forwarder_dropped_packets_total",
+            "counter",
+            "Packets that are dropped by forwarder",
+            &dropped_packets,
+        );
+        write_metric(
+            "forwarder_active_peers",
+            "gauge",
+            "Peers that currently exist",
+            &no_labels(active_peers as u64),
+        );
+        write_metric(
+            "forwarder_peers_created_total",
+            "counter",
+            "Peers that are created for new clients",
+            &no_labels(self.peers_created.load(Ordering::Relaxed)),
+        );
+        write_metric(
+            "forwarder_peers_cleaned_total",
+            "counter",
+            "Peers that are cleaned because they were idle",
+            &no_labels(self.peers_cleaned.load(Ordering::Relaxed)),
+        );
+        write_metric(
+            "forwarder_peers_evicted_total",
+            "counter",
+            "Peers that are removed to make room for new clients when max peers is reached",
+            &no_labels(self.peers_evicted.load(Ordering::Relaxed)),
+        );
+        output
+    }
+}
+
+/// creates listener of metrics endpoint, it's created before threads of
+/// forwarder so errors like address being in use are returned early
+pub fn bind(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr)?;
+    // accept respects receive timeout in linux so it doesn't block forever
+    SockRef::from(&listener).set_read_timeout(Some(SHUTDOWN_CHECK_INTERVAL))?;
+    Ok(listener)
+}
+
+/// serves metrics over http on `listener` until shutdown is requested
+pub fn serve(
+    listener: TcpListener,
+    metrics: &Metrics,
+    peer_manager: &RwLock<PeerManager>,
+    shutdown: &Shutdown,
+) {
+    while !shutdown.is_requested() {
+        let Ok((stream, from_addr)) = listener.accept() else {
+            continue;
+        };
+        let body = metrics.render(peer_manager.read().len());
+        if let Err(error) = respond(stream, &body) {
+            log::debug!("couldn't serve metrics to '{from_addr}': {error}");
+        }
+    }
+}
+
+fn respond(stream: TcpStream, body: &str) -> io::Result<()> {
+    stream.set_read_timeout(Some(METRICS_CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(METRICS_CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // rest of request is only headers that we don't care about
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", body)
+    } else {
+        ("404 Not Found", "")
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_in_prometheus_format() {
+        let metrics = Metrics::new();
+        metrics.on_packet(Direction::ClientToRemote, 10);
+        metrics.on_packet(Direction::ClientToRemote, 5);
+        metrics.on_drop(DropReason::MaxPeers);
+        metrics.on_peer_created();
+
+        let output = metrics.render(1);
+        assert!(output.contains("# TYPE forwarder_packets_total counter\n"));
+        assert!(output.contains("forwarder_packets_total{direction=\"client_to_remote\"} 2\n"));
+        assert!(output.contains("forwarder_bytes_total{direction=\"client_to_remote\"} 15\n"));
+        assert!(output.contains("forwarder_bytes_total{direction=\"remote_to_client\"} 0\n"));
+        assert!(output.contains("forwarder_dropped_packets_total{reason=\"max_peers\"} 1\n"));
+        assert!(output.contains("forwarder_active_peers 1\n"));
+        assert!(output.contains("forwarder_peers_created_total 1\n"));
+    }
+}
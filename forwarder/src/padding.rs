@@ -0,0 +1,211 @@
+use crate::{transform::Transform, uri::Protocol};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// size of the length of padding that is appended after padding
+const LENGTH_LEN: usize = 2;
+
+/// returns the size of the headers that are put before each packet on wire when it's
+/// sent with `protocol` over ipv4 or ipv6, tcp streams also put the length of each
+/// packet before it
+pub fn headers_len(protocol: Protocol, ipv6: bool) -> usize {
+    let ip_header_len = if ipv6 { 40 } else { 20 };
+    let protocol_header_len = match protocol {
+        Protocol::Udp => 8,
+        Protocol::Icmp => 8,
+        Protocol::Tcp => 20 + 2,
+    };
+    ip_header_len + protocol_header_len
+}
+
+/// how the length of padding of each packet is picked
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PaddingDistribution {
+    /// every length between zero and max padding is as likely
+    #[default]
+    Uniform,
+    /// short paddings are more likely, it costs less bandwidth but sizes still vary
+    Exponential,
+}
+
+impl FromStr for PaddingDistribution {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "uniform" => Ok(PaddingDistribution::Uniform),
+            "exponential" => Ok(PaddingDistribution::Exponential),
+            _ => anyhow::bail!(
+                "invalid padding distribution, valid distributions are: 'uniform' and 'exponential'"
+            ),
+        }
+    }
+}
+
+/// appends random bytes of random length to packets so their size doesn't reveal what
+/// they are, it needs to be before encryption in the same chain so length of padding
+/// gets encrypted too
+///
+/// # Examples
+/// ```
+/// use forwarder::{padding::{Padding, PaddingDistribution}, transform::Transform};
+///
+/// let padding = Padding::new(64, PaddingDistribution::Uniform);
+/// let mut buffer = [0u8; 5 + 64 + 2];
+/// buffer[..5].copy_from_slice(b"hello");
+/// let size = padding.encode(&mut buffer, 5).unwrap();
+/// assert!(size > 5);
+/// assert_eq!(padding.decode(&mut buffer, size), Some(5));
+/// ```
+#[derive(Debug)]
+pub struct Padding {
+    max_padding: usize,
+    distribution: PaddingDistribution,
+    /// packets are not padded to more than this size, e.g. to stay under mtu of path
+    max_packet_size: usize,
+    /// state of random generator of padding, splitmix64 so threads can share it cheaply
+    random_state: AtomicU64,
+}
+
+impl Padding {
+    /// creates padding that adds up to `max_padding` random bytes to each packet
+    pub fn new(max_padding: usize, distribution: PaddingDistribution) -> Self {
+        Self {
+            // length is kept in two bytes
+            max_padding: max_padding.min(u16::MAX as usize),
+            distribution,
+            max_packet_size: usize::MAX,
+            random_state: AtomicU64::new(OsRng.next_u64()),
+        }
+    }
+
+    /// packets are only padded while they are at most `max_packet_size` bytes, it's the
+    /// payload of a packet on wire so its headers (`headers_len`) need to be subtracted
+    /// from mtu of path, e.g. 1472 for udp on ipv4 and 1452 on ipv6 when mtu is 1500,
+    /// encryption overhead is added after padding so it needs to be subtracted too
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    fn next_random(&self) -> u64 {
+        let mut z = self
+            .random_state
+            .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// picks length of padding of a packet, at most `max_len`
+    fn padding_len(&self, max_len: usize) -> usize {
+        if max_len == 0 {
+            return 0;
+        }
+        let random = self.next_random();
+        let len = match self.distribution {
+            PaddingDistribution::Uniform => (random % (max_len as u64 + 1)) as usize,
+            PaddingDistribution::Exponential => {
+                // mean of a quarter of max padding, longer ones are cut to max
+                let uniform = (random >> 11) as f64 / (1u64 << 53) as f64;
+                let mean = self.max_padding as f64 / 4.0;
+                (-(1.0 - uniform).ln() * mean) as usize
+            }
+        };
+        len.min(max_len)
+    }
+}
+
+impl Transform for Padding {
+    fn encode(&self, buffer: &mut [u8], size: usize) -> Option<usize> {
+        let max_len = self
+            .max_padding
+            .min(buffer.len().checked_sub(size + LENGTH_LEN)?)
+            .min(self.max_packet_size.saturating_sub(size + LENGTH_LEN));
+        let padding_len = self.padding_len(max_len);
+        // padding is random so it doesn't leak anything through weak ciphers like xor
+        for chunk in buffer[size..size + padding_len].chunks_mut(8) {
+            let random = self.next_random().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+        let end = size + padding_len;
+        buffer[end..end + LENGTH_LEN].copy_from_slice(&(padding_len as u16).to_be_bytes());
+        Some(end + LENGTH_LEN)
+    }
+
+    fn decode(&self, buffer: &mut [u8], size: usize) -> Option<usize> {
+        let length_start = size.checked_sub(LENGTH_LEN)?;
+        let padding_len = u16::from_be_bytes(buffer[length_start..size].try_into().ok()?) as usize;
+        length_start.checked_sub(padding_len)
+    }
+
+    fn max_overhead(&self) -> usize {
+        self.max_padding + LENGTH_LEN
+    }
+
+    fn pads(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_packets_have_different_sizes() {
+        for distribution in [
+            PaddingDistribution::Uniform,
+            PaddingDistribution::Exponential,
+        ] {
+            let padding = Padding::new(100, distribution);
+            let mut sizes = Vec::new();
+            for _ in 0..50 {
+                let mut buffer = [0u8; 148 + 100 + LENGTH_LEN];
+                buffer[..148].fill(7);
+                let size = padding.encode(&mut buffer, 148).unwrap();
+                assert!((148 + LENGTH_LEN..=148 + 100 + LENGTH_LEN).contains(&size));
+                assert_eq!(padding.decode(&mut buffer, size), Some(148));
+                assert_eq!(buffer[..148], [7; 148]);
+                sizes.push(size);
+            }
+            sizes.sort();
+            sizes.dedup();
+            assert!(sizes.len() > 5, "{distribution:?} padding isn't random");
+        }
+    }
+
+    #[test]
+    fn padding_respects_max_packet_size() {
+        let padding = Padding::new(100, PaddingDistribution::Uniform).with_max_packet_size(160);
+        for _ in 0..50 {
+            let mut buffer = [0u8; 148 + 100 + LENGTH_LEN];
+            let size = padding.encode(&mut buffer, 148).unwrap();
+            assert!(size <= 160);
+        }
+        // packets that are already too big only get the length
+        let mut buffer = [0u8; 200 + 100 + LENGTH_LEN];
+        assert_eq!(padding.encode(&mut buffer, 200), Some(200 + LENGTH_LEN));
+    }
+
+    #[test]
+    fn headers_depend_on_protocol_and_ip_version() {
+        assert_eq!(headers_len(Protocol::Udp, false), 28);
+        assert_eq!(headers_len(Protocol::Udp, true), 48);
+        assert_eq!(headers_len(Protocol::Icmp, false), 28);
+        assert_eq!(headers_len(Protocol::Tcp, false), 42);
+        assert_eq!(headers_len(Protocol::Tcp, true), 62);
+    }
+
+    #[test]
+    fn invalid_padding_is_dropped() {
+        let padding = Padding::new(100, PaddingDistribution::Uniform);
+        let mut buffer = [0u8; 10];
+        assert_eq!(padding.decode(&mut buffer, 1), None);
+        buffer[..4].copy_from_slice(&[0, 0, 0, 5]);
+        assert_eq!(padding.decode(&mut buffer, 4), None);
+    }
+}
@@ -1,51 +1,369 @@
+use crate::clock;
+use crate::handshake::{HELLO_RETRY_INTERVAL, MAX_HELLO_RETRY_INTERVAL, REPLY_TIMEOUT};
+use crate::limit::{Shaper, TrafficLimit};
+use crate::metrics::Direction;
 use crate::poll::Registry;
-use crate::socket::NonBlockingSocket;
-use crate::uri::Uri;
+use crate::socket::{NonBlockingSocket, Socket, SocketOptions};
+use crate::uri::Protocol;
+use parking_lot::Mutex;
 use std::fmt::Debug;
 use std::{
     borrow::Borrow,
     collections::BTreeMap,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::atomic::Ordering,
-    sync::{atomic::AtomicBool, Arc},
+    sync::{
+        atomic::{AtomicU32, AtomicU64},
+        Arc,
+    },
+    time::Duration,
 };
 
+/// packets that a peer holds at most while it waits for the ack of its first hello
+const MAX_HELD_PACKETS: usize = 32;
+
 #[derive(Debug)]
 pub struct Peer {
     pub socket: NonBlockingSocket,
     client_addr: SocketAddr,
-    used: AtomicBool,
+    /// original client that proxy header of packets carries, same as `client_addr`
+    /// if packets of client don't have any
+    source_addr: Mutex<SocketAddr>,
+    /// index of remote in `Remotes` that peer is connected to
+    remote_index: usize,
+    /// time in `clock::now_millis` that peer got created
+    created_at: u64,
+    /// last time in `clock::now_millis` that client sent a packet
+    last_client_activity: AtomicU64,
+    /// last time in `clock::now_millis` that remote sent a packet
+    last_remote_activity: AtomicU64,
+    /// limits of traffic of peer in each `Direction`
+    shapers: [Shaper; 2],
+    /// packets of each `Direction` that are dropped because they exceeded the limits
+    dropped_packets: [AtomicU64; 2],
+    /// packets that are forwarded in each `Direction`
+    forwarded_packets: [AtomicU64; 2],
+    /// bytes of packets that are forwarded in each `Direction`
+    forwarded_bytes: [AtomicU64; 2],
+    /// packets of each `Direction` that couldn't be sent
+    send_errors: [AtomicU64; 2],
+    /// state of the handshake of peer, only used when handshake is on remote side
+    handshake: Mutex<HandshakeState>,
+    /// last time in `clock::now_millis` that peer sent hello, `u64::MAX` if it never did
+    last_hello: AtomicU64,
+    /// hellos that are sent since remote last sent a packet that isn't an ack
+    unanswered_hellos: AtomicU32,
 }
 
 impl Peer {
-    pub fn new(remote_uri: &Uri, client_addr: SocketAddr) -> anyhow::Result<Self> {
-        let addr = create_any_addr(remote_uri.addr.is_ipv6());
-        let mut socket = NonBlockingSocket::bind(remote_uri.protocol, &addr)?;
-        socket.connect(&remote_uri.addr)?;
+    pub fn new(
+        remote_protocol: Protocol,
+        remote_index: usize,
+        remote_addr: &SocketAddr,
+        client_addr: SocketAddr,
+        socket_options: &SocketOptions,
+        limits: [TrafficLimit; 2],
+    ) -> anyhow::Result<Self> {
+        let addr = create_any_addr(remote_addr.is_ipv6());
+        let socket = NonBlockingSocket::bind(remote_protocol, &addr, socket_options)?;
+        socket.connect(remote_addr)?;
+        let now = clock::now_millis();
         let peer = Self {
             socket,
             client_addr,
-            used: AtomicBool::new(true),
+            source_addr: Mutex::new(client_addr),
+            remote_index,
+            created_at: now,
+            last_client_activity: AtomicU64::new(now),
+            last_remote_activity: AtomicU64::new(now),
+            shapers: limits.map(Shaper::new),
+            dropped_packets: Default::default(),
+            forwarded_packets: Default::default(),
+            forwarded_bytes: Default::default(),
+            send_errors: Default::default(),
+            handshake: Mutex::new(HandshakeState::Waiting(Vec::new())),
+            last_hello: AtomicU64::new(u64::MAX),
+            unanswered_hellos: AtomicU32::new(0),
         };
         Ok(peer)
     }
 
-    /// mark `Peer` as being used to prevent cleanup thread from cleaning it
-    pub fn set_used(&self) {
-        self.used
-            .compare_exchange_weak(false, true, Ordering::Relaxed, Ordering::Relaxed)
-            .ok();
+    /// records that client just sent a packet
+    pub fn touch_client(&self) {
+        self.last_client_activity
+            .store(clock::now_millis(), Ordering::Relaxed);
+    }
+
+    /// records that remote just sent a packet
+    pub fn touch_remote(&self) {
+        self.last_remote_activity
+            .store(clock::now_millis(), Ordering::Relaxed);
+    }
+
+    /// returns the time in `clock::now_millis` that peer becomes idle, peer is idle when
+    /// client has been idle for `client_idle_timeout` and remote has been idle
+    /// for `remote_idle_timeout`
+    pub fn idle_deadline(
+        &self,
+        client_idle_timeout: Duration,
+        remote_idle_timeout: Duration,
+    ) -> u64 {
+        let client_deadline = self.last_client_activity.load(Ordering::Relaxed)
+            + client_idle_timeout.as_millis() as u64;
+        let remote_deadline = self.last_remote_activity.load(Ordering::Relaxed)
+            + remote_idle_timeout.as_millis() as u64;
+        client_deadline.max(remote_deadline)
+    }
+
+    /// takes tokens for a packet of `size` bytes in `direction`, returns false if
+    /// packet exceeds the limits of peer
+    pub fn try_pass(&self, direction: Direction, size: usize) -> bool {
+        self.shapers[direction as usize].try_pass(size)
+    }
+
+    /// gives back the tokens that `try_pass` took for a packet that got dropped after all
+    pub fn refund(&self, direction: Direction, size: usize) {
+        self.shapers[direction as usize].refund(size)
+    }
+
+    /// replaces limits of traffic of peer in each `Direction`
+    pub fn set_limits(&self, limits: [TrafficLimit; 2]) {
+        for (shaper, limit) in self.shapers.iter().zip(limits) {
+            shaper.set_limit(limit);
+        }
+    }
+
+    /// records that a packet of `direction` got dropped because it exceeded the limits
+    pub fn on_drop(&self, direction: Direction) {
+        self.dropped_packets[direction as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// records that a packet of `size` bytes got forwarded in `direction`
+    pub fn on_forward(&self, direction: Direction, size: usize) {
+        self.forwarded_packets[direction as usize].fetch_add(1, Ordering::Relaxed);
+        self.forwarded_bytes[direction as usize].fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    /// records that a packet of `direction` couldn't be sent
+    pub fn on_send_error(&self, direction: Direction) {
+        self.send_errors[direction as usize].fetch_add(1, Ordering::Relaxed);
     }
 
-    /// mark `Peer` as not being in use and returns `true` if it was used
-    /// before reseting otherwise returns `false`
-    pub fn reset_used(&self) -> bool {
-        self.used.swap(false, Ordering::Relaxed)
+    /// returns counters of peer at this moment
+    pub fn stats(&self) -> PeerStats {
+        let now = clock::now_millis();
+        let elapsed = |since: u64| Duration::from_millis(now.saturating_sub(since));
+        let load = |counters: &[AtomicU64; 2]| {
+            counters
+                .each_ref()
+                .map(|counter| counter.load(Ordering::Relaxed))
+        };
+        let source_addr = self.source_addr();
+        PeerStats {
+            client_addr: source_addr,
+            proxy_addr: (source_addr != self.client_addr).then_some(self.client_addr),
+            local_port: self
+                .socket
+                .local_addr()
+                .map(|addr| addr.port())
+                .unwrap_or_default(),
+            remote_index: self.remote_index,
+            age: elapsed(self.created_at),
+            client_idle: elapsed(self.last_client_activity.load(Ordering::Relaxed)),
+            remote_idle: elapsed(self.last_remote_activity.load(Ordering::Relaxed)),
+            packets: load(&self.forwarded_packets),
+            bytes: load(&self.forwarded_bytes),
+            send_errors: load(&self.send_errors),
+            dropped_packets: load(&self.dropped_packets),
+        }
+    }
+
+    /// returns whether peer needs to send a hello to remote before its next packet, it's
+    /// true until remote acknowledges the first hello and again whenever remote doesn't
+    /// send anything for `REPLY_TIMEOUT`, the wait between hellos starts from
+    /// `HELLO_RETRY_INTERVAL` and doubles up to `MAX_HELLO_RETRY_INTERVAL` until remote
+    /// sends a packet that isn't an ack
+    pub fn should_send_hello(&self) -> bool {
+        let now = clock::now_millis();
+        // remote that lost the peer drops its packets without answering them, so it
+        // needs a new hello, ack of the hello is a reply so it stops once remote is back
+        if matches!(*self.handshake.lock(), HandshakeState::Acked)
+            && now.saturating_sub(self.last_remote_activity.load(Ordering::Relaxed))
+                < REPLY_TIMEOUT.as_millis() as u64
+        {
+            return false;
+        }
+        let last_hello = self.last_hello.load(Ordering::Relaxed);
+        let unanswered_hellos = self.unanswered_hellos.load(Ordering::Relaxed);
+        if last_hello != u64::MAX
+            && now.saturating_sub(last_hello) < hello_retry_interval(unanswered_hellos)
+        {
+            return false;
+        }
+        self.last_hello.store(now, Ordering::Relaxed);
+        self.unanswered_hellos
+            .store(unanswered_hellos.saturating_add(1), Ordering::Relaxed);
+        true
+    }
+
+    /// holds `packet` until remote acknowledges the first hello of peer, packets can't
+    /// be held anymore once peer holds `MAX_HELD_PACKETS` of them
+    pub fn hold_until_acked(&self, packet: &[u8]) -> Hold {
+        let mut handshake = self.handshake.lock();
+        let HandshakeState::Waiting(held_packets) = &mut *handshake else {
+            return Hold::Acked;
+        };
+        if held_packets.len() >= MAX_HELD_PACKETS {
+            return Hold::Full;
+        }
+        held_packets.push(packet.to_vec());
+        Hold::Held
+    }
+
+    /// records that remote acknowledged a hello of peer, returns the packets that were
+    /// held until then in the order that they should be sent
+    pub fn on_handshake_ack(&self) -> Vec<Vec<u8>> {
+        match std::mem::replace(&mut *self.handshake.lock(), HandshakeState::Acked) {
+            HandshakeState::Waiting(held_packets) => held_packets,
+            HandshakeState::Acked => Vec::new(),
+        }
+    }
+
+    /// records that remote sent a packet that isn't an ack, so it still has the peer
+    pub fn on_remote_packet(&self) {
+        if self.unanswered_hellos.load(Ordering::Relaxed) != 0 {
+            self.unanswered_hellos.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// returns the last time in `clock::now_millis` that either client or remote sent a packet
+    pub fn last_activity(&self) -> u64 {
+        let client_activity = self.last_client_activity.load(Ordering::Relaxed);
+        client_activity.max(self.last_remote_activity.load(Ordering::Relaxed))
     }
 
     pub fn get_client_addr(&self) -> &SocketAddr {
         &self.client_addr
     }
+
+    /// returns the original client, it's `client_addr` unless client is proxied
+    pub fn source_addr(&self) -> SocketAddr {
+        *self.source_addr.lock()
+    }
+
+    pub fn set_source_addr(&self, source_addr: SocketAddr) {
+        *self.source_addr.lock() = source_addr;
+    }
+
+    pub fn remote_index(&self) -> usize {
+        self.remote_index
+    }
+}
+
+/// counters of a peer at one moment, it's returned by `ForwarderHandle::peers`
+/// handshake state of a peer that has handshake on its remote side
+#[derive(Debug)]
+enum HandshakeState {
+    /// remote didn't acknowledge any hello yet, packets are held until it does
+    Waiting(Vec<Vec<u8>>),
+    Acked,
+}
+
+/// what happened to a packet that is given to `Peer::hold_until_acked`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hold {
+    /// handshake is already acknowledged so packet can be sent right away
+    Acked,
+    /// packet is held until handshake is acknowledged
+    Held,
+    /// packet couldn't be held since peer holds too many packets
+    Full,
+}
+
+/// returns how long in milliseconds a peer waits for an answer after it sent
+/// `unanswered_hellos` hellos before it sends the next one
+fn hello_retry_interval(unanswered_hellos: u32) -> u64 {
+    let interval = HELLO_RETRY_INTERVAL.as_millis() as u64;
+    let shift = unanswered_hellos.saturating_sub(1).min(16);
+    (interval << shift).min(MAX_HELLO_RETRY_INTERVAL.as_millis() as u64)
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    client_addr: SocketAddr,
+    proxy_addr: Option<SocketAddr>,
+    local_port: u16,
+    remote_index: usize,
+    age: Duration,
+    client_idle: Duration,
+    remote_idle: Duration,
+    packets: [u64; 2],
+    bytes: [u64; 2],
+    send_errors: [u64; 2],
+    dropped_packets: [u64; 2],
+}
+
+impl PeerStats {
+    /// returns the original client, proxied clients have the address that proxy header carries
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+
+    /// returns the address that packets of a proxied client come from, e.g. a port of
+    /// the forwarder that added proxy headers, `None` if client isn't proxied
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy_addr
+    }
+
+    /// returns port of the socket that peer sends packets of client to remote from
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// returns index of remote in remote uris of config that peer is connected to
+    pub fn remote_index(&self) -> usize {
+        self.remote_index
+    }
+
+    /// returns time since peer got created
+    pub fn age(&self) -> Duration {
+        self.age
+    }
+
+    /// returns time since client sent a packet
+    pub fn client_idle(&self) -> Duration {
+        self.client_idle
+    }
+
+    /// returns time since remote sent a packet
+    pub fn remote_idle(&self) -> Duration {
+        self.remote_idle
+    }
+
+    /// returns time since either client or remote sent a packet
+    pub fn idle(&self) -> Duration {
+        self.client_idle.min(self.remote_idle)
+    }
+
+    /// returns packets that are forwarded in `direction`
+    pub fn packets(&self, direction: Direction) -> u64 {
+        self.packets[direction as usize]
+    }
+
+    /// returns bytes of packets that are forwarded in `direction`
+    pub fn bytes(&self, direction: Direction) -> u64 {
+        self.bytes[direction as usize]
+    }
+
+    /// returns packets of `direction` that couldn't be sent
+    pub fn send_errors(&self, direction: Direction) -> u64 {
+        self.send_errors[direction as usize]
+    }
+
+    /// returns packets of `direction` that are dropped because they exceeded the limits
+    pub fn dropped_packets(&self, direction: Direction) -> u64 {
+        self.dropped_packets[direction as usize]
+    }
 }
 
 pub fn create_any_addr(is_ipv6: bool) -> SocketAddr {
@@ -65,14 +383,17 @@ pub struct PeerManager {
     client_addr_to_peers: BTreeMap<SocketAddr, Arc<Peer>>,
     port_to_peers: BTreeMap<u16, Arc<Peer>>,
     registry: Box<dyn Registry>,
+    /// server socket that clients are connected to, their connection is closed with their peer
+    server_socket: Arc<Socket>,
 }
 
 impl PeerManager {
-    pub fn new(registry: Box<dyn Registry>) -> Self {
+    pub fn new(registry: Box<dyn Registry>, server_socket: Arc<Socket>) -> Self {
         Self {
             client_addr_to_peers: BTreeMap::new(),
             port_to_peers: BTreeMap::new(),
             registry,
+            server_socket,
         }
     }
 
@@ -96,13 +417,31 @@ impl PeerManager {
         self.port_to_peers.get(port).map(|peer| peer.borrow())
     }
 
+    pub fn len(&self) -> usize {
+        self.client_addr_to_peers.len()
+    }
+
+    /// returns the peer that has been idle the longest
+    pub fn least_recently_used(&self) -> Option<Arc<Peer>> {
+        self.client_addr_to_peers
+            .values()
+            .min_by_key(|peer| peer.last_activity())
+            .cloned()
+    }
+
     pub fn get_all(&self) -> Vec<Arc<Peer>> {
         self.client_addr_to_peers.values().cloned().collect()
     }
 
+    /// returns the peer that its socket is bound to `port` so it can be removed
+    pub fn get_with_port(&self, port: &u16) -> Option<Arc<Peer>> {
+        self.port_to_peers.get(port).cloned()
+    }
+
     pub fn remove_peer(&mut self, peer: Arc<Peer>) -> anyhow::Result<()> {
         self.client_addr_to_peers.remove(&peer.client_addr);
         self.port_to_peers.remove(&peer.socket.local_addr()?.port());
+        self.server_socket.disconnect(&peer.client_addr);
 
         let mut peer =
             Arc::try_unwrap(peer).map_err(|_| anyhow::anyhow!("can't unwrap Arc<peer>"))?;
@@ -110,3 +449,46 @@ impl PeerManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_peer() -> Peer {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 9));
+        let options = SocketOptions::default();
+        Peer::new(Protocol::Udp, 0, &addr, addr, &options, Default::default()).unwrap()
+    }
+
+    #[test]
+    fn hellos_back_off_until_remote_answers() {
+        let interval = HELLO_RETRY_INTERVAL.as_millis() as u64;
+        assert_eq!(hello_retry_interval(1), interval);
+        assert_eq!(hello_retry_interval(2), interval * 2);
+        assert_eq!(hello_retry_interval(3), interval * 4);
+        let max_interval = MAX_HELLO_RETRY_INTERVAL.as_millis() as u64;
+        assert_eq!(hello_retry_interval(u32::MAX), max_interval);
+
+        let peer = new_peer();
+        assert!(peer.should_send_hello());
+        assert!(!peer.should_send_hello());
+        peer.on_remote_packet();
+        assert_eq!(peer.unanswered_hellos.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn packets_are_held_until_hello_is_acked() {
+        let peer = new_peer();
+        for index in 0..MAX_HELD_PACKETS {
+            assert_eq!(peer.hold_until_acked(&[index as u8]), Hold::Held);
+        }
+        assert_eq!(peer.hold_until_acked(b"too many"), Hold::Full);
+        let held_packets = peer.on_handshake_ack();
+        assert_eq!(held_packets.len(), MAX_HELD_PACKETS);
+        assert_eq!(held_packets[1], [1]);
+        assert_eq!(peer.hold_until_acked(b"sent"), Hold::Acked);
+        assert!(peer.on_handshake_ack().is_empty());
+        // acknowledged peer doesn't need hellos while remote answers
+        assert!(!peer.should_send_hello());
+    }
+}
@@ -1,21 +1,33 @@
 use crate::{
     peer::{Peer, PeerManager},
-    socket::NonBlockingSocket,
+    shutdown::Shutdown,
+    socket::{IcmpMode, NonBlockingSocket},
     uri::Protocol,
 };
 use parking_lot::RwLock;
 use std::sync::Arc;
 
-type OnPeerRecvCallback = dyn Fn(&Peer, &mut [u8]);
+/// handles packets that peers receive from remote
+pub trait PeerHandler {
+    fn on_recv(&mut self, peer: &Peer, packet: &mut [u8]);
+    /// called when there isn't any received packet left for now, so the
+    /// packets that `on_recv` queued can be sent together
+    fn flush(&mut self);
+    /// called when connection of peer that its socket is bound to `port` is closed,
+    /// the peer is useless then and needs to be removed
+    fn on_closed(&mut self, peers: &mut PeerManager, port: u16);
+}
 
 /// trait to be able to listen on multiple sockets asynchronously
 pub trait Poll: Send {
     /// blocks the current thread and listens on multiple registered `NonBlockingSocket`'s
-    /// at the same time and calls `on_peer_recv` on new packets from peer
+    /// at the same time and passes new packets from peer to `handler`, returns when
+    /// `shutdown` is requested
     fn poll(
         &mut self,
         peers: Arc<RwLock<PeerManager>>,
-        on_peer_recv: Box<OnPeerRecvCallback>,
+        handler: &mut dyn PeerHandler,
+        shutdown: &Shutdown,
     ) -> anyhow::Result<()>;
 
     /// returns clone of poll registry
@@ -30,11 +42,38 @@ pub trait Registry: Send + Sync {
 }
 
 mod icmp;
+mod tcp;
 mod udp;
 
-pub fn new(protocol: Protocol, is_ipv6: bool) -> anyhow::Result<Box<dyn Poll>> {
+/// creates `Poll` for peer sockets of `protocol`, packets are received in
+/// buffers of `buffer_size`, at most `batch_size` packets are received with one
+/// syscall and at most `events_capacity` events are handled in one wake up,
+/// `icmp_mode` is only used by icmp poll
+pub fn new(
+    protocol: Protocol,
+    is_ipv6: bool,
+    buffer_size: usize,
+    batch_size: usize,
+    events_capacity: usize,
+    icmp_mode: IcmpMode,
+) -> anyhow::Result<Box<dyn Poll>> {
     Ok(match protocol {
-        Protocol::Udp => Box::new(udp::UdpPoll(mio::Poll::new()?)),
-        Protocol::Icmp => Box::new(icmp::IcmpPoll { is_ipv6 }),
+        Protocol::Udp => Box::new(udp::UdpPoll {
+            poll: mio::Poll::new()?,
+            buffer_size,
+            batch_size,
+            events_capacity,
+        }),
+        Protocol::Icmp => Box::new(icmp::IcmpPoll {
+            is_ipv6,
+            buffer_size,
+            batch_size,
+            mode: icmp_mode,
+        }),
+        Protocol::Tcp => Box::new(tcp::TcpPoll {
+            poll: mio::Poll::new()?,
+            buffer_size,
+            events_capacity,
+        }),
     })
 }
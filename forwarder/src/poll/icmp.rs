@@ -1,15 +1,21 @@
-use super::{Poll, Registry};
+use super::{PeerHandler, Poll, Registry};
 use crate::{
-    peer::{Peer, PeerManager},
-    socket::{icmp::IcmpSocket, NonBlockingSocket},
-    MAX_PACKET_SIZE,
+    peer::PeerManager,
+    shutdown::{Shutdown, SHUTDOWN_CHECK_INTERVAL},
+    socket::{
+        icmp::{self, IcmpSocket},
+        IcmpMode, NonBlockingSocket, PacketBatch,
+    },
 };
 use parking_lot::RwLock;
-use std::{mem::MaybeUninit, sync::Arc};
+use std::{os::fd::AsRawFd, sync::Arc};
 
 #[derive(Debug)]
 pub struct IcmpPoll {
     pub is_ipv6: bool,
+    pub buffer_size: usize,
+    pub batch_size: usize,
+    pub mode: IcmpMode,
 }
 
 impl Poll for IcmpPoll {
@@ -20,30 +26,43 @@ impl Poll for IcmpPoll {
     fn poll(
         &mut self,
         peers: Arc<RwLock<PeerManager>>,
-        on_peer_recv: Box<dyn Fn(&Peer, &mut [u8])>,
+        handler: &mut dyn PeerHandler,
+        shutdown: &Shutdown,
     ) -> anyhow::Result<()> {
         let listen_addr = crate::peer::create_any_addr(self.is_ipv6);
         let socket: socket2::Socket = IcmpSocket::inner_bind(listen_addr)?;
-        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        socket.set_read_timeout(Some(SHUTDOWN_CHECK_INTERVAL))?;
+        let mut batch = PacketBatch::new(self.batch_size, self.buffer_size, self.buffer_size);
+        // peers send requests and listen side of remote answers with this type
+        let echo_type = icmp::answer_echo_type(self.mode);
 
-        loop {
-            let Ok(size) =
-                socket.recv(unsafe { &mut *(&mut buffer as *mut [u8] as *mut [MaybeUninit<u8>]) })
-            else {
+        while !shutdown.is_requested() {
+            // raw socket receives packets of all peers, so they are received together
+            let Ok(count) = batch.recv_mmsg(socket.as_raw_fd(), true, false) else {
                 continue;
             };
-            let Some(icmp_packet) =
-                crate::socket::icmp::parse_icmp_packet(&mut buffer[..size], self.is_ipv6)
-            else {
-                continue;
-            };
-            let peers = peers.read();
-            let port = icmp_packet.dst_port;
-            let Some(peer) = peers.find_peer_with_port(&port) else {
-                continue;
-            };
-            on_peer_recv(peer, icmp_packet.payload);
+            {
+                let peers = peers.read();
+                for index in 0..count {
+                    let (packet, size) = batch.packet_mut(index);
+                    let Some(icmp_packet) = icmp::parse_icmp_packet(
+                        &mut packet[..size],
+                        self.is_ipv6,
+                        self.mode,
+                        echo_type,
+                    ) else {
+                        continue;
+                    };
+                    let port = icmp_packet.dst_port;
+                    let Some(peer) = peers.find_peer_with_port(&port) else {
+                        continue;
+                    };
+                    handler.on_recv(peer, icmp_packet.payload);
+                }
+            }
+            handler.flush();
         }
+        Ok(())
     }
 }
 
@@ -0,0 +1,106 @@
+use super::{PeerHandler, Poll, Registry};
+use crate::{
+    peer::PeerManager,
+    shutdown::{Shutdown, SHUTDOWN_CHECK_INTERVAL},
+    socket::{NonBlockingSocket, NonBlockingSocketTrait},
+};
+use mio::{unix::SourceFd, Events, Interest, Token};
+use parking_lot::RwLock;
+use std::{io, sync::Arc};
+
+#[derive(Debug)]
+pub struct TcpPoll {
+    pub poll: mio::Poll,
+    pub buffer_size: usize,
+    pub events_capacity: usize,
+}
+
+impl Poll for TcpPoll {
+    fn get_registry(&self) -> anyhow::Result<Box<dyn Registry>> {
+        let registry = self.poll.registry().try_clone()?;
+        let registry = TcpRegistry(registry);
+        Ok(Box::new(registry))
+    }
+
+    fn poll(
+        &mut self,
+        peers: Arc<RwLock<PeerManager>>,
+        handler: &mut dyn PeerHandler,
+        shutdown: &Shutdown,
+    ) -> anyhow::Result<()> {
+        let mut events = Events::with_capacity(self.events_capacity);
+        let mut buffer = vec![0u8; self.buffer_size];
+        let mut closed_ports = Vec::new();
+
+        while !shutdown.is_requested() {
+            self.poll.poll(&mut events, Some(SHUTDOWN_CHECK_INTERVAL))?;
+
+            {
+                let peers = peers.read();
+                for event in &events {
+                    let port = event.token().0 as u16;
+                    let Some(peer) = peers.find_peer_with_port(&port) else {
+                        continue;
+                    };
+                    if event.is_writable() {
+                        if let Err(error) = peer.socket.flush() {
+                            log::info!(
+                                "connection of peer that handled '{}' closed: {error}",
+                                peer.get_client_addr()
+                            );
+                            closed_ports.push(port);
+                            continue;
+                        }
+                    }
+                    // each readiness event may contain multiple datagrams
+                    loop {
+                        match peer.socket.recv(&mut buffer) {
+                            Ok(size) => handler.on_recv(peer, &mut buffer[..size]),
+                            Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(error) => {
+                                log::info!(
+                                    "connection of peer that handled '{}' closed: {error}",
+                                    peer.get_client_addr()
+                                );
+                                closed_ports.push(port);
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            handler.flush();
+
+            // peers with closed connection are useless, so they get removed right away
+            // and the next packet of their client creates a new connection
+            if !closed_ports.is_empty() {
+                let mut peers = peers.write();
+                for port in closed_ports.drain(..) {
+                    handler.on_closed(&mut peers, port);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct TcpRegistry(pub mio::Registry);
+impl Registry for TcpRegistry {
+    fn register(&self, socket: &mut NonBlockingSocket) -> anyhow::Result<()> {
+        let socket = socket.as_mut_tcp().unwrap();
+        let local_port = socket.local_addr()?.port();
+        self.0.register(
+            &mut SourceFd(&socket.as_raw_fd()),
+            Token(local_port.into()),
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        Ok(())
+    }
+
+    fn deregister(&self, socket: &mut NonBlockingSocket) -> anyhow::Result<()> {
+        let socket = socket.as_mut_tcp().unwrap();
+        self.0.deregister(&mut SourceFd(&socket.as_raw_fd()))?;
+        Ok(())
+    }
+}
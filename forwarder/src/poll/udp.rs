@@ -1,21 +1,24 @@
-use super::{Poll, Registry};
+use super::{PeerHandler, Poll, Registry};
 use crate::{
-    peer::{Peer, PeerManager},
-    socket::{NonBlockingSocket, NonBlockingSocketTrait},
-    MAX_PACKET_SIZE,
+    peer::PeerManager,
+    shutdown::{Shutdown, SHUTDOWN_CHECK_INTERVAL},
+    socket::{NonBlockingSocket, NonBlockingSocketTrait, PacketBatch},
 };
 use mio::{Events, Interest, Token};
 use parking_lot::RwLock;
 use std::sync::Arc;
 
-const EPOLL_EVENTS_CAPACITY: usize = 1024;
-
 #[derive(Debug)]
-pub struct UdpPoll(pub mio::Poll);
+pub struct UdpPoll {
+    pub poll: mio::Poll,
+    pub buffer_size: usize,
+    pub batch_size: usize,
+    pub events_capacity: usize,
+}
 
 impl Poll for UdpPoll {
     fn get_registry(&self) -> anyhow::Result<Box<dyn Registry>> {
-        let registry = self.0.registry().try_clone()?;
+        let registry = self.poll.registry().try_clone()?;
         let registry = UdpRegistry(registry);
         Ok(Box::new(registry))
     }
@@ -23,27 +26,34 @@ impl Poll for UdpPoll {
     fn poll(
         &mut self,
         peers: Arc<RwLock<PeerManager>>,
-        on_peer_recv: Box<dyn Fn(&Peer, &mut [u8])>,
+        handler: &mut dyn PeerHandler,
+        shutdown: &Shutdown,
     ) -> anyhow::Result<()> {
-        let mut events = Events::with_capacity(EPOLL_EVENTS_CAPACITY);
-        let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+        let mut events = Events::with_capacity(self.events_capacity);
+        let mut batch = PacketBatch::new(self.batch_size, self.buffer_size, self.buffer_size);
 
-        loop {
-            self.0.poll(&mut events, None)?;
+        while !shutdown.is_requested() {
+            self.poll.poll(&mut events, Some(SHUTDOWN_CHECK_INTERVAL))?;
 
-            let peers = peers.read();
-            for event in &events {
-                let port = event.token().0 as u16;
-                let Some(peer) = peers.find_peer_with_port(&port) else {
-                    continue;
-                };
-                peer.set_used();
-                // each epoll event may result in multiple readiness events
-                while let Ok(size) = peer.socket.recv(&mut buffer) {
-                    on_peer_recv(peer, &mut buffer[..size]);
+            {
+                let peers = peers.read();
+                for event in &events {
+                    let port = event.token().0 as u16;
+                    let Some(peer) = peers.find_peer_with_port(&port) else {
+                        continue;
+                    };
+                    // each epoll event may result in multiple readiness events
+                    while let Ok(count) = peer.socket.recv_batch(&mut batch) {
+                        for index in 0..count {
+                            let (packet, size) = batch.packet_mut(index);
+                            handler.on_recv(peer, &mut packet[..size]);
+                        }
+                    }
                 }
             }
+            handler.flush();
         }
+        Ok(())
     }
 }
 
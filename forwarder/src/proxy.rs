@@ -0,0 +1,193 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// signature that all proxy protocol v2 headers start with
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// size of the fixed part of header that comes before addresses
+const FIXED_SIZE: usize = 16;
+
+/// size of the addresses of ipv4 and ipv6
+const INET_ADDRS_SIZE: usize = 12;
+const INET6_ADDRS_SIZE: usize = 36;
+
+/// maximum size of a header that forwarder writes
+pub const MAX_HEADER_SIZE: usize = FIXED_SIZE + INET6_ADDRS_SIZE;
+
+/// version 2 with `PROXY` command, the packet is relayed for the source address
+const PROXY_COMMAND: u8 = 0x21;
+/// version 2 with `LOCAL` command, the packet is from the proxy itself
+const LOCAL_COMMAND: u8 = 0x20;
+
+/// address families with `DGRAM` transport
+const INET_DGRAM: u8 = 0x12;
+const INET6_DGRAM: u8 = 0x22;
+
+/// original addresses of a packet that are carried by proxy protocol v2 header, `source` is
+/// the client and `destination` is the address that the client sent the packet to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProxyAddrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// moves packet in `buffer[..size]` forward and writes header with `addrs` before it,
+/// returns the new size or `None` if `buffer` doesn't have enough free space
+pub fn prepend(addrs: &ProxyAddrs, buffer: &mut [u8], size: usize) -> Option<usize> {
+    let (family, source, destination) = match (addrs.source.ip(), addrs.destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => (
+            INET_DGRAM,
+            source.octets().to_vec(),
+            destination.octets().to_vec(),
+        ),
+        // families can't be mixed in one header so ipv4 gets mapped to ipv6
+        (source, destination) => (
+            INET6_DGRAM,
+            to_ipv6(source).octets().to_vec(),
+            to_ipv6(destination).octets().to_vec(),
+        ),
+    };
+    let addrs_size = source.len() * 2 + 4;
+    let header_size = FIXED_SIZE + addrs_size;
+    if buffer.len() < size + header_size {
+        return None;
+    }
+    buffer.copy_within(..size, header_size);
+
+    let header = &mut buffer[..header_size];
+    header[..12].copy_from_slice(&SIGNATURE);
+    header[12] = PROXY_COMMAND;
+    header[13] = family;
+    header[14..16].copy_from_slice(&(addrs_size as u16).to_be_bytes());
+    let ports = [addrs.source.port(), addrs.destination.port()];
+    let addrs = [source, destination].concat();
+    header[16..16 + addrs.len()].copy_from_slice(&addrs);
+    header[16 + addrs.len()..16 + addrs.len() + 2].copy_from_slice(&ports[0].to_be_bytes());
+    header[16 + addrs.len() + 2..].copy_from_slice(&ports[1].to_be_bytes());
+    Some(size + header_size)
+}
+
+/// parses header at the start of `packet` and returns the addresses that it carries and
+/// its size, addresses are `None` for `LOCAL` headers or families other than ip, returns
+/// `None` if `packet` doesn't start with a valid header
+pub fn parse(packet: &[u8]) -> Option<(Option<ProxyAddrs>, usize)> {
+    if packet.len() < FIXED_SIZE || packet[..12] != SIGNATURE {
+        return None;
+    }
+    let size = FIXED_SIZE + u16::from_be_bytes([packet[14], packet[15]]) as usize;
+    // addresses may be followed by tlvs that we skip
+    let addrs = packet.get(FIXED_SIZE..size)?;
+    match packet[12] {
+        LOCAL_COMMAND => return Some((None, size)),
+        PROXY_COMMAND => (),
+        _ => return None,
+    }
+    let addrs = match packet[13] >> 4 {
+        // AF_INET
+        1 => {
+            let addrs = addrs.get(..INET_ADDRS_SIZE)?;
+            let source: [u8; 4] = addrs[..4].try_into().unwrap();
+            let destination: [u8; 4] = addrs[4..8].try_into().unwrap();
+            Some(ProxyAddrs {
+                source: SocketAddr::new(Ipv4Addr::from(source).into(), port(&addrs[8..])),
+                destination: SocketAddr::new(
+                    Ipv4Addr::from(destination).into(),
+                    port(&addrs[10..]),
+                ),
+            })
+        }
+        // AF_INET6
+        2 => {
+            let addrs = addrs.get(..INET6_ADDRS_SIZE)?;
+            let source: [u8; 16] = addrs[..16].try_into().unwrap();
+            let destination: [u8; 16] = addrs[16..32].try_into().unwrap();
+            Some(ProxyAddrs {
+                source: SocketAddr::new(from_ipv6(source), port(&addrs[32..])),
+                destination: SocketAddr::new(from_ipv6(destination), port(&addrs[34..])),
+            })
+        }
+        // AF_UNSPEC and AF_UNIX don't have any address that we can use
+        _ => None,
+    };
+    Some((addrs, size))
+}
+
+fn port(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// ipv4 addresses that are mapped to ipv6 by `prepend` get back to ipv4
+fn from_ipv6(octets: [u8; 16]) -> IpAddr {
+    let ip = Ipv6Addr::from(octets);
+    match ip.to_ipv4_mapped() {
+        Some(ip) => ip.into(),
+        None => ip.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(source: &str, destination: &str) -> ProxyAddrs {
+        ProxyAddrs {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn headers_are_prepended_and_parsed() {
+        for addrs in [
+            addrs("1.2.3.4:1000", "5.6.7.8:2000"),
+            addrs("[2001:db8::1]:1000", "[2001:db8::2]:2000"),
+            addrs("[2001:db8::1]:1000", "5.6.7.8:2000"),
+        ] {
+            let mut buffer = [0u8; 100];
+            buffer[..5].copy_from_slice(b"hello");
+            let size = prepend(&addrs, &mut buffer, 5).unwrap();
+            let (parsed, header_size) = parse(&buffer[..size]).unwrap();
+            assert_eq!(parsed, Some(addrs));
+            assert_eq!(&buffer[header_size..size], b"hello");
+        }
+        assert!(prepend(&addrs("1.2.3.4:1000", "5.6.7.8:2000"), &mut [0u8; 30], 5).is_none());
+    }
+
+    #[test]
+    fn ipv4_header_has_the_standard_layout() {
+        let mut buffer = [0u8; 28];
+        let size = prepend(&addrs("1.2.3.4:1000", "5.6.7.8:2000"), &mut buffer, 0).unwrap();
+        assert_eq!(size, 28);
+        assert_eq!(
+            buffer[12..],
+            [0x21, 0x12, 0, 12, 1, 2, 3, 4, 5, 6, 7, 8, 0x03, 0xe8, 0x07, 0xd0]
+        );
+    }
+
+    #[test]
+    fn local_headers_and_tlvs_are_skipped() {
+        let mut packet = SIGNATURE.to_vec();
+        packet.extend_from_slice(&[LOCAL_COMMAND, 0, 0, 0]);
+        packet.extend_from_slice(b"hello");
+        assert_eq!(parse(&packet), Some((None, FIXED_SIZE)));
+
+        let mut buffer = [0u8; 100];
+        let size = prepend(&addrs("1.2.3.4:1000", "5.6.7.8:2000"), &mut buffer, 0).unwrap();
+        let mut packet = buffer[..size].to_vec();
+        // NOOP tlv with 2 bytes of value
+        packet.extend_from_slice(&[0x04, 0, 2, 0, 0]);
+        packet[15] += 5;
+        let (parsed, header_size) = parse(&packet).unwrap();
+        assert_eq!(parsed, Some(addrs("1.2.3.4:1000", "5.6.7.8:2000")));
+        assert_eq!(header_size, packet.len());
+
+        assert_eq!(parse(b"hello"), None);
+        assert_eq!(parse(&packet[..20]), None);
+    }
+}
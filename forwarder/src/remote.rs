@@ -0,0 +1,188 @@
+use crate::clock;
+use parking_lot::RwLock;
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// how a remote gets picked for new clients when there are multiple remotes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RemotePolicy {
+    /// the first healthy remote in order, the others are backups
+    #[default]
+    Failover,
+    /// healthy remotes take turns
+    RoundRobin,
+    /// remote is picked by hash of client ip so each client sticks to one remote
+    Hash,
+}
+
+impl FromStr for RemotePolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "failover" => Ok(RemotePolicy::Failover),
+            "round-robin" => Ok(RemotePolicy::RoundRobin),
+            "hash" => Ok(RemotePolicy::Hash),
+            _ => anyhow::bail!(
+                "invalid remote policy, valid policies are: 'failover', 'round-robin' and 'hash'"
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Remote {
+    addr: RwLock<SocketAddr>,
+    /// time in `clock::now_millis` since packets are sent to remote without
+    /// getting any packet back, zero if remote answered
+    unanswered_since: AtomicU64,
+    /// last time in `clock::now_millis` that an unhealthy remote got a new
+    /// peer to check if it's back
+    last_probe: AtomicU64,
+}
+
+/// remotes of forwarder and their health, a remote is unhealthy when packets are sent to it
+/// for `health_timeout` without getting anything back and it gets skipped for new peers
+#[derive(Debug)]
+pub(crate) struct Remotes {
+    remotes: Vec<Remote>,
+    policy: RemotePolicy,
+    health_timeout: u64,
+    /// counter of round robin policy
+    next: AtomicUsize,
+}
+
+impl Remotes {
+    pub fn new(addrs: &[SocketAddr], policy: RemotePolicy, health_timeout: Duration) -> Self {
+        let remotes = addrs
+            .iter()
+            .map(|addr| Remote {
+                addr: RwLock::new(*addr),
+                unanswered_since: AtomicU64::new(0),
+                last_probe: AtomicU64::new(0),
+            })
+            .collect();
+        Self {
+            remotes,
+            policy,
+            health_timeout: health_timeout.as_millis() as u64,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn addr(&self, index: usize) -> SocketAddr {
+        *self.remotes[index].addr.read()
+    }
+
+    pub fn set_addr(&self, index: usize, addr: SocketAddr) {
+        *self.remotes[index].addr.write() = addr;
+    }
+
+    /// records that a packet is sent to remote
+    pub fn on_sent(&self, index: usize) {
+        let unanswered_since = &self.remotes[index].unanswered_since;
+        if unanswered_since.load(Ordering::Relaxed) == 0 {
+            // zero means remote answered, so it can't be used as time
+            unanswered_since.store(clock::now_millis().max(1), Ordering::Relaxed);
+        }
+    }
+
+    /// records that remote sent a packet back
+    pub fn on_reply(&self, index: usize) {
+        let unanswered_since = &self.remotes[index].unanswered_since;
+        if unanswered_since.load(Ordering::Relaxed) != 0 {
+            unanswered_since.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn is_healthy(&self, index: usize, now: u64) -> bool {
+        let unanswered_since = self.remotes[index].unanswered_since.load(Ordering::Relaxed);
+        unanswered_since == 0 || now.saturating_sub(unanswered_since) <= self.health_timeout
+    }
+
+    /// returns index of the remote that new peer of `client_addr` connects to
+    pub fn select(&self, client_addr: &SocketAddr) -> usize {
+        let count = self.remotes.len();
+        if count == 1 {
+            return 0;
+        }
+        let start = match self.policy {
+            RemotePolicy::Failover => 0,
+            RemotePolicy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % count,
+            RemotePolicy::Hash => {
+                let mut hasher = DefaultHasher::new();
+                client_addr.ip().hash(&mut hasher);
+                hasher.finish() as usize % count
+            }
+        };
+
+        let now = clock::now_millis();
+        let indexes = (0..count).map(|offset| (start + offset) % count);
+        if let Some(index) = indexes.clone().find(|index| self.is_healthy(*index, now)) {
+            return index;
+        }
+        // all remotes are unhealthy, one new peer per `health_timeout` is given to
+        // each of them so the one that is back becomes healthy again
+        for index in indexes {
+            let last_probe = &self.remotes[index].last_probe;
+            if now.saturating_sub(last_probe.load(Ordering::Relaxed)) > self.health_timeout {
+                last_probe.store(now, Ordering::Relaxed);
+                return index;
+            }
+        }
+        start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remotes(policy: RemotePolicy) -> Remotes {
+        let addrs = [
+            SocketAddr::from_str("127.0.0.1:1000").unwrap(),
+            SocketAddr::from_str("127.0.0.1:2000").unwrap(),
+        ];
+        Remotes::new(&addrs, policy, Duration::ZERO)
+    }
+
+    fn make_unhealthy(remotes: &Remotes, index: usize) {
+        remotes.on_sent(index);
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    #[test]
+    fn failover_skips_unhealthy_remote() {
+        let client = SocketAddr::from_str("10.0.0.1:5000").unwrap();
+        let remotes = remotes(RemotePolicy::Failover);
+        assert_eq!(remotes.select(&client), 0);
+
+        make_unhealthy(&remotes, 0);
+        assert_eq!(remotes.select(&client), 1);
+        remotes.on_reply(0);
+        assert_eq!(remotes.select(&client), 0);
+    }
+
+    #[test]
+    fn round_robin_and_hash_spread_clients() {
+        let client = SocketAddr::from_str("10.0.0.1:5000").unwrap();
+        let remotes = remotes(RemotePolicy::RoundRobin);
+        assert_eq!(remotes.select(&client), 0);
+        assert_eq!(remotes.select(&client), 1);
+        assert_eq!(remotes.select(&client), 0);
+        make_unhealthy(&remotes, 1);
+        assert_eq!(remotes.select(&client), 0);
+
+        let remotes = self::remotes(RemotePolicy::Hash);
+        let index = remotes.select(&client);
+        // clients of same ip stick to one remote
+        let same_ip_client = SocketAddr::from_str("10.0.0.1:6000").unwrap();
+        assert_eq!(remotes.select(&same_ip_client), index);
+        make_unhealthy(&remotes, index);
+        assert_ne!(remotes.select(&client), index);
+    }
+}
@@ -0,0 +1,20 @@
+use std::{
+    fmt::Debug,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+};
+
+/// resolves hostnames of `Uri`s to addresses
+pub trait Resolver: Send + Sync + Debug {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// `Resolver` that uses the resolver of operating system
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
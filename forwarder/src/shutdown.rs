@@ -0,0 +1,110 @@
+use parking_lot::Mutex;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread::Thread,
+    time::{Duration, Instant},
+};
+
+/// maximum time that blocking calls wait before checking if shutdown is requested
+pub const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// signal that tells all threads of a forwarder to stop
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    /// threads that are sleeping in `Shutdown::sleep`
+    sleepers: Mutex<Vec<Thread>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+        for thread in self.sleepers.lock().iter() {
+            thread.unpark();
+        }
+    }
+
+    /// wakes up sleeping threads so they can check if they need to wake up early
+    pub fn wake_sleepers(&self) {
+        for thread in self.sleepers.lock().iter() {
+            thread.unpark();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// sleeps for `duration` and wakes up early if shutdown is requested,
+    /// returns `false` if shutdown is requested
+    pub fn sleep(&self, duration: Duration) -> bool {
+        self.sleep_or(duration, || false)
+    }
+
+    /// same as `sleep` but also wakes up early if `wake_up` returns true, it's
+    /// checked each time that sleeping threads are woken up by `wake_sleepers`
+    pub fn sleep_or(&self, duration: Duration, wake_up: impl Fn() -> bool) -> bool {
+        let current_thread = std::thread::current();
+        self.sleepers.lock().push(current_thread.clone());
+
+        let deadline = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if self.is_requested() || now >= deadline || wake_up() {
+                break;
+            }
+            // park may wake up spuriously so it's inside a loop
+            std::thread::park_timeout(deadline - now);
+        }
+
+        self.sleepers
+            .lock()
+            .retain(|thread| thread.id() != current_thread.id());
+        !self.is_requested()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn request_wakes_up_sleeping_thread() {
+        let shutdown = Arc::new(Shutdown::new());
+        let sleeper = {
+            let shutdown = shutdown.clone();
+            std::thread::spawn(move || shutdown.sleep(Duration::from_secs(60)))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        shutdown.request();
+        assert!(!sleeper.join().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!shutdown.sleep(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn sleepers_wake_up_when_asked_to() {
+        let shutdown = Arc::new(Shutdown::new());
+        let woken = Arc::new(AtomicBool::new(false));
+        let sleeper = {
+            let (shutdown, woken) = (shutdown.clone(), woken.clone());
+            std::thread::spawn(move || {
+                shutdown.sleep_or(Duration::from_secs(60), || woken.load(Ordering::Relaxed))
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        woken.store(true, Ordering::Relaxed);
+        shutdown.wake_sleepers();
+        assert!(sleeper.join().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -1,8 +1,13 @@
+pub use batch::{PacketBatch, MAX_BATCH_SIZE};
+
 use crate::uri::Protocol;
+use socket2::SockRef;
 use std::{
     io,
-    net::SocketAddr,
+    net::{Ipv4Addr, SocketAddr},
     ops::{Deref, DerefMut},
+    str::FromStr,
+    time::Duration,
 };
 
 macro_rules! impl_enum_deref {
@@ -13,6 +18,7 @@ macro_rules! impl_enum_deref {
                 match self {
                     Self::Udp(inner) => inner,
                     Self::Icmp(inner) => inner,
+                    Self::Tcp(inner) => inner,
                 }
             }
         }
@@ -21,34 +27,129 @@ macro_rules! impl_enum_deref {
                 match self {
                     Self::Udp(inner) => inner,
                     Self::Icmp(inner) => inner,
+                    Self::Tcp(inner) => inner,
                 }
             }
         }
     };
 }
 
+/// options that are applied on sockets that forwarder creates
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SocketOptions {
+    /// size of kernel receive buffer (SO_RCVBUF), `None` keeps the system default
+    pub recv_buffer_size: Option<usize>,
+    /// size of kernel send buffer (SO_SNDBUF), `None` keeps the system default
+    pub send_buffer_size: Option<usize>,
+    /// how icmp packets are exchanged, only used by icmp sockets
+    pub icmp_mode: IcmpMode,
+    /// enables `UDP_GRO` on receive and `UDP_SEGMENT` on send of udp sockets,
+    /// each of them stays disabled if kernel doesn't support it
+    pub udp_offload: bool,
+}
+
+impl SocketOptions {
+    pub(crate) fn apply(&self, socket: SockRef) -> io::Result<()> {
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// how two forwarders exchange icmp packets, both of them need to use the same mode
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IcmpMode {
+    /// both sides send echo requests, it doesn't work behind NAT
+    #[default]
+    Request,
+    /// the side that connects sends echo requests and the listen side answers with
+    /// echo replies that have the same id and sequence, so stateful NATs can track
+    /// the flow, kernel echo replies need to be disabled on listen side
+    Reply,
+}
+
+impl FromStr for IcmpMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "request" => Ok(IcmpMode::Request),
+            "reply" => Ok(IcmpMode::Reply),
+            _ => anyhow::bail!("invalid icmp mode, valid modes are: 'request' and 'reply'"),
+        }
+    }
+}
+
 // using enum instead of vtable because i think it's more performant
 #[derive(Debug)]
 pub enum Socket {
     Udp(udp::UdpSocket),
     Icmp(icmp::IcmpSocket),
+    Tcp(tcp::TcpSocket),
 }
 
 impl Socket {
     /// creates a socket based on `protocol` and binds it to `addr` address
-    pub fn bind(protocol: Protocol, addr: &SocketAddr) -> io::Result<Self> {
+    pub fn bind(
+        protocol: Protocol,
+        addr: &SocketAddr,
+        options: &SocketOptions,
+    ) -> io::Result<Self> {
         let socket = match protocol {
-            Protocol::Udp => Socket::Udp(udp::UdpSocket::bind(addr)?),
-            Protocol::Icmp => Socket::Icmp(icmp::IcmpSocket::bind(addr)?),
+            Protocol::Udp => Socket::Udp(udp::UdpSocket::bind(addr, options)?),
+            Protocol::Icmp => Socket::Icmp(icmp::IcmpSocket::bind(addr, options)?),
+            Protocol::Tcp => Socket::Tcp(tcp::TcpSocket::bind(addr, options)?),
         };
         Ok(socket)
     }
+
+    /// same as `bind` but multiple sockets can be bound to the same `addr` and kernel
+    /// spreads clients between them, only udp supports it
+    pub fn bind_reuse_port(
+        protocol: Protocol,
+        addr: &SocketAddr,
+        options: &SocketOptions,
+    ) -> io::Result<Self> {
+        match protocol {
+            Protocol::Udp => Ok(Socket::Udp(udp::UdpSocket::bind_reuse_port(addr, options)?)),
+            _ => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
 }
 
 pub trait SocketTrait {
     fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
     fn send_to(&self, buffer: &[u8], to: &SocketAddr) -> io::Result<usize>;
     fn local_addr(&self) -> io::Result<SocketAddr>;
+    /// sets timeout of `recv_from`, `None` means it blocks forever
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// closes the connection of client if protocol has connections, e.g. tcp
+    fn disconnect(&self, _client_addr: &SocketAddr) {}
+
+    /// replaces packets of `batch` with received packets and returns their count,
+    /// it only waits for the first packet
+    fn recv_from_batch(&self, batch: &mut PacketBatch) -> io::Result<usize> {
+        batch.clear();
+        // batch has at least one slot after clear
+        let (size, from_addr) = self.recv_from(batch.recv_slot().unwrap())?;
+        batch.push(size, from_addr);
+        Ok(1)
+    }
+
+    /// sends each packet of `batch` to its address, packets that can't be sent
+    /// are skipped, returns indexes of packets that couldn't be sent
+    fn send_batch(&self, batch: &mut PacketBatch) -> Vec<usize> {
+        (0..batch.len())
+            .filter(|index| {
+                let (packet, to) = batch.packet(*index);
+                self.send_to(packet, &to).is_err()
+            })
+            .collect()
+    }
 }
 impl_enum_deref! { Socket, dyn SocketTrait }
 
@@ -56,14 +157,20 @@ impl_enum_deref! { Socket, dyn SocketTrait }
 pub enum NonBlockingSocket {
     Udp(udp::NonBlockingUdpSocket),
     Icmp(icmp::NonBlockingIcmpSocket),
+    Tcp(tcp::NonBlockingTcpSocket),
 }
 
 impl NonBlockingSocket {
     /// creates a socket based on `protocol` and binds it to `addr` address
-    pub fn bind(protocol: Protocol, addr: &SocketAddr) -> io::Result<Self> {
+    pub fn bind(
+        protocol: Protocol,
+        addr: &SocketAddr,
+        options: &SocketOptions,
+    ) -> io::Result<Self> {
         let socket = match protocol {
-            Protocol::Udp => Self::Udp(udp::NonBlockingUdpSocket::bind(addr)?),
-            Protocol::Icmp => Self::Icmp(icmp::NonBlockingIcmpSocket::bind(addr)?),
+            Protocol::Udp => Self::Udp(udp::NonBlockingUdpSocket::bind(addr, options)?),
+            Protocol::Icmp => Self::Icmp(icmp::NonBlockingIcmpSocket::bind(addr, options)?),
+            Protocol::Tcp => Self::Tcp(tcp::NonBlockingTcpSocket::bind(addr, options)?),
         };
         Ok(socket)
     }
@@ -74,15 +181,46 @@ impl NonBlockingSocket {
             _ => None,
         }
     }
+
+    pub fn as_mut_tcp(&mut self) -> Option<&mut tcp::NonBlockingTcpSocket> {
+        match self {
+            Self::Tcp(inner) => Some(inner),
+            _ => None,
+        }
+    }
 }
 
 pub trait NonBlockingSocketTrait {
-    fn connect(&mut self, addr: &SocketAddr) -> io::Result<()>;
+    /// connects socket to `addr`, it can be called again to move the socket to another address
+    fn connect(&self, addr: &SocketAddr) -> io::Result<()>;
     fn send(&self, buffer: &[u8]) -> io::Result<usize>;
     fn recv(&self, buffer: &mut [u8]) -> io::Result<usize>;
     fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// writes data that waits for socket to become writable, it's called when
+    /// poll finds out that socket is writable
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// replaces packets of `batch` with packets that are already received and returns
+    /// their count, returns the error of first `recv` if there isn't any, e.g. `WouldBlock`
+    fn recv_batch(&self, batch: &mut PacketBatch) -> io::Result<usize> {
+        batch.clear();
+        while let Some(slot) = batch.recv_slot() {
+            match self.recv(slot) {
+                // socket is connected so address of packets doesn't matter
+                Ok(size) => batch.push(size, (Ipv4Addr::UNSPECIFIED, 0).into()),
+                Err(error) if batch.is_empty() => return Err(error),
+                Err(_) => break,
+            }
+        }
+        Ok(batch.len())
+    }
 }
 impl_enum_deref! { NonBlockingSocket, dyn NonBlockingSocketTrait }
 
+mod batch;
 pub(crate) mod icmp;
+pub(crate) mod tcp;
 pub(crate) mod udp;
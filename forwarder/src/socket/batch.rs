@@ -0,0 +1,554 @@
+use crate::MAX_PACKET_SIZE;
+use socket2::SockAddr;
+use std::{
+    io, mem,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    ops::Range,
+    os::fd::RawFd,
+};
+
+/// maximum number of packets in a batch, same as `UIO_MAXIOV` of linux
+pub const MAX_BATCH_SIZE: usize = 1024;
+
+/// maximum number of packets that are coalesced into one segmented packet,
+/// same as `UDP_MAX_SEGMENTS` of linux
+const MAX_SEGMENTS: usize = 64;
+
+/// maximum size of a segmented packet, the biggest payload of udp over ipv4
+const MAX_SEGMENTED_SIZE: usize = 65507;
+
+/// maximum number of messages that are received with one syscall when `UDP_GRO` is
+/// enabled, each of them needs a whole `MAX_PACKET_SIZE` buffer because it can carry
+/// up to `MAX_SEGMENTS` packets, so it keeps the gro buffer at 1 MiB even for big batches
+const MAX_GRO_MESSAGES: usize = 16;
+
+/// control message buffer that fits a `UDP_SEGMENT` or `UDP_GRO` option
+#[derive(Clone, Copy)]
+#[repr(C, align(8))]
+struct Control([u8; 32]);
+
+/// multiple packets that are received or sent together, on udp sockets the
+/// whole batch is handled by a single `recvmmsg` or `sendmmsg` syscall
+pub struct PacketBatch {
+    /// slots of packets next to each other, each of them is `slot_size` bytes
+    buffer: Vec<u8>,
+    slot_size: usize,
+    /// packets are received in the first `recv_size` bytes of slot, rest
+    /// of it is free space for transforms
+    recv_size: usize,
+    /// size and address of each packet that is in the batch
+    packets: Vec<(usize, SocketAddr)>,
+    /// number of slots, it only grows when coalesced packets are split
+    capacity: usize,
+    /// maximum number of packets that are received with one syscall
+    batch_size: usize,
+    /// coalesced packets are received here before getting split into slots
+    gro_buffer: Vec<u8>,
+    // scratch space of syscalls, they are rebuilt before each syscall
+    headers: Vec<libc::mmsghdr>,
+    iovecs: Vec<libc::iovec>,
+    addrs: Vec<libc::sockaddr_storage>,
+    controls: Vec<Control>,
+    /// number of packets in each message that is sent
+    group_lens: Vec<usize>,
+}
+
+// raw pointers of scratch space only point into the batch itself and they are
+// never used outside of `recv_mmsg` and `send_mmsg`
+unsafe impl Send for PacketBatch {}
+
+impl PacketBatch {
+    /// creates a batch of `capacity` packets that each of them can be received
+    /// in `recv_size` bytes and grow up to `slot_size` bytes
+    pub fn new(capacity: usize, recv_size: usize, slot_size: usize) -> Self {
+        assert!(recv_size <= slot_size);
+        Self {
+            buffer: vec![0u8; capacity * slot_size],
+            slot_size,
+            recv_size,
+            packets: Vec::with_capacity(capacity),
+            capacity,
+            batch_size: capacity,
+            gro_buffer: Vec::new(),
+            headers: Vec::with_capacity(capacity),
+            iovecs: Vec::with_capacity(capacity),
+            addrs: Vec::with_capacity(capacity),
+            controls: Vec::with_capacity(capacity),
+            group_lens: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.packets.len() == self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+
+    /// returns packet at `index` and the address that it's received from or is sent to
+    pub fn packet(&self, index: usize) -> (&[u8], SocketAddr) {
+        let (size, addr) = self.packets[index];
+        (&self.slot(index)[..size], addr)
+    }
+
+    /// returns the whole slot of packet at `index` and its size, so the packet can
+    /// be changed in place and grow up to the end of slot
+    pub fn packet_mut(&mut self, index: usize) -> (&mut [u8], usize) {
+        let size = self.packets[index].0;
+        (self.slot_mut(index), size)
+    }
+
+    /// returns the slot that next packet gets written in, `None` if batch is full
+    pub fn free_slot(&mut self) -> Option<&mut [u8]> {
+        (!self.is_full()).then(|| self.slot_mut(self.packets.len()))
+    }
+
+    /// returns the part of next slot that packets are received in, `None` if batch is full
+    pub fn recv_slot(&mut self) -> Option<&mut [u8]> {
+        let recv_size = self.recv_size;
+        self.free_slot().map(|slot| &mut slot[..recv_size])
+    }
+
+    /// appends the packet of `size` bytes that is written in `free_slot`
+    pub fn push(&mut self, size: usize, addr: SocketAddr) {
+        assert!(!self.is_full() && size <= self.slot_size);
+        self.packets.push((size, addr));
+    }
+
+    fn slot(&self, index: usize) -> &[u8] {
+        &self.buffer[index * self.slot_size..(index + 1) * self.slot_size]
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut [u8] {
+        &mut self.buffer[index * self.slot_size..(index + 1) * self.slot_size]
+    }
+
+    /// copies `packet` to a new slot, batch grows if it's full
+    fn push_copy(&mut self, packet: &[u8], addr: SocketAddr) {
+        if self.is_full() {
+            self.capacity *= 2;
+            self.buffer.resize(self.capacity * self.slot_size, 0);
+        }
+        // packets that are bigger than `recv_size` get truncated like a normal receive
+        let size = packet.len().min(self.recv_size);
+        self.free_slot().unwrap()[..size].copy_from_slice(&packet[..size]);
+        self.push(size, addr);
+    }
+
+    /// replaces packets of batch with packets that are received from `fd` by one
+    /// `recvmmsg`, waits only for the first packet if `wait_for_one` is set, `gro`
+    /// needs to be set if `UDP_GRO` is enabled on `fd` so coalesced packets get split
+    pub(crate) fn recv_mmsg(
+        &mut self,
+        fd: RawFd,
+        wait_for_one: bool,
+        gro: bool,
+    ) -> io::Result<usize> {
+        self.clear();
+        self.headers.clear();
+        self.iovecs.clear();
+        self.addrs.clear();
+        self.controls.clear();
+        let message_count = if gro {
+            self.batch_size.min(MAX_GRO_MESSAGES)
+        } else {
+            self.batch_size
+        };
+        if gro && self.gro_buffer.is_empty() {
+            self.gro_buffer = vec![0u8; message_count * MAX_PACKET_SIZE];
+        }
+        for index in 0..message_count {
+            let iovec = if gro {
+                libc::iovec {
+                    iov_base: self.gro_buffer[index * MAX_PACKET_SIZE..]
+                        .as_mut_ptr()
+                        .cast(),
+                    iov_len: MAX_PACKET_SIZE,
+                }
+            } else {
+                libc::iovec {
+                    iov_base: self.buffer[index * self.slot_size..].as_mut_ptr().cast(),
+                    iov_len: self.recv_size,
+                }
+            };
+            self.iovecs.push(iovec);
+            // zeroed `sockaddr_storage` is valid
+            self.addrs.push(unsafe { mem::zeroed() });
+            self.controls.push(Control([0; 32]));
+        }
+        // headers are built after the other vectors so their items don't move anymore
+        for index in 0..message_count {
+            let mut header: libc::msghdr = unsafe { mem::zeroed() };
+            header.msg_name = (&mut self.addrs[index] as *mut libc::sockaddr_storage).cast();
+            header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_iov = &mut self.iovecs[index];
+            header.msg_iovlen = 1;
+            if gro {
+                header.msg_control = self.controls[index].0.as_mut_ptr().cast();
+                header.msg_controllen = control_len::<libc::c_int>();
+            }
+            self.headers.push(libc::mmsghdr {
+                msg_hdr: header,
+                msg_len: 0,
+            });
+        }
+
+        let flags = if wait_for_one {
+            libc::MSG_WAITFORONE
+        } else {
+            libc::MSG_DONTWAIT
+        };
+        let count = unsafe {
+            libc::recvmmsg(
+                fd,
+                self.headers.as_mut_ptr(),
+                message_count as libc::c_uint,
+                flags,
+                std::ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let count = count as usize;
+        if !gro {
+            for index in 0..count {
+                let addr = self.received_addr(index);
+                self.packets
+                    .push((self.headers[index].msg_len as usize, addr));
+            }
+            return Ok(count);
+        }
+
+        let gro_buffer = mem::take(&mut self.gro_buffer);
+        for index in 0..count {
+            let addr = self.received_addr(index);
+            let header = &self.headers[index];
+            let size = header.msg_len as usize;
+            let segment_size = gro_segment_size(&header.msg_hdr).unwrap_or(size).max(1);
+            let start = index * MAX_PACKET_SIZE;
+            for segment in gro_buffer[start..start + size].chunks(segment_size) {
+                self.push_copy(segment, addr);
+            }
+        }
+        self.gro_buffer = gro_buffer;
+        Ok(self.len())
+    }
+
+    fn received_addr(&self, index: usize) -> SocketAddr {
+        // kernel initialized the address and its length
+        let addr =
+            unsafe { SockAddr::new(self.addrs[index], self.headers[index].msg_hdr.msg_namelen) };
+        addr.as_socket()
+            .unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into())
+    }
+
+    /// sends packets of batch to their addresses from `fd` with `sendmmsg`, packets
+    /// that can't be sent are skipped, if `gso` is set consecutive packets that go to
+    /// the same address are sent as one segmented packet with `UDP_SEGMENT`
+    ///
+    /// returns indexes of packets that couldn't be sent and whether kernel rejected
+    /// segmented packets, packets of rejected segmented packets are sent one by one
+    pub(crate) fn send_mmsg(&mut self, fd: RawFd, gso: bool) -> (Vec<usize>, bool) {
+        self.headers.clear();
+        self.iovecs.clear();
+        self.addrs.clear();
+        self.controls.clear();
+        self.group_lens.clear();
+        for (index, (size, _)) in self.packets.iter().enumerate() {
+            self.iovecs.push(libc::iovec {
+                iov_base: self.buffer[index * self.slot_size..].as_mut_ptr().cast(),
+                iov_len: *size,
+            });
+        }
+        let mut start = 0;
+        while start < self.packets.len() {
+            let end = if gso {
+                self.segment_group_end(start)
+            } else {
+                start + 1
+            };
+            self.group_lens.push(end - start);
+            self.addrs.push(to_storage(&self.packets[start].1));
+            self.controls.push(Control([0; 32]));
+            start = end;
+        }
+        // headers are built after the other vectors so their items don't move anymore
+        let mut first_packet = 0;
+        for (index, group_len) in self.group_lens.iter().enumerate() {
+            let addr = SockAddr::from(self.packets[first_packet].1);
+            let mut header: libc::msghdr = unsafe { mem::zeroed() };
+            header.msg_name = (&mut self.addrs[index] as *mut libc::sockaddr_storage).cast();
+            header.msg_namelen = addr.len();
+            header.msg_iov = &mut self.iovecs[first_packet];
+            header.msg_iovlen = *group_len;
+            if *group_len > 1 {
+                let segment_size = self.packets[first_packet].0 as u16;
+                header.msg_control = self.controls[index].0.as_mut_ptr().cast();
+                header.msg_controllen = control_len::<u16>();
+                // control buffer is big enough for one `u16` option
+                unsafe {
+                    let cmsg = libc::CMSG_FIRSTHDR(&header);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as usize;
+                    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast(), segment_size);
+                }
+            }
+            self.headers.push(libc::mmsghdr {
+                msg_hdr: header,
+                msg_len: 0,
+            });
+            first_packet += group_len;
+        }
+
+        let mut failed = Vec::new();
+        let mut gso_rejected = false;
+        let mut offset = 0;
+        let mut first_packet = 0;
+        while offset < self.headers.len() {
+            let count = unsafe {
+                libc::sendmmsg(
+                    fd,
+                    self.headers[offset..].as_mut_ptr(),
+                    (self.headers.len() - offset) as libc::c_uint,
+                    0,
+                )
+            };
+            if count > 0 {
+                let groups = &self.group_lens[offset..offset + count as usize];
+                first_packet += groups.iter().sum::<usize>();
+                offset += count as usize;
+                continue;
+            }
+            // the message at `offset` failed
+            let error = io::Error::last_os_error();
+            let group_len = self.group_lens[offset];
+            let packets = first_packet..first_packet + group_len;
+            if group_len > 1 {
+                // e.g. device doesn't support checksum offload
+                gso_rejected |= matches!(error.raw_os_error(), Some(libc::EIO | libc::EINVAL));
+                failed.extend(self.send_one_by_one(fd, packets));
+            } else {
+                failed.extend(packets);
+            }
+            first_packet += group_len;
+            offset += 1;
+        }
+        (failed, gso_rejected)
+    }
+
+    /// returns the end of packets from `start` that can be sent as one segmented packet,
+    /// all of them go to the same address and have the same size except the last one
+    /// that can be smaller
+    fn segment_group_end(&self, start: usize) -> usize {
+        let (segment_size, addr) = self.packets[start];
+        let mut end = start + 1;
+        let mut total_size = segment_size;
+        while end < self.packets.len() && end - start < MAX_SEGMENTS {
+            let (size, next_addr) = self.packets[end];
+            let previous_is_full = self.packets[end - 1].0 == segment_size;
+            if next_addr != addr
+                || !previous_is_full
+                || size == 0
+                || size > segment_size
+                || total_size + size > MAX_SEGMENTED_SIZE
+            {
+                break;
+            }
+            total_size += size;
+            end += 1;
+        }
+        end
+    }
+
+    /// sends each packet of `packets` with its own syscall and returns indexes
+    /// of the ones that couldn't be sent
+    fn send_one_by_one(&self, fd: RawFd, packets: Range<usize>) -> Vec<usize> {
+        packets
+            .filter(|index| {
+                let (packet, addr) = self.packet(*index);
+                let addr = SockAddr::from(addr);
+                let result = unsafe {
+                    libc::sendto(
+                        fd,
+                        packet.as_ptr().cast(),
+                        packet.len(),
+                        0,
+                        addr.as_ptr(),
+                        addr.len(),
+                    )
+                };
+                result < 0
+            })
+            .collect()
+    }
+}
+
+/// returns size of control buffer that fits one option of type `T`
+fn control_len<T>() -> usize {
+    // it only calculates the size
+    let len = unsafe { libc::CMSG_SPACE(mem::size_of::<T>() as u32) } as usize;
+    debug_assert!(len <= mem::size_of::<Control>());
+    len
+}
+
+fn to_storage(addr: &SocketAddr) -> libc::sockaddr_storage {
+    let addr = SockAddr::from(*addr);
+    // zeroed `sockaddr_storage` is valid and `SockAddr` is at most as big as it
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        std::ptr::copy_nonoverlapping(
+            addr.as_ptr().cast::<u8>(),
+            (&mut storage as *mut libc::sockaddr_storage).cast::<u8>(),
+            addr.len() as usize,
+        );
+        storage
+    }
+}
+
+/// returns size of segments of a received coalesced packet, `None` if it's not coalesced
+fn gro_segment_size(header: &libc::msghdr) -> Option<usize> {
+    // kernel wrote valid control messages in the control buffer
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(header);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                let size = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::c_int>());
+                return Some(size as usize);
+            }
+            cmsg = libc::CMSG_NXTHDR(header, cmsg);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::UdpSocket, os::fd::AsRawFd, str::FromStr, time::Duration};
+
+    fn push_packets(batch: &mut PacketBatch, packets: &[&[u8]], addr: SocketAddr) {
+        for packet in packets {
+            batch.free_slot().unwrap()[..packet.len()].copy_from_slice(packet);
+            batch.push(packet.len(), addr);
+        }
+    }
+
+    fn recv_all(
+        batch: &mut PacketBatch,
+        socket: &UdpSocket,
+        gro: bool,
+        count: usize,
+    ) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+        while received.len() < count {
+            let received_count = batch.recv_mmsg(socket.as_raw_fd(), true, gro).unwrap();
+            for index in 0..received_count {
+                received.push(batch.packet(index).0.to_vec());
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn batch_is_sent_and_received_with_one_syscall() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let mut batch = PacketBatch::new(4, 10, 20);
+        let packets: [&[u8]; 3] = [b"hello", b"hi", b"bye"];
+        push_packets(&mut batch, &packets, receiver.local_addr().unwrap());
+        assert_eq!(batch.send_mmsg(sender.as_raw_fd(), false), (vec![], false));
+
+        let mut batch = PacketBatch::new(4, 10, 20);
+        assert_eq!(recv_all(&mut batch, &receiver, false, 3), packets);
+        assert_eq!(batch.packet(0).1, sender.local_addr().unwrap());
+        let (slot, size) = batch.packet_mut(0);
+        assert_eq!((slot.len(), size), (20, 5));
+    }
+
+    #[test]
+    fn packets_that_fail_in_the_middle_of_batch_are_reported() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        // ipv4 socket can't send to an ipv6 address
+        let unreachable_addr = SocketAddr::from_str("[::1]:9").unwrap();
+
+        let mut batch = PacketBatch::new(4, 10, 20);
+        push_packets(&mut batch, &[b"hello"], receiver_addr);
+        push_packets(&mut batch, &[b"lost"], unreachable_addr);
+        push_packets(&mut batch, &[b"bye"], receiver_addr);
+        assert_eq!(batch.send_mmsg(sender.as_raw_fd(), false), (vec![1], false));
+
+        let mut batch = PacketBatch::new(4, 10, 20);
+        assert_eq!(
+            recv_all(&mut batch, &receiver, false, 2),
+            [b"hello".as_slice(), b"bye"]
+        );
+    }
+
+    #[test]
+    fn segmented_packets_are_split_after_receive() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let value: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                receiver.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                (&value as *const libc::c_int).cast(),
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            // kernel doesn't support gro
+            return;
+        }
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+
+        let mut batch = PacketBatch::new(4, 10, 20);
+        let packets: [&[u8]; 3] = [b"12345", b"67890", b"ab"];
+        push_packets(&mut batch, &packets, receiver_addr);
+        let (failed, gso_rejected) = batch.send_mmsg(sender.as_raw_fd(), true);
+        if gso_rejected {
+            return;
+        }
+        assert!(failed.is_empty());
+        batch.clear();
+        push_packets(&mut batch, &[b"x"], receiver_addr);
+        assert_eq!(batch.send_mmsg(sender.as_raw_fd(), true), (vec![], false));
+
+        // a batch of two slots needs to grow to fit the split packets
+        let mut batch = PacketBatch::new(2, 10, 20);
+        let received = recv_all(&mut batch, &receiver, true, 4);
+        assert_eq!(received, [&b"12345"[..], b"67890", b"ab", b"x"]);
+
+        // big batches don't get a gro buffer of `MAX_PACKET_SIZE` for each of their packets
+        let mut batch = PacketBatch::new(MAX_BATCH_SIZE, 10, 20);
+        push_packets(&mut batch, &[b"x"], receiver_addr);
+        assert_eq!(batch.send_mmsg(sender.as_raw_fd(), true), (vec![], false));
+        assert_eq!(recv_all(&mut batch, &receiver, true, 1), [b"x"]);
+        assert_eq!(batch.gro_buffer.len(), MAX_GRO_MESSAGES * MAX_PACKET_SIZE);
+    }
+}
@@ -1,16 +1,18 @@
 mod ether_helper;
 
-use super::{NonBlockingSocketTrait, SocketTrait};
+use super::{IcmpMode, NonBlockingSocketTrait, SocketOptions, SocketTrait};
 use crate::MAX_PACKET_SIZE;
 use ether_helper::IcmpSlice;
 use etherparse::{
     IcmpEchoHeader, Icmpv4Header, Icmpv4Type, Icmpv6Header, Icmpv6Type, Ipv4HeaderSlice,
 };
+use parking_lot::Mutex;
 use socket2::{Domain, Protocol, Type};
 use std::{
     io,
     mem::MaybeUninit,
     net::{SocketAddr, SocketAddrV6},
+    time::Duration,
 };
 
 /// `IcmpSocket` that is very similiar to `UdpSocket`
@@ -22,18 +24,21 @@ pub struct IcmpSocket {
     _udp_socket: std::net::UdpSocket,
     /// address of udp socket same as `udp_socket.local_addr()`
     udp_socket_addr: SocketAddr,
+    mode: IcmpMode,
 }
 
 impl IcmpSocket {
-    pub fn bind(addr: &SocketAddr) -> io::Result<Self> {
+    pub fn bind(addr: &SocketAddr, options: &SocketOptions) -> io::Result<Self> {
         let udp_socket = std::net::UdpSocket::bind(addr)?;
         let udp_socket_addr = udp_socket.local_addr()?;
         let socket = IcmpSocket::inner_bind(*addr)?;
+        options.apply((&socket).into())?;
 
         Ok(IcmpSocket {
             _udp_socket: udp_socket,
             udp_socket_addr,
             socket,
+            mode: options.icmp_mode,
         })
     }
 
@@ -50,7 +55,8 @@ impl IcmpSocket {
 
 impl SocketTrait for IcmpSocket {
     fn send_to(&self, buffer: &[u8], to: &SocketAddr) -> io::Result<usize> {
-        let packet = craft_icmp_packet(buffer, &self.udp_socket_addr, to)?;
+        let echo_type = answer_echo_type(self.mode);
+        let packet = craft_icmp_packet(buffer, self.mode, echo_type, &self.udp_socket_addr, to)?;
         let mut to_addr = *to;
         // in linux `send_to` on icmpv6 socket requires destination port to be zero
         to_addr.set_port(0);
@@ -64,8 +70,12 @@ impl SocketTrait for IcmpSocket {
             let (size, from_addr) = self.socket.recv_from(unsafe {
                 &mut *(&mut second_buffer as *mut [u8] as *mut [MaybeUninit<u8>])
             })?;
-            let Some(packet) = parse_icmp_packet(&mut second_buffer[..size], local_addr.is_ipv6())
-            else {
+            let Some(packet) = parse_icmp_packet(
+                &mut second_buffer[..size],
+                local_addr.is_ipv6(),
+                self.mode,
+                EchoType::Request,
+            ) else {
                 continue;
             };
             if packet.dst_port != local_addr.port() {
@@ -84,6 +94,10 @@ impl SocketTrait for IcmpSocket {
     fn local_addr(&self) -> io::Result<SocketAddr> {
         Ok(self.udp_socket_addr)
     }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
 }
 
 #[derive(Debug)]
@@ -92,16 +106,16 @@ pub struct NonBlockingIcmpSocket {
     // we need to have a copy of connected addr because we
     // need it to craft packet, in ipv6 we need addr + port and
     // int ipv4 we need port
-    connected_addr: Option<SocketAddr>,
+    connected_addr: Mutex<Option<SocketAddr>>,
 }
 
 impl NonBlockingIcmpSocket {
-    pub fn bind(addr: &SocketAddr) -> io::Result<Self> {
-        let icmp_socket = IcmpSocket::bind(addr)?;
+    pub fn bind(addr: &SocketAddr, options: &SocketOptions) -> io::Result<Self> {
+        let icmp_socket = IcmpSocket::bind(addr, options)?;
         icmp_socket.socket.set_nonblocking(true)?;
         Ok(Self {
             icmp_socket,
-            connected_addr: None,
+            connected_addr: Mutex::new(None),
         })
     }
 }
@@ -114,17 +128,25 @@ impl NonBlockingSocketTrait for NonBlockingIcmpSocket {
     fn send(&self, buffer: &[u8]) -> io::Result<usize> {
         let dst_addr = self
             .connected_addr
+            .lock()
             .ok_or_else(|| Into::<io::Error>::into(io::ErrorKind::NotConnected))?;
-        let packet = craft_icmp_packet(buffer, &self.icmp_socket.udp_socket_addr, &dst_addr)?;
+        let packet = craft_icmp_packet(
+            buffer,
+            self.icmp_socket.mode,
+            EchoType::Request,
+            &self.icmp_socket.udp_socket_addr,
+            &dst_addr,
+        )?;
         self.icmp_socket.socket.send(&packet)
     }
 
-    fn connect(&mut self, addr: &SocketAddr) -> io::Result<()> {
-        self.connected_addr = Some(*addr);
-        let mut addr = *addr;
+    fn connect(&self, addr: &SocketAddr) -> io::Result<()> {
+        let mut connected_addr = self.connected_addr.lock();
+        let mut zero_port_addr = *addr;
         // in linux icmpv6 socket requires destination port to be zero
-        addr.set_port(0);
-        self.icmp_socket.socket.connect(&addr.into())?;
+        zero_port_addr.set_port(0);
+        self.icmp_socket.socket.connect(&zero_port_addr.into())?;
+        *connected_addr = Some(*addr);
         Ok(())
     }
 
@@ -133,23 +155,61 @@ impl NonBlockingSocketTrait for NonBlockingIcmpSocket {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EchoType {
+    Request,
+    Reply,
+}
+
+/// returns type of echo packets that listen side sends to its clients
+pub fn answer_echo_type(mode: IcmpMode) -> EchoType {
+    match mode {
+        IcmpMode::Request => EchoType::Request,
+        IcmpMode::Reply => EchoType::Reply,
+    }
+}
+
+/// icmp is on layer 3 so it has no idea about ports, we use identification part of icmp
+/// packet as destination port and sequence part as source port, but NATs rewrite the
+/// identification of echo requests, so in reply mode requests carry the source port in
+/// identification and replies echo the same identification and sequence back
+fn is_port_swapped(mode: IcmpMode, echo_type: EchoType) -> bool {
+    mode == IcmpMode::Reply && echo_type == EchoType::Request
+}
+
 fn craft_icmp_packet(
     payload: &[u8],
+    mode: IcmpMode,
+    echo_type: EchoType,
     source_addr: &SocketAddr,
     dst_addr: &SocketAddr,
 ) -> io::Result<Vec<u8>> {
-    let echo_header = IcmpEchoHeader {
-        id: dst_addr.port(),
-        seq: source_addr.port(),
+    let (src_port, dst_port) = (source_addr.port(), dst_addr.port());
+    let echo_header = if is_port_swapped(mode, echo_type) {
+        IcmpEchoHeader {
+            id: src_port,
+            seq: dst_port,
+        }
+    } else {
+        IcmpEchoHeader {
+            id: dst_port,
+            seq: src_port,
+        }
     };
 
     let icmp_header = if source_addr.is_ipv4() {
-        let icmp_type = Icmpv4Type::EchoRequest(echo_header);
+        let icmp_type = match echo_type {
+            EchoType::Request => Icmpv4Type::EchoRequest(echo_header),
+            EchoType::Reply => Icmpv4Type::EchoReply(echo_header),
+        };
         Icmpv4Header::with_checksum(icmp_type, payload)
             .to_bytes()
             .to_vec()
     } else {
-        let icmp_type = Icmpv6Type::EchoRequest(echo_header);
+        let icmp_type = match echo_type {
+            EchoType::Request => Icmpv6Type::EchoRequest(echo_header),
+            EchoType::Reply => Icmpv6Type::EchoReply(echo_header),
+        };
         let source_ip = as_socket_addr_v6(*source_addr).ip().octets();
         let destination_ip = as_socket_addr_v6(*dst_addr).ip().octets();
         Icmpv6Header::with_checksum(icmp_type, source_ip, destination_ip, payload)
@@ -170,7 +230,13 @@ pub struct IcmpPacket<'a> {
     pub dst_port: u16,
 }
 
-pub fn parse_icmp_packet(packet: &mut [u8], is_ipv6: bool) -> Option<IcmpPacket<'_>> {
+/// parses icmp echo packets of `echo_type` and drops the others
+pub fn parse_icmp_packet(
+    packet: &mut [u8],
+    is_ipv6: bool,
+    mode: IcmpMode,
+    echo_type: EchoType,
+) -> Option<IcmpPacket<'_>> {
     // according to 'icmp6' man page on freebsd (seems like linux does this too):
     // 'Incoming packets on the socket are received with the IPv6 header and any extension headers removed'
     //
@@ -187,25 +253,27 @@ pub fn parse_icmp_packet(packet: &mut [u8], is_ipv6: bool) -> Option<IcmpPacket<
     };
 
     let icmp = IcmpSlice::from_slice(is_ipv6, &packet[payload_start_index..])?;
-    // we only work with icmp echo requests so if any other type of icmp
-    // packet we receive we just ignore it
-    let correct_icmp_type = if is_ipv6 {
-        etherparse::icmpv6::TYPE_ECHO_REQUEST
-    } else {
-        etherparse::icmpv4::TYPE_ECHO_REQUEST
+    // we only work with one type of icmp echo packets so if any other type
+    // of icmp packet we receive we just ignore it
+    let correct_icmp_type = match (is_ipv6, echo_type) {
+        (true, EchoType::Request) => etherparse::icmpv6::TYPE_ECHO_REQUEST,
+        (true, EchoType::Reply) => etherparse::icmpv6::TYPE_ECHO_REPLY,
+        (false, EchoType::Request) => etherparse::icmpv4::TYPE_ECHO_REQUEST,
+        (false, EchoType::Reply) => etherparse::icmpv4::TYPE_ECHO_REPLY,
     };
     if icmp.type_u8() != correct_icmp_type || icmp.code_u8() != 0 {
         return None;
     }
 
     let bytes5to8 = icmp.bytes5to8();
-    // icmp is on layer 3 so it has no idea about ports
-    // we use identification part of icmp packet as destination port
-    // to identify packets that are really meant for us
-    let dst_port = u16::from_be_bytes([bytes5to8[0], bytes5to8[1]]);
-
-    // we also use sequence part of icmp packet as source port
-    let src_port = u16::from_be_bytes([bytes5to8[2], bytes5to8[3]]);
+    let id = u16::from_be_bytes([bytes5to8[0], bytes5to8[1]]);
+    let seq = u16::from_be_bytes([bytes5to8[2], bytes5to8[3]]);
+    // destination port is used to identify packets that are really meant for us
+    let (src_port, dst_port) = if is_port_swapped(mode, echo_type) {
+        (id, seq)
+    } else {
+        (seq, id)
+    };
 
     let payload_len = icmp.payload().len();
     let total_len = packet.len();
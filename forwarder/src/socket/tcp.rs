@@ -0,0 +1,470 @@
+use super::{NonBlockingSocketTrait, SocketOptions, SocketTrait};
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events, Interest, Token,
+};
+use parking_lot::Mutex;
+use socket2::{Domain, SockRef, Type};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr},
+    os::fd::{AsRawFd, RawFd},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+/// each datagram is prefixed with its length as big endian u16 in tcp stream
+const LENGTH_PREFIX_LEN: usize = 2;
+
+/// maximum number of connections that listen socket keeps, new connections are
+/// closed right away when it's reached so clients can't use up file descriptors
+const MAX_CONNECTIONS: usize = 4096;
+
+/// maximum bytes of frames that wait for a connection to become writable, datagrams
+/// that don't fit are dropped like when send buffer of a udp socket is full
+const MAX_PENDING_WRITE: usize = 256 * 1024;
+
+/// size of chunks that are read from connections
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// token of listener in poll of listen socket, tokens of connections count up from 0
+const LISTENER: Token = Token(usize::MAX);
+
+/// maximum events that listen socket handles in one poll
+const EVENTS_CAPACITY: usize = 1024;
+
+/// tcp listener that behaves like `UdpSocket`, each accepted connection is a
+/// client and its address is the address of connection, listener and connections
+/// are polled by the thread that calls `recv_from` so nothing blocks on a slow client
+#[derive(Debug)]
+pub struct TcpSocket {
+    local_addr: SocketAddr,
+    listener: TcpListener,
+    /// only one worker waits on poll at a time, others wait on its lock, it's
+    /// boxed so tcp doesn't make `Socket` of other protocols bigger
+    poll: Mutex<Box<ListenPoll>>,
+    registry: mio::Registry,
+    connections: Mutex<Connections>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+#[derive(Debug)]
+struct ListenPoll {
+    poll: mio::Poll,
+    events: Events,
+    /// connections that may have datagrams to read, they are read in turn
+    /// so a busy connection doesn't starve the others
+    readable: VecDeque<Token>,
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    by_token: HashMap<Token, Connection>,
+    tokens: HashMap<SocketAddr, Token>,
+    next_token: usize,
+}
+
+#[derive(Debug)]
+struct Connection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    /// bytes that are read from stream but are not a complete datagram yet
+    read_buffer: Vec<u8>,
+    /// frames that wait for stream to become writable
+    write_buffer: Vec<u8>,
+}
+
+impl TcpSocket {
+    pub fn bind(addr: &SocketAddr, options: &SocketOptions) -> io::Result<Self> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        options.apply(SockRef::from(&listener))?;
+        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(listener);
+        let local_addr = listener.local_addr()?;
+
+        let poll = mio::Poll::new()?;
+        let registry = poll.registry().try_clone()?;
+        registry.register(&mut listener, LISTENER, Interest::READABLE)?;
+        Ok(Self {
+            local_addr,
+            listener,
+            poll: Mutex::new(Box::new(ListenPoll {
+                poll,
+                events: Events::with_capacity(EVENTS_CAPACITY),
+                readable: VecDeque::new(),
+            })),
+            registry,
+            connections: Mutex::default(),
+            read_timeout: Mutex::new(None),
+        })
+    }
+
+    /// accepts pending connections until there isn't any or there are `MAX_CONNECTIONS`
+    fn accept_connections(&self) {
+        loop {
+            let (stream, addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return,
+                Err(error) => {
+                    log::warn!("couldn't accept tcp connection: {error}");
+                    return;
+                }
+            };
+            let mut connections = self.connections.lock();
+            if connections.by_token.len() >= MAX_CONNECTIONS {
+                // dropping the stream closes it
+                log::debug!("closed tcp connection of '{addr}', there are too many connections");
+                continue;
+            }
+            if let Err(error) = connections.insert(stream, addr, &self.registry) {
+                log::warn!("couldn't accept tcp connection of '{addr}': {error}");
+            }
+        }
+    }
+
+    /// returns the next datagram of connection or `None` if it doesn't have a complete
+    /// one, connection is closed if it's broken or its client closed it
+    fn recv_from_connection(&self, token: Token, buffer: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let mut connections = self.connections.lock();
+        let connection = connections.by_token.get_mut(&token)?;
+        match recv_frame(&connection.stream, &mut connection.read_buffer, buffer) {
+            Ok(size) => Some((size, connection.addr)),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => None,
+            Err(error) => {
+                log::debug!("tcp connection of '{}' closed: {error}", connection.addr);
+                connections.remove(token, &self.registry);
+                None
+            }
+        }
+    }
+
+    fn flush_connection(&self, token: Token) {
+        let mut connections = self.connections.lock();
+        let Some(connection) = connections.by_token.get_mut(&token) else {
+            return;
+        };
+        if let Err(error) = write_pending(&connection.stream, &mut connection.write_buffer) {
+            log::debug!("tcp connection of '{}' closed: {error}", connection.addr);
+            connections.remove(token, &self.registry);
+        }
+    }
+}
+
+impl Connections {
+    fn insert(
+        &mut self,
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        registry: &mio::Registry,
+    ) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        registry.register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+        let connection = Connection {
+            stream,
+            addr,
+            read_buffer: Vec::new(),
+            write_buffer: Vec::new(),
+        };
+        self.by_token.insert(token, connection);
+        if let Some(old_token) = self.tokens.insert(addr, token) {
+            self.remove(old_token, registry);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, token: Token, registry: &mio::Registry) {
+        let Some(mut connection) = self.by_token.remove(&token) else {
+            return;
+        };
+        if self.tokens.get(&connection.addr) == Some(&token) {
+            self.tokens.remove(&connection.addr);
+        }
+        registry.deregister(&mut connection.stream).ok();
+        connection.stream.shutdown(Shutdown::Both).ok();
+    }
+}
+
+impl SocketTrait for TcpSocket {
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut poll = self.poll.lock();
+        let deadline = self
+            .read_timeout
+            .lock()
+            .map(|timeout| Instant::now() + timeout);
+        loop {
+            while let Some(token) = poll.readable.pop_front() {
+                if let Some(received) = self.recv_from_connection(token, buffer) {
+                    poll.readable.push_back(token);
+                    return Ok(received);
+                }
+            }
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => Some(timeout),
+                    _ => return Err(io::ErrorKind::WouldBlock.into()),
+                },
+                None => None,
+            };
+            let ListenPoll {
+                poll,
+                events,
+                readable,
+            } = &mut **poll;
+            if let Err(error) = poll.poll(events, timeout) {
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept_connections(),
+                    token => {
+                        if event.is_writable() {
+                            self.flush_connection(token);
+                        }
+                        if event.is_readable() || event.is_read_closed() || event.is_error() {
+                            readable.push_back(token);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// queues the datagram if connection isn't writable, returns `WouldBlock` and drops
+    /// it if too much is queued, so frames are never cut in the middle
+    fn send_to(&self, buffer: &[u8], to: &SocketAddr) -> io::Result<usize> {
+        let mut connections = self.connections.lock();
+        let token = *connections
+            .tokens
+            .get(to)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        // tokens only point to existing connections
+        let connection = connections.by_token.get_mut(&token).unwrap();
+        queue_frame(&mut connection.write_buffer, buffer)?;
+        if let Err(error) = write_pending(&connection.stream, &mut connection.write_buffer) {
+            log::debug!("tcp connection of '{to}' closed: {error}");
+            connections.remove(token, &self.registry);
+            return Err(error);
+        }
+        Ok(buffer.len())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock() = timeout;
+        Ok(())
+    }
+
+    fn disconnect(&self, client_addr: &SocketAddr) {
+        let mut connections = self.connections.lock();
+        if let Some(token) = connections.tokens.get(client_addr).copied() {
+            connections.remove(token, &self.registry);
+        }
+    }
+}
+
+/// tcp socket that connects to remote and behaves like `NonBlockingUdpSocket`
+#[derive(Debug)]
+pub struct NonBlockingTcpSocket {
+    socket: socket2::Socket,
+    /// bytes that are read from stream but are not a complete datagram yet
+    read_buffer: Mutex<Vec<u8>>,
+    /// frames that wait for the connection to be established or become writable,
+    /// it also makes sure that datagrams of multiple writers don't get mixed
+    write_buffer: Mutex<Vec<u8>>,
+    /// connection is established, frames are only queued before it
+    connected: AtomicBool,
+}
+
+impl NonBlockingTcpSocket {
+    pub fn bind(addr: &SocketAddr, options: &SocketOptions) -> io::Result<Self> {
+        let socket = socket2::Socket::new(
+            Domain::for_address(*addr),
+            Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        socket.bind(&(*addr).into())?;
+        options.apply((&socket).into())?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            read_buffer: Mutex::new(Vec::new()),
+            write_buffer: Mutex::new(Vec::new()),
+            connected: AtomicBool::new(false),
+        })
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl NonBlockingSocketTrait for NonBlockingTcpSocket {
+    /// starts connecting without waiting for it, so peers can be created while lock of
+    /// peers is held, `flush` is called when poll finds out that connection is established
+    fn connect(&self, addr: &SocketAddr) -> io::Result<()> {
+        match self.socket.connect(&(*addr).into()) {
+            Ok(()) => self.connected.store(true, Ordering::Relaxed),
+            Err(error) if error.raw_os_error() == Some(libc::EINPROGRESS) => (),
+            Err(error) => return Err(error),
+        }
+        self.socket.set_nodelay(true)?;
+        Ok(())
+    }
+
+    /// queues the datagram if connection isn't writable, returns `WouldBlock` and drops
+    /// it if too much is queued, connection is shut down if writing to it fails
+    fn send(&self, buffer: &[u8]) -> io::Result<usize> {
+        let mut write_buffer = self.write_buffer.lock();
+        queue_frame(&mut write_buffer, buffer)?;
+        if self.connected.load(Ordering::Relaxed) {
+            if let Err(error) = write_pending(&self.socket, &mut write_buffer) {
+                self.socket.shutdown(Shutdown::Both).ok();
+                return Err(error);
+            }
+        }
+        Ok(buffer.len())
+    }
+
+    /// returns `WouldBlock` when there is no complete datagram and
+    /// `UnexpectedEof` when remote closed the connection
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        recv_frame(&self.socket, &mut self.read_buffer.lock(), buffer)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::ErrorKind::InvalidInput.into())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut write_buffer = self.write_buffer.lock();
+        // socket becomes writable for the first time when connection is established,
+        // errors of connecting are returned by the first write
+        self.connected.store(true, Ordering::Relaxed);
+        let result = write_pending(&self.socket, &mut write_buffer);
+        if result.is_err() {
+            self.socket.shutdown(Shutdown::Both).ok();
+        }
+        result
+    }
+}
+
+/// appends frame of `datagram` to `pending`, datagrams that don't fit in
+/// `MAX_PENDING_WRITE` are dropped as a whole with `WouldBlock`
+fn queue_frame(pending: &mut Vec<u8>, datagram: &[u8]) -> io::Result<()> {
+    let len =
+        u16::try_from(datagram.len()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    if pending.len() + LENGTH_PREFIX_LEN + datagram.len() > MAX_PENDING_WRITE {
+        return Err(io::ErrorKind::WouldBlock.into());
+    }
+    pending.extend_from_slice(&len.to_be_bytes());
+    pending.extend_from_slice(datagram);
+    Ok(())
+}
+
+/// writes `pending` until it's empty or `stream` would block, written bytes are
+/// removed from it so the rest is written on the next call
+fn write_pending(mut stream: impl Write, pending: &mut Vec<u8>) -> io::Result<()> {
+    while !pending.is_empty() {
+        match stream.write(pending) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(size) => {
+                pending.drain(..size);
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// reads from `stream` until `read_buffer` has a complete datagram and moves it to
+/// `buffer`, returns `WouldBlock` when there isn't any and `UnexpectedEof` when the
+/// other side closed the connection
+fn recv_frame(
+    mut stream: impl Read,
+    read_buffer: &mut Vec<u8>,
+    buffer: &mut [u8],
+) -> io::Result<usize> {
+    let mut chunk = [0u8; READ_CHUNK_SIZE];
+    loop {
+        if let Some(size) = take_frame(read_buffer, buffer) {
+            return Ok(size);
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(size) => read_buffer.extend_from_slice(&chunk[..size]),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// moves the first complete datagram of `read_buffer` to `buffer` and
+/// returns its size, datagrams that are bigger than `buffer` get truncated
+fn take_frame(read_buffer: &mut Vec<u8>, buffer: &mut [u8]) -> Option<usize> {
+    let len: [u8; LENGTH_PREFIX_LEN] = read_buffer.get(..LENGTH_PREFIX_LEN)?.try_into().ok()?;
+    let frame_len = LENGTH_PREFIX_LEN + usize::from(u16::from_be_bytes(len));
+    if read_buffer.len() < frame_len {
+        return None;
+    }
+    let datagram = &read_buffer[LENGTH_PREFIX_LEN..frame_len];
+    let size = datagram.len().min(buffer.len());
+    buffer[..size].copy_from_slice(&datagram[..size]);
+    read_buffer.drain(..frame_len);
+    Some(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_taken_only_when_complete() {
+        let mut stream = Vec::new();
+        queue_frame(&mut stream, b"hello").unwrap();
+        queue_frame(&mut stream, b"hi").unwrap();
+
+        let mut buffer = [0u8; 10];
+        let mut read_buffer = stream[..4].to_vec();
+        assert_eq!(take_frame(&mut read_buffer, &mut buffer), None);
+
+        read_buffer.extend_from_slice(&stream[4..]);
+        let size = take_frame(&mut read_buffer, &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"hello");
+        let size = take_frame(&mut read_buffer, &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"hi");
+        assert!(read_buffer.is_empty());
+
+        let mut read_buffer = Vec::new();
+        let size = recv_frame(&stream[..7], &mut read_buffer, &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"hello");
+        let size = recv_frame(&stream[7..], &mut read_buffer, &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"hi");
+        let error = recv_frame(&[][..], &mut read_buffer, &mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // datagrams are dropped as a whole when too much is pending
+        let mut pending = Vec::new();
+        let error = loop {
+            if let Err(error) = queue_frame(&mut pending, &[0u8; 1000]) {
+                break error;
+            }
+        };
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(pending.len() <= MAX_PENDING_WRITE);
+        assert_eq!(pending.len() % (LENGTH_PREFIX_LEN + 1000), 0);
+    }
+}
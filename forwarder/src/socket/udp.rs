@@ -1,58 +1,159 @@
-use super::{NonBlockingSocketTrait, SocketTrait};
-use std::{io, net::SocketAddr};
+use super::{NonBlockingSocketTrait, PacketBatch, SocketOptions, SocketTrait};
+use socket2::{Domain, SockRef, Type};
+use std::{
+    io, mem,
+    net::SocketAddr,
+    os::fd::{AsRawFd, RawFd},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 #[derive(Debug)]
-pub struct UdpSocket(std::net::UdpSocket);
+pub struct UdpSocket {
+    socket: std::net::UdpSocket,
+    /// kernel coalesces received packets, so they need to be received by
+    /// `recv_from_batch` that splits them
+    gro: bool,
+    /// packets of a batch that go to the same client are sent as one segmented
+    /// packet, it's turned off when kernel rejects segmented packets
+    gso: AtomicBool,
+}
 
 impl UdpSocket {
-    pub fn bind(address: &SocketAddr) -> io::Result<Self> {
+    pub fn bind(address: &SocketAddr, options: &SocketOptions) -> io::Result<Self> {
         let socket = std::net::UdpSocket::bind(address)?;
-        Ok(UdpSocket(socket))
+        Self::new(socket, options)
+    }
+
+    /// same as `bind` but sets `SO_REUSEPORT` so multiple sockets can be bound to `address`
+    pub fn bind_reuse_port(address: &SocketAddr, options: &SocketOptions) -> io::Result<Self> {
+        let socket = socket2::Socket::new(
+            Domain::for_address(*address),
+            Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&(*address).into())?;
+        Self::new(socket.into(), options)
+    }
+
+    fn new(socket: std::net::UdpSocket, options: &SocketOptions) -> io::Result<Self> {
+        options.apply(SockRef::from(&socket))?;
+        let (gro, gso) = if options.udp_offload {
+            enable_offload(socket.as_raw_fd())
+        } else {
+            (false, false)
+        };
+        Ok(Self {
+            socket,
+            gro,
+            gso: AtomicBool::new(gso),
+        })
     }
 }
 
 impl SocketTrait for UdpSocket {
     fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        self.0.recv_from(buffer)
+        self.socket.recv_from(buffer)
     }
 
     fn send_to(&self, buffer: &[u8], to: &SocketAddr) -> io::Result<usize> {
-        self.0.send_to(buffer, to)
+        self.socket.send_to(buffer, to)
     }
 
     fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.0.local_addr()
+        self.socket.local_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn recv_from_batch(&self, batch: &mut PacketBatch) -> io::Result<usize> {
+        batch.recv_mmsg(self.socket.as_raw_fd(), true, self.gro)
+    }
+
+    fn send_batch(&self, batch: &mut PacketBatch) -> Vec<usize> {
+        let gso = self.gso.load(Ordering::Relaxed);
+        let (failed, gso_rejected) = batch.send_mmsg(self.socket.as_raw_fd(), gso);
+        if gso_rejected {
+            log::warn!("kernel rejected segmented udp packets, disabling gso");
+            self.gso.store(false, Ordering::Relaxed);
+        }
+        failed
     }
 }
 
 #[derive(Debug)]
-pub struct NonBlockingUdpSocket(mio::net::UdpSocket);
+pub struct NonBlockingUdpSocket {
+    socket: mio::net::UdpSocket,
+    /// kernel coalesces received packets, so they need to be received by
+    /// `recv_batch` that splits them
+    gro: bool,
+}
 
 impl NonBlockingUdpSocket {
-    pub fn bind(address: &SocketAddr) -> io::Result<Self> {
+    pub fn bind(address: &SocketAddr, options: &SocketOptions) -> io::Result<Self> {
         let socket = mio::net::UdpSocket::bind(*address)?;
-        Ok(Self(socket))
+        options.apply(SockRef::from(&socket))?;
+        // peers send one packet at a time so only gro is useful for them
+        let gro = options.udp_offload && enable_offload(socket.as_raw_fd()).0;
+        Ok(Self { socket, gro })
     }
 
     pub fn as_inner(&mut self) -> &mut mio::net::UdpSocket {
-        &mut self.0
+        &mut self.socket
     }
 }
 
 impl NonBlockingSocketTrait for NonBlockingUdpSocket {
     fn send(&self, buffer: &[u8]) -> io::Result<usize> {
-        self.0.send(buffer)
+        self.socket.send(buffer)
     }
 
-    fn connect(&mut self, addr: &SocketAddr) -> io::Result<()> {
-        self.0.connect(*addr)
+    fn connect(&self, addr: &SocketAddr) -> io::Result<()> {
+        self.socket.connect(*addr)
     }
 
     fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
-        self.0.recv(buffer)
+        self.socket.recv(buffer)
     }
 
     fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.0.local_addr()
+        self.socket.local_addr()
+    }
+
+    fn recv_batch(&self, batch: &mut PacketBatch) -> io::Result<usize> {
+        batch.recv_mmsg(self.socket.as_raw_fd(), false, self.gro)
+    }
+}
+
+/// enables `UDP_GRO` and checks that kernel supports `UDP_SEGMENT`, returns
+/// whether each of them can be used, the unsupported ones stay disabled
+fn enable_offload(fd: RawFd) -> (bool, bool) {
+    let gro = set_udp_option(fd, libc::UDP_GRO, 1);
+    // zero doesn't segment packets by default, it only checks that option exists
+    let gso = set_udp_option(fd, libc::UDP_SEGMENT, 0);
+    for (name, result) in [("gro", &gro), ("gso", &gso)] {
+        if let Err(error) = result {
+            log::debug!("udp {name} is disabled, kernel doesn't support it: {error}");
+        }
+    }
+    (gro.is_ok(), gso.is_ok())
+}
+
+fn set_udp_option(fd: RawFd, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_UDP,
+            name,
+            (&value as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(())
 }
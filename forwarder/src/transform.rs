@@ -0,0 +1,203 @@
+use std::{fmt::Debug, str::FromStr};
+
+/// a step of processing that is applied on packets of one side of forwarder,
+/// such as encryption, padding, compression or header obfuscation
+///
+/// both methods get the whole working `buffer` that has the packet at `buffer[..size]`,
+/// the free space after `size` is at least the sum of `max_overhead` of all transforms,
+/// they return the new size of packet or `None` if the packet needs to be dropped
+pub trait Transform: Send + Sync + Debug {
+    /// called on packets that are going to be sent from this side
+    fn encode(&self, buffer: &mut [u8], size: usize) -> Option<usize>;
+
+    /// called on packets that are received on this side
+    fn decode(&self, buffer: &mut [u8], size: usize) -> Option<usize>;
+
+    /// same as `decode` but also returns the sequence that the other side put in the
+    /// packet if there is any, packets whose sequence is seen before get dropped
+    fn decode_sequenced(
+        &self,
+        buffer: &mut [u8],
+        size: usize,
+    ) -> Option<(usize, Option<Sequence>)> {
+        Some((self.decode(buffer, size)?, None))
+    }
+
+    /// maximum amount of bytes that this transform may add to a packet
+    fn max_overhead(&self) -> usize {
+        0
+    }
+
+    /// whether this transform encrypts packets
+    fn encrypts(&self) -> bool {
+        false
+    }
+
+    /// whether this transform pads packets, it needs to come before encryption in
+    /// a chain so length of padding gets encrypted too
+    fn pads(&self) -> bool {
+        false
+    }
+}
+
+/// sequence of a packet that the other side put in it so replayed packets can be dropped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sequence {
+    /// random id of the run of sender, a new one starts when `counter` overflows
+    pub session: u32,
+    /// number of packets that are sent before this one in `session`
+    pub counter: u32,
+    /// unix time in seconds when packet was sent
+    pub sent_at: u32,
+}
+
+/// ordered list of `Transform`s, encoding happens in order and decoding
+/// happens in reverse order so the last transform is the outer most layer
+#[derive(Debug, Default)]
+pub struct TransformChain(Vec<Box<dyn Transform>>);
+
+impl TransformChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// appends `transform` to the end of chain
+    pub fn push(&mut self, transform: Box<dyn Transform>) {
+        self.0.push(transform);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn encode(&self, buffer: &mut [u8], mut size: usize) -> Option<usize> {
+        for transform in &self.0 {
+            size = transform.encode(buffer, size)?;
+        }
+        Some(size)
+    }
+
+    pub fn decode(&self, buffer: &mut [u8], size: usize) -> Option<usize> {
+        self.decode_sequenced(buffer, size).map(|(size, _)| size)
+    }
+
+    /// same as `decode` but also returns the sequence number that one of the transforms found
+    pub fn decode_sequenced(
+        &self,
+        buffer: &mut [u8],
+        mut size: usize,
+    ) -> Option<(usize, Option<Sequence>)> {
+        let mut sequence = None;
+        for transform in self.0.iter().rev() {
+            let (new_size, new_sequence) = transform.decode_sequenced(buffer, size)?;
+            size = new_size;
+            sequence = sequence.or(new_sequence);
+        }
+        Some((size, sequence))
+    }
+
+    pub fn max_overhead(&self) -> usize {
+        self.0
+            .iter()
+            .map(|transform| transform.max_overhead())
+            .sum()
+    }
+
+    /// returns whether a padding comes after an encryption, such padding is sent in clear
+    pub fn pads_after_encryption(&self) -> bool {
+        self.0
+            .iter()
+            .skip_while(|transform| !transform.encrypts())
+            .any(|transform| transform.pads())
+    }
+}
+
+/// side of forwarder, listen side is where clients are and remote side is
+/// where remote server is
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Side {
+    Listen,
+    Remote,
+}
+
+impl FromStr for Side {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "listen" => Ok(Side::Listen),
+            "remote" => Ok(Side::Remote),
+            _ => anyhow::bail!("invalid side name, valid sides are: 'listen' and 'remote'"),
+        }
+    }
+}
+
+/// `TransformChain`s of both sides of forwarder
+///
+/// packets from client get decoded by `listen` and then encoded by `remote`,
+/// packets from remote get decoded by `remote` and then encoded by `listen`
+#[derive(Debug, Default)]
+pub struct Transforms {
+    pub listen: TransformChain,
+    pub remote: TransformChain,
+}
+
+impl Transforms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// appends `transform` to the end of chain of `side`
+    pub fn push(&mut self, side: Side, transform: Box<dyn Transform>) {
+        match side {
+            Side::Listen => self.listen.push(transform),
+            Side::Remote => self.remote.push(transform),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listen.is_empty() && self.remote.is_empty()
+    }
+
+    /// maximum amount of bytes that a packet may grow while passing through forwarder
+    pub fn max_overhead(&self) -> usize {
+        self.listen.max_overhead() + self.remote.max_overhead()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Append(u8);
+
+    impl Transform for Append {
+        fn encode(&self, buffer: &mut [u8], size: usize) -> Option<usize> {
+            buffer[size] = self.0;
+            Some(size + 1)
+        }
+
+        fn decode(&self, buffer: &mut [u8], size: usize) -> Option<usize> {
+            // drop packets that are not encoded by us
+            (size > 0 && buffer[size - 1] == self.0).then_some(size - 1)
+        }
+
+        fn max_overhead(&self) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn chain_decodes_in_reverse_order() {
+        let mut chain = TransformChain::new();
+        chain.push(Box::new(Append(1)));
+        chain.push(Box::new(Append(2)));
+        assert_eq!(chain.max_overhead(), 2);
+
+        let mut buffer = [0u8; 3];
+        let size = chain.encode(&mut buffer, 1).unwrap();
+        assert_eq!(buffer[..size], [0, 1, 2]);
+        assert_eq!(chain.decode(&mut buffer, size), Some(1));
+        assert_eq!(chain.decode(&mut buffer, 1), None);
+    }
+}
@@ -1,5 +1,7 @@
-use anyhow::{bail, ensure};
-use std::{fmt::Display, net::SocketAddr, str::FromStr};
+use crate::resolver::{Resolver, SystemResolver};
+use anyhow::{bail, ensure, Context};
+use parking_lot::Mutex;
+use std::{collections::BTreeSet, fmt::Display, net::SocketAddr, ops::Deref, str::FromStr};
 
 /// # Examples
 /// ```
@@ -15,24 +17,34 @@ use std::{fmt::Display, net::SocketAddr, str::FromStr};
 ///     )
 /// );
 /// assert_eq!(uri.protocol, Protocol::Udp);
+///
+/// // hostnames get resolved while parsing
+/// let uri = Uri::from_str("localhost:8000/tcp")?;
+/// assert_eq!(uri.host.as_deref(), Some("localhost"));
+/// assert!(uri.addr.ip().is_loopback());
 /// # Ok::<(), anyhow::Error>(())
 /// ```
 #[derive(Clone, Copy, Debug)]
 pub struct Uri {
+    /// resolved address of `host` or the literal address of uri
     pub addr: SocketAddr,
     pub protocol: Protocol,
+    /// hostname of uri without port, `None` if uri has a literal address
+    pub host: Option<Host>,
 }
 
 #[allow(unused)]
 impl Uri {
     pub fn new(addr: SocketAddr, protocol: Protocol) -> Self {
-        Uri { addr, protocol }
+        Uri {
+            addr,
+            protocol,
+            host: None,
+        }
     }
-}
 
-impl FromStr for Uri {
-    type Err = anyhow::Error;
-    fn from_str(s: &str) -> anyhow::Result<Self> {
+    /// same as `Uri::from_str` but hostnames get resolved by `resolver`
+    pub fn parse(s: &str, resolver: &dyn Resolver) -> anyhow::Result<Self> {
         let parts: Vec<&str> = s.split('/').collect();
         ensure!(
             parts.len() <= 2,
@@ -42,7 +54,18 @@ impl FromStr for Uri {
         let addr_str = parts.first().ok_or(anyhow::anyhow!(
             "uri need to have address part like '127.0.0.1:8080'"
         ))?;
-        let addr = SocketAddr::from_str(addr_str)?;
+        let (addr, host) = match SocketAddr::from_str(addr_str) {
+            Ok(addr) => (addr, None),
+            Err(_) => {
+                let (host, port) = addr_str.rsplit_once(':').ok_or(anyhow::anyhow!(
+                    "uri need to have address part like '127.0.0.1:8080' or 'example.org:8080'"
+                ))?;
+                ensure!(!host.is_empty(), "hostname of uri is empty");
+                let port = u16::from_str(port).with_context(|| "invalid port")?;
+                let addr = resolve_host(resolver, host, port, None)?;
+                (addr, Some(Host::new(host)))
+            }
+        };
 
         let protocol = match parts.get(1) {
             Some(protocol_str) => Protocol::from_str(protocol_str)?,
@@ -50,13 +73,92 @@ impl FromStr for Uri {
             None => Protocol::Udp,
         };
 
-        Ok(Uri { addr, protocol })
+        Ok(Uri {
+            addr,
+            protocol,
+            host,
+        })
+    }
+
+    /// resolves `host` again and updates `addr`, returns true if `addr` changed,
+    /// the new address needs to be in the same family as the old one and the
+    /// old address is kept if it's still one of the results
+    pub fn resolve(&mut self, resolver: &dyn Resolver) -> anyhow::Result<bool> {
+        let Some(host) = self.host else {
+            return Ok(false);
+        };
+        let addr = resolve_host(resolver, &host, self.addr.port(), Some(self.addr))?;
+        let changed = addr != self.addr;
+        self.addr = addr;
+        Ok(changed)
+    }
+}
+
+/// hostname of a `Uri`, hostnames are kept once for the whole process so `Uri` stays `Copy`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Host(&'static str);
+
+impl Host {
+    pub fn new(host: &str) -> Self {
+        // there are only a few hostnames in configs so they are never freed
+        static HOSTS: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+        let mut hosts = HOSTS.lock();
+        if let Some(&host) = hosts.get(host) {
+            return Self(host);
+        }
+        let host: &'static str = Box::leak(host.to_owned().into_boxed_str());
+        hosts.insert(host);
+        Self(host)
+    }
+}
+
+impl Deref for Host {
+    type Target = str;
+    fn deref(&self) -> &str {
+        self.0
+    }
+}
+
+impl Display for Host {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// resolves `host` and picks one address, `current` is preferred if it's one of the
+/// results and otherwise the first address that has the same family as it
+fn resolve_host(
+    resolver: &dyn Resolver,
+    host: &str,
+    port: u16,
+    current: Option<SocketAddr>,
+) -> anyhow::Result<SocketAddr> {
+    let addrs = resolver
+        .resolve(host, port)
+        .with_context(|| format!("couldn't resolve '{host}'"))?;
+    let addr = match current {
+        Some(current) if addrs.contains(&current) => Some(current),
+        Some(current) => addrs
+            .into_iter()
+            .find(|addr| addr.is_ipv6() == current.is_ipv6()),
+        None => addrs.into_iter().next(),
+    };
+    addr.ok_or_else(|| anyhow::anyhow!("'{host}' didn't resolve to any usable address"))
+}
+
+impl FromStr for Uri {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Uri::parse(s, &SystemResolver)
     }
 }
 
 impl Display for Uri {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}/{}", self.addr, self.protocol)
+        match self.host {
+            Some(host) => write!(f, "{host}:{}/{}", self.addr.port(), self.protocol),
+            None => write!(f, "{}/{}", self.addr, self.protocol),
+        }
     }
 }
 
@@ -71,6 +173,7 @@ impl TryFrom<&str> for Uri {
 pub enum Protocol {
     Udp,
     Icmp,
+    Tcp,
 }
 
 impl FromStr for Protocol {
@@ -79,8 +182,9 @@ impl FromStr for Protocol {
         match s.to_lowercase().as_str() {
             "udp" => Ok(Protocol::Udp),
             "icmp" => Ok(Protocol::Icmp),
+            "tcp" => Ok(Protocol::Tcp),
             _ => {
-                bail!("invalid socket protocol name, valid socket protocols are: 'udp' and 'icmp'")
+                bail!("invalid socket protocol name, valid socket protocols are: 'udp', 'icmp' and 'tcp'")
             }
         }
     }
@@ -91,6 +195,7 @@ impl Display for Protocol {
         let str = match self {
             Protocol::Icmp => "icmp".to_owned(),
             Protocol::Udp => "udp".to_owned(),
+            Protocol::Tcp => "tcp".to_owned(),
         };
         write!(f, "{str}")
     }
@@ -105,5 +210,45 @@ mod tests {
         assert!(Uri::from_str("127,0:8000/udp").is_err());
         assert!(Uri::from_str("127.0.0.1:8000/haha").is_err());
         assert!(Uri::from_str("").is_err());
+        assert!(Uri::from_str(":8000/udp").is_err());
+        assert!(Uri::from_str("localhost:haha/udp").is_err());
+    }
+
+    #[derive(Debug)]
+    struct StubResolver(Vec<SocketAddr>);
+
+    impl Resolver for StubResolver {
+        fn resolve(&self, _host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+            let mut addrs = self.0.clone();
+            addrs.iter_mut().for_each(|addr| addr.set_port(port));
+            Ok(addrs)
+        }
+    }
+
+    #[test]
+    fn hostname_gets_resolved_and_keeps_family() {
+        let v4 = SocketAddr::from_str("10.0.0.1:0").unwrap();
+        let other_v4 = SocketAddr::from_str("10.0.0.2:0").unwrap();
+        let v6 = SocketAddr::from_str("[::2]:0").unwrap();
+
+        let mut uri = Uri::parse("vpn.example.org:51820/tcp", &StubResolver(vec![v4])).unwrap();
+        assert_eq!(uri.host.as_deref(), Some("vpn.example.org"));
+        assert_eq!(uri.addr, SocketAddr::from_str("10.0.0.1:51820").unwrap());
+        assert_eq!(uri.protocol, Protocol::Tcp);
+        assert_eq!(uri.to_string(), "vpn.example.org:51820/tcp");
+
+        // current address is kept while it's still valid
+        assert!(!uri.resolve(&StubResolver(vec![other_v4, v4])).unwrap());
+        assert!(uri.resolve(&StubResolver(vec![v6, other_v4])).unwrap());
+        assert_eq!(uri.addr, SocketAddr::from_str("10.0.0.2:51820").unwrap());
+        assert!(uri.resolve(&StubResolver(vec![v6])).is_err());
+    }
+
+    #[test]
+    fn same_hostnames_are_kept_once() {
+        let (first, second) = (Host::new("vpn.example.org"), Host::new("vpn.example.org"));
+        assert_eq!(first, second);
+        assert!(std::ptr::eq(first.0, second.0));
+        assert_ne!(first, Host::new("example.org"));
     }
 }
@@ -1,9 +1,23 @@
-use forwarder::uri::Uri;
+use forwarder::{
+    acl::Cidr,
+    config::ForwarderConfig,
+    encryption::{ChaCha20Poly1305, Cipher},
+    kdf::{MasterKey, DEFAULT_SALT},
+    limit::{MaxPeersPolicy, Rate, TrafficLimit},
+    padding::{Padding, PaddingDistribution},
+    resolver::Resolver,
+    socket::{IcmpMode, SocketOptions},
+    transform::{Side, Transform, TransformChain, Transforms},
+    uri::Uri,
+    Direction,
+};
 use std::{
-    io::ErrorKind,
-    net::{SocketAddr, UdpSocket},
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    os::unix::net::UnixStream,
     str::FromStr,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 #[test]
@@ -126,6 +140,1177 @@ fn test_icmpv6_double_forwarder_back_and_forth() {
     spawn_double_forwarder_and_test_connection(forwarder_uri, second_forwarder_uri, remote_uri);
 }
 
+#[test]
+#[ignore = "icmp sockets requires special access, please run this test with ./test_icmp.sh"]
+fn test_icmp_echo_reply_double_forwarder_back_and_forth() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38830/udp").unwrap();
+    let second_forwarder_uri = Uri::from_str("127.0.0.1:38831/icmp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38832/udp").unwrap();
+    let socket_options = SocketOptions {
+        icmp_mode: IcmpMode::Reply,
+        ..Default::default()
+    };
+    for (listen_uri, remote_uri) in [
+        (forwarder_uri, second_forwarder_uri),
+        (second_forwarder_uri, remote_uri),
+    ] {
+        let config = ForwarderConfig::builder(listen_uri, remote_uri)
+            .socket_options(socket_options)
+            .build()
+            .unwrap();
+        forwarder::start(config).unwrap();
+    }
+
+    let timeout = Duration::from_secs(2);
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote.set_read_timeout(Some(timeout)).unwrap();
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 100];
+        let (size, from_addr) = remote.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], "hello".as_bytes());
+        remote.send_to("hi".as_bytes(), from_addr).unwrap();
+    });
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    client.set_read_timeout(Some(timeout)).unwrap();
+    client.send("hello".as_bytes()).unwrap();
+    let mut buffer = [0u8; 100];
+    loop {
+        let size = client
+            .recv(&mut buffer)
+            .map_err(|_| "client didn't receive hi back from remote")
+            .unwrap();
+        // kernel echo replies of our own requests come back too if they are not disabled
+        if &buffer[..size] != "hello".as_bytes() {
+            assert_eq!(&buffer[..size], "hi".as_bytes());
+            break;
+        }
+    }
+}
+
+#[test]
+fn test_tcp_double_forwarder_back_and_forth() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38827/udp").unwrap();
+    let second_forwarder_uri = Uri::from_str("127.0.0.1:38828/tcp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38829/udp").unwrap();
+    spawn_double_forwarder_and_test_connection(forwarder_uri, second_forwarder_uri, remote_uri);
+}
+
+#[test]
+fn test_tcp_connection_is_closed_with_its_peer() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38878/tcp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38879/udp").unwrap();
+    let control_socket =
+        std::env::temp_dir().join(format!("forwarder-test-tcp-{}.sock", std::process::id()));
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .control_socket(&control_socket)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let timeout = Duration::from_secs(1);
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote.set_read_timeout(Some(timeout)).unwrap();
+    let mut client = TcpStream::connect(forwarder_uri.addr).unwrap();
+    client.set_read_timeout(Some(timeout)).unwrap();
+    // datagrams are prefixed with their length
+    client.write_all(b"\0\x05hello").unwrap();
+    let mut buffer = [0u8; 100];
+    let (size, peer_addr) = remote.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"hello");
+    remote.send_to(b"hi", peer_addr).unwrap();
+    client.read_exact(&mut buffer[..4]).unwrap();
+    assert_eq!(&buffer[..4], b"\0\x02hi");
+
+    let mut stream = UnixStream::connect(&control_socket).unwrap();
+    writeln!(stream, "kick {}", client.local_addr().unwrap()).unwrap();
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).unwrap();
+    assert_eq!(response, "{\"ok\":true}\n");
+    assert_eq!(client.read(&mut buffer).unwrap(), 0);
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_chacha20_poly1305_double_forwarder_back_and_forth() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38818/udp").unwrap();
+    let second_forwarder_uri = Uri::from_str("127.0.0.1:38819/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38820/udp").unwrap();
+
+    std::thread::spawn(move || {
+        let encryption = Cipher::ChaCha20Poly1305.new_transform("some_password");
+        let config = ForwarderConfig::builder(forwarder_uri, second_forwarder_uri)
+            .transform(Side::Remote, encryption)
+            .build()
+            .unwrap();
+        forwarder::run_with_config(config).unwrap();
+    });
+    std::thread::spawn(move || {
+        let encryption = Cipher::ChaCha20Poly1305.new_transform("some_password");
+        let config = ForwarderConfig::builder(second_forwarder_uri, remote_uri)
+            .transform(Side::Listen, encryption)
+            .build()
+            .unwrap();
+        forwarder::run_with_config(config).unwrap();
+    });
+    test_connection(&forwarder_uri.addr, &remote_uri.addr);
+}
+
+#[test]
+fn test_handshake_double_forwarder_back_and_forth() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38855/udp").unwrap();
+    let second_forwarder_uri = Uri::from_str("127.0.0.1:38856/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38857/udp").unwrap();
+    for (listen_uri, remote_uri, side) in [
+        (forwarder_uri, second_forwarder_uri, Side::Remote),
+        (second_forwarder_uri, remote_uri, Side::Listen),
+    ] {
+        let encryption = Cipher::Xor.new_transform("some_password");
+        let config = ForwarderConfig::builder(listen_uri, remote_uri)
+            .transform(side, encryption)
+            .handshake(side, "some_password")
+            .build()
+            .unwrap();
+        forwarder::start(config).unwrap();
+    }
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    // xor decoding never fails, but without handshake no peer is created
+    let unauthenticated_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    unauthenticated_client
+        .send_to(b"garbage", second_forwarder_uri.addr)
+        .unwrap();
+    let mut buffer = [0u8; 100];
+    assert!(remote.recv_from(&mut buffer).is_err());
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    for message in [b"first", b"again"] {
+        client.send(message).unwrap();
+        let (size, from_addr) = remote.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], message);
+        remote.send_to(b"hi", from_addr).unwrap();
+        // handshake ack is not forwarded to client
+        let size = client.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"hi");
+    }
+}
+
+#[test]
+fn test_handshake_recovers_after_server_restart() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38880/udp").unwrap();
+    let second_forwarder_uri = Uri::from_str("127.0.0.1:38881/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38882/udp").unwrap();
+    let config = |listen_uri: Uri, remote_uri: Uri, side| {
+        ForwarderConfig::builder(listen_uri, remote_uri)
+            .transform(side, Cipher::Xor.new_transform("some_password"))
+            .handshake(side, "some_password")
+            .build()
+            .unwrap()
+    };
+    forwarder::start(config(forwarder_uri, second_forwarder_uri, Side::Remote)).unwrap();
+    let server = config(second_forwarder_uri, remote_uri, Side::Listen);
+    let handle = forwarder::start(server).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    client.send(b"first").unwrap();
+    let mut buffer = [0u8; 100];
+    let (_, from_addr) = remote.recv_from(&mut buffer).unwrap();
+    remote.send_to(b"hi", from_addr).unwrap();
+    client.recv(&mut buffer).unwrap();
+
+    // restarted server doesn't know the peer, so client needs to send hello again
+    handle.shutdown();
+    handle.join().unwrap();
+    let server = config(second_forwarder_uri, remote_uri, Side::Listen);
+    let handle = forwarder::start(server).unwrap();
+    let start = Instant::now();
+    let received = loop {
+        client.send(b"again").unwrap();
+        match remote.recv_from(&mut buffer) {
+            Ok((size, _)) => break buffer[..size].to_vec(),
+            Err(_) if start.elapsed() < Duration::from_secs(6) => continue,
+            Err(error) => panic!("server didn't get packets after restart: {error}"),
+        }
+    };
+    assert_eq!(received, b"again");
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_derived_key_double_forwarder_back_and_forth() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38862/udp").unwrap();
+    let second_forwarder_uri = Uri::from_str("127.0.0.1:38863/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38864/udp").unwrap();
+    let key = MasterKey::derive("pass", DEFAULT_SALT).unwrap();
+    for (listen_uri, remote_uri, side) in [
+        (forwarder_uri, second_forwarder_uri, Side::Remote),
+        (second_forwarder_uri, remote_uri, Side::Listen),
+    ] {
+        let config = ForwarderConfig::builder(listen_uri, remote_uri)
+            .transform(side, Cipher::Xor.new_keyed_transform(&key))
+            .handshake_with_key(side, &key)
+            .build()
+            .unwrap();
+        forwarder::start(config).unwrap();
+    }
+    test_connection(&forwarder_uri.addr, &remote_uri.addr);
+}
+
+#[test]
+fn test_replayed_packets_are_dropped() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38858/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38859/udp").unwrap();
+    let encryption = ChaCha20Poly1305::new("some_password").with_replay_protection();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .transform(Side::Listen, Box::new(encryption))
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    // client plays the other forwarder
+    let encryption = ChaCha20Poly1305::new("some_password").with_replay_protection();
+    let encrypt = |message: &[u8]| {
+        let mut buffer = [0u8; 100];
+        buffer[..message.len()].copy_from_slice(message);
+        let size = encryption.encode(&mut buffer, message.len()).unwrap();
+        buffer[..size].to_vec()
+    };
+    let (first, second) = (encrypt(b"first"), encrypt(b"second"));
+    for packet in [&second, &first, &second, &first] {
+        client.send(packet).unwrap();
+    }
+
+    let mut buffer = [0u8; 100];
+    for message in [b"second".as_slice(), b"first"] {
+        let size = remote.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], message);
+    }
+    assert!(
+        remote.recv(&mut buffer).is_err(),
+        "replayed packet got forwarded"
+    );
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_packets_replayed_from_another_address_are_dropped() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38883/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38884/udp").unwrap();
+    let encryption = ChaCha20Poly1305::new("some_password").with_replay_protection();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .transform(Side::Listen, Box::new(encryption))
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    // client plays the other forwarder and attacker captured one of its packets
+    let encryption = ChaCha20Poly1305::new("some_password").with_replay_protection();
+    let mut packet = [0u8; 100];
+    packet[..5].copy_from_slice(b"hello");
+    let size = encryption.encode(&mut packet, 5).unwrap();
+    let packet = &packet[..size];
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.send_to(packet, forwarder_uri.addr).unwrap();
+    let mut buffer = [0u8; 100];
+    let size = remote.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"hello");
+
+    // attacker's socket has another port, replayed packet shouldn't get it a peer
+    let attacker = UdpSocket::bind("127.0.0.1:0").unwrap();
+    attacker.send_to(packet, forwarder_uri.addr).unwrap();
+    assert!(
+        remote.recv(&mut buffer).is_err(),
+        "packet replayed from another address got forwarded"
+    );
+    assert_eq!(handle.peers().len(), 1);
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_padded_packets_have_random_sizes() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38860/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38861/udp").unwrap();
+    let new_chain = || {
+        let mut chain = TransformChain::new();
+        let padding = Padding::new(64, PaddingDistribution::Uniform).with_max_packet_size(1200);
+        chain.push(Box::new(padding));
+        chain.push(Cipher::ChaCha20Poly1305.new_transform("some_password"));
+        chain
+    };
+    let transforms = Transforms {
+        listen: new_chain(),
+        remote: TransformChain::new(),
+    };
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .transforms(transforms)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    // client plays the other forwarder
+    let chain = new_chain();
+    let mut buffer = [0u8; 200];
+    let mut sizes = Vec::new();
+    for _ in 0..20 {
+        buffer[..5].copy_from_slice(b"hello");
+        let size = chain.encode(&mut buffer, 5).unwrap();
+        client.send(&buffer[..size]).unwrap();
+        let (size, from_addr) = remote.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"hello");
+
+        remote.send_to(b"hi", from_addr).unwrap();
+        let size = client.recv(&mut buffer).unwrap();
+        sizes.push(size);
+        let size = chain.decode(&mut buffer, size).unwrap();
+        assert_eq!(&buffer[..size], b"hi");
+    }
+    sizes.sort();
+    sizes.dedup();
+    assert!(sizes.len() > 1, "packets of remote are not padded");
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_forwarder_shutdown_and_restart() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38821/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38822/udp").unwrap();
+
+    let config = || {
+        ForwarderConfig::builder(forwarder_uri, remote_uri)
+            .build()
+            .unwrap()
+    };
+    let handle = forwarder::start(config()).unwrap();
+    test_connection(&forwarder_uri.addr, &remote_uri.addr);
+
+    let start = Instant::now();
+    handle.shutdown();
+    handle.join().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // listen address should be free again after join
+    let handle = forwarder::start(config()).unwrap();
+    test_connection(&forwarder_uri.addr, &remote_uri.addr);
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_reload_keeps_peers_that_did_not_change() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38865/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38866/udp").unwrap();
+    let new_remote_uri = Uri::from_str("127.0.0.1:38867/udp").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    let new_remote = UdpSocket::bind(new_remote_uri.addr).unwrap();
+    for socket in [&remote, &new_remote] {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+    }
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    let mut buffer = [0u8; 100];
+    client.send(b"first").unwrap();
+    let (_, peer_addr) = remote.recv_from(&mut buffer).unwrap();
+
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .idle_timeout(Duration::from_secs(60))
+        .peer_limit(
+            Direction::ClientToRemote,
+            TrafficLimit {
+                bytes: None,
+                packets: Some(Rate::new(1, 1)),
+            },
+        )
+        .build()
+        .unwrap();
+    assert_eq!(handle.needs_restart(&config), None);
+    handle.reload(config).unwrap();
+    // same peer forwards packets of client and its new limit applies
+    client.send(b"second").unwrap();
+    client.send(b"dropped").unwrap();
+    let (size, from_addr) = remote.recv_from(&mut buffer).unwrap();
+    assert_eq!(
+        (&buffer[..size], from_addr),
+        (b"second".as_slice(), peer_addr)
+    );
+    assert!(remote.recv_from(&mut buffer).is_err());
+
+    let config = ForwarderConfig::builder(forwarder_uri, new_remote_uri)
+        .build()
+        .unwrap();
+    handle.reload(config).unwrap();
+    // peer of old remote is removed and a new one is created
+    client.send(b"third").unwrap();
+    let size = new_remote.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"third");
+
+    let another_listen_uri = Uri::from_str("127.0.0.1:38868/udp").unwrap();
+    let config = ForwarderConfig::builder(another_listen_uri, new_remote_uri)
+        .build()
+        .unwrap();
+    assert_eq!(handle.needs_restart(&config), Some("listen uri"));
+    assert!(handle.reload(config).is_err());
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_control_socket_lists_and_kicks_peers() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38869/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38870/udp").unwrap();
+    let control_socket =
+        std::env::temp_dir().join(format!("forwarder-test-{}.sock", std::process::id()));
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .control_socket(&control_socket)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+    let command = |command: &str| {
+        let mut stream = UnixStream::connect(&control_socket).unwrap();
+        writeln!(stream, "{command}").unwrap();
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).unwrap();
+        response
+    };
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    client.send(b"hello").unwrap();
+    let mut buffer = [0u8; 100];
+    let (_, peer_addr) = remote.recv_from(&mut buffer).unwrap();
+    remote.send_to(b"hi", peer_addr).unwrap();
+    client.recv(&mut buffer).unwrap();
+
+    let client_addr = client.local_addr().unwrap();
+    let bytes = r#""bytes":{"client_to_remote":5,"remote_to_client":2}"#;
+    // counters are updated right after packets are sent, so they may lag behind a bit
+    let start = Instant::now();
+    let peers = loop {
+        let peers = command("peers");
+        if peers.contains(bytes) || start.elapsed() > Duration::from_secs(1) {
+            break peers;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert!(peers.starts_with(&format!(
+        r#"{{"ok":true,"peers":[{{"client_addr":"{client_addr}","proxy_addr":null,"local_port":{},"#,
+        peer_addr.port()
+    )));
+    assert!(peers.contains(bytes));
+
+    assert_eq!(command(&format!("kick {client_addr}")), "{\"ok\":true}\n");
+    assert_eq!(command("peers"), "{\"ok\":true,\"peers\":[]}\n");
+    assert!(command(&format!("kick {client_addr}")).starts_with(r#"{"ok":false,"error":"#));
+    assert_eq!(
+        command("cleanup"),
+        "{\"ok\":true,\"cleaned\":0,\"remaining\":0}\n"
+    );
+    assert!(command("unknown").starts_with(r#"{"ok":false,"error":"invalid command"#));
+
+    handle.shutdown();
+    handle.join().unwrap();
+    assert!(!control_socket.exists());
+}
+
+#[test]
+fn test_peer_stats_count_traffic_of_each_client() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38871/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38872/udp").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+    assert!(handle.peers().is_empty());
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    let mut buffer = [0u8; 100];
+    client.send(b"hello").unwrap();
+    client.send(b"again").unwrap();
+    let (_, peer_addr) = remote.recv_from(&mut buffer).unwrap();
+    remote.recv_from(&mut buffer).unwrap();
+    remote.send_to(b"hi", peer_addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    client.recv(&mut buffer).unwrap();
+
+    // counters are updated right after packets are sent, so they may lag behind a bit
+    let start = Instant::now();
+    let peers = loop {
+        let peers = handle.peers();
+        let counted = peers.first().is_some_and(|stats| {
+            stats.packets(Direction::ClientToRemote) == 2
+                && stats.packets(Direction::RemoteToClient) == 1
+        });
+        if counted || start.elapsed() > Duration::from_secs(1) {
+            break peers;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(peers.len(), 1);
+    let stats = &peers[0];
+    assert_eq!(stats.client_addr(), client.local_addr().unwrap());
+    assert_eq!(stats.local_port(), peer_addr.port());
+    assert_eq!(stats.remote_index(), 0);
+    assert_eq!(stats.packets(Direction::ClientToRemote), 2);
+    assert_eq!(stats.bytes(Direction::ClientToRemote), 10);
+    assert_eq!(stats.packets(Direction::RemoteToClient), 1);
+    assert_eq!(stats.bytes(Direction::RemoteToClient), 2);
+    assert_eq!(stats.send_errors(Direction::ClientToRemote), 0);
+    assert_eq!(stats.dropped_packets(Direction::RemoteToClient), 0);
+    assert!(stats.idle() <= stats.client_idle() && stats.client_idle() <= stats.age());
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_proxy_protocol_carries_client_address_through_forwarders() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38873/udp").unwrap();
+    let second_forwarder_uri = Uri::from_str("127.0.0.1:38874/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38875/udp").unwrap();
+    let xor = || Box::new(forwarder::encryption::Xor::new("some_password"));
+    let config = ForwarderConfig::builder(forwarder_uri, second_forwarder_uri)
+        .transform(Side::Remote, xor())
+        .proxy_protocol(Side::Remote)
+        .build()
+        .unwrap();
+    let first_handle = forwarder::start(config).unwrap();
+    // second forwarder passes on the client that first one saw
+    let config = ForwarderConfig::builder(second_forwarder_uri, remote_uri)
+        .transform(Side::Listen, xor())
+        .proxy_protocol(Side::Listen)
+        .proxy_protocol(Side::Remote)
+        .build()
+        .unwrap();
+    let second_handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    client.send(b"hello").unwrap();
+    let mut buffer = [0u8; 100];
+    let (size, peer_addr) = remote.recv_from(&mut buffer).unwrap();
+    // ipv4 header is 28 bytes, addresses start after the 16 fixed bytes
+    assert_eq!(size, 28 + 5);
+    assert_eq!(buffer[..12], *b"\r\n\r\n\0\r\nQUIT\n");
+    assert_eq!(buffer[12..16], [0x21, 0x12, 0, 12]);
+    let client_addr = client.local_addr().unwrap();
+    let source_port = u16::from_be_bytes([buffer[24], buffer[25]]);
+    let destination_port = u16::from_be_bytes([buffer[26], buffer[27]]);
+    assert_eq!(buffer[16..20], [127, 0, 0, 1]);
+    assert_eq!(source_port, client_addr.port());
+    assert_eq!(destination_port, forwarder_uri.addr.port());
+    assert_eq!(&buffer[28..size], b"hello");
+
+    // replies don't have any header
+    remote.send_to(b"hi", peer_addr).unwrap();
+    let size = client.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"hi");
+
+    // second forwarder knows the original client and that first forwarder proxied it
+    let peers = second_handle.peers();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].client_addr(), client_addr);
+    let first_peer_port = first_handle.peers()[0].local_port();
+    assert_eq!(
+        peers[0].proxy_addr().map(|addr| addr.port()),
+        Some(first_peer_port)
+    );
+
+    // packets without header don't reach remote
+    let another_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut packet = b"hello".to_vec();
+    forwarder::encryption::Xor::new("some_password").encode(&mut packet, 5);
+    another_client
+        .send_to(&packet, second_forwarder_uri.addr)
+        .unwrap();
+    assert!(remote.recv_from(&mut buffer).is_err());
+
+    for handle in [first_handle, second_handle] {
+        handle.shutdown();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn test_reload_applies_shorter_idle_timeout_right_away() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38876/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38877/udp").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .idle_timeout(Duration::from_secs(600))
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+    let _remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.send_to(b"hello", forwarder_uri.addr).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(handle.peers().len(), 1);
+
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .idle_timeout(Duration::from_secs(1))
+        .build()
+        .unwrap();
+    handle.reload(config).unwrap();
+    // cleanup thread doesn't wait for the old idle timeout
+    std::thread::sleep(Duration::from_millis(2500));
+    assert!(handle.peers().is_empty());
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_max_peers_drops_new_clients() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38823/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38824/udp").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .max_peers(1)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let mut buffer = [0u8; 100];
+    for (index, should_receive) in [true, false].into_iter().enumerate() {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&[index as u8], forwarder_uri.addr).unwrap();
+        assert_eq!(remote.recv(&mut buffer).is_ok(), should_receive);
+    }
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_clients_dropped_by_max_peers_keep_new_peer_tokens() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38885/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38886/udp").unwrap();
+    let control_socket = std::env::temp_dir().join(format!(
+        "forwarder-test-max-peers-{}.sock",
+        std::process::id()
+    ));
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .max_peers(1)
+        .new_peer_rate(Rate::new(1, 2))
+        .control_socket(&control_socket)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let mut buffer = [0u8; 100];
+    let first_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    first_client.send_to(b"first", forwarder_uri.addr).unwrap();
+    remote.recv(&mut buffer).unwrap();
+    let dropped_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for _ in 0..3 {
+        dropped_client
+            .send_to(b"dropped", forwarder_uri.addr)
+            .unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(50));
+
+    let mut stream = UnixStream::connect(&control_socket).unwrap();
+    writeln!(stream, "kick {}", first_client.local_addr().unwrap()).unwrap();
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).unwrap();
+    assert_eq!(response, "{\"ok\":true}\n");
+    // the second token of burst is still there for the next client
+    let next_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    next_client.send_to(b"next", forwarder_uri.addr).unwrap();
+    let size = remote.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"next");
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_max_peers_evicts_least_recently_used_peer() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38849/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38850/udp").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .max_peers(2)
+        .max_peers_policy(MaxPeersPolicy::EvictLru)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let clients: Vec<UdpSocket> = (0..3)
+        .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+        .collect();
+    let mut buffer = [0u8; 100];
+    let mut send_and_get_peer_addr = |client: &UdpSocket| {
+        client.send_to(b"hello", forwarder_uri.addr).unwrap();
+        let peer_addr = remote.recv_from(&mut buffer).unwrap().1;
+        // activity of peers is kept in milliseconds
+        std::thread::sleep(Duration::from_millis(10));
+        peer_addr
+    };
+
+    let first_peer_addr = send_and_get_peer_addr(&clients[0]);
+    send_and_get_peer_addr(&clients[1]);
+    assert_eq!(send_and_get_peer_addr(&clients[0]), first_peer_addr);
+    // second client is the least recently used one and gets evicted
+    send_and_get_peer_addr(&clients[2]);
+    assert_eq!(send_and_get_peer_addr(&clients[0]), first_peer_addr);
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_new_peer_rate_drops_new_clients() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38851/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38852/udp").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .new_peer_rate(Rate::new(1, 1))
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let first_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let second_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut buffer = [0u8; 100];
+    first_client.send_to(b"first", forwarder_uri.addr).unwrap();
+    second_client
+        .send_to(b"second", forwarder_uri.addr)
+        .unwrap();
+    let size = remote.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"first");
+    assert!(remote.recv(&mut buffer).is_err());
+
+    // existing peers are not limited
+    first_client.send_to(b"again", forwarder_uri.addr).unwrap();
+    let size = remote.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"again");
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_traffic_limits_drop_exceeding_packets() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38853/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38854/udp").unwrap();
+    let packets_limit = |burst| TrafficLimit {
+        bytes: None,
+        packets: Some(Rate::new(1, burst)),
+    };
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .peer_limit(Direction::ClientToRemote, packets_limit(3))
+        .global_limit(Direction::RemoteToClient, packets_limit(2))
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    for index in 0..5u8 {
+        client.send(&[index]).unwrap();
+    }
+
+    let mut buffer = [0u8; 100];
+    let mut received = Vec::new();
+    while let Ok((size, from_addr)) = remote.recv_from(&mut buffer) {
+        received.extend_from_slice(&buffer[..size]);
+        remote.send_to(&buffer[..size], from_addr).unwrap();
+    }
+    assert_eq!(received, [0, 1, 2]);
+    let mut replies = Vec::new();
+    while let Ok(size) = client.recv(&mut buffer) {
+        replies.extend_from_slice(&buffer[..size]);
+    }
+    assert_eq!(replies, [0, 1]);
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_idle_peer_gets_cleaned() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38825/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38826/udp").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .idle_timeout(Duration::from_secs(1))
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    let mut buffer = [0u8; 100];
+    let mut send_and_get_peer_addr = || {
+        client.send("hello".as_bytes()).unwrap();
+        remote.recv_from(&mut buffer).unwrap().1
+    };
+
+    let first_peer_addr = send_and_get_peer_addr();
+    assert_eq!(send_and_get_peer_addr(), first_peer_addr);
+    std::thread::sleep(Duration::from_millis(2500));
+    // peer got cleaned so a new peer with new port should be created
+    assert_ne!(send_and_get_peer_addr(), first_peer_addr);
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_failover_skips_remote_that_stopped_replying() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38836/udp").unwrap();
+    let primary_uri = Uri::from_str("127.0.0.1:38837/udp").unwrap();
+    let backup_uri = Uri::from_str("127.0.0.1:38838/udp").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, primary_uri)
+        .remote(backup_uri)
+        .remote_health_timeout(Duration::from_millis(300))
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let bind_remote = |addr| {
+        let remote = UdpSocket::bind(addr).unwrap();
+        remote
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        remote
+    };
+    let (primary, backup) = (bind_remote(primary_uri.addr), bind_remote(backup_uri.addr));
+    let mut buffer = [0u8; 100];
+    let first_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    first_client
+        .send_to("hello".as_bytes(), forwarder_uri.addr)
+        .unwrap();
+    assert!(primary.recv(&mut buffer).is_ok());
+
+    // primary never replies so it becomes unhealthy
+    std::thread::sleep(Duration::from_millis(500));
+    let second_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    second_client
+        .send_to("hello".as_bytes(), forwarder_uri.addr)
+        .unwrap();
+    assert!(backup.recv(&mut buffer).is_ok());
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_metrics_endpoint_counts_traffic() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38839/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38840/udp").unwrap();
+    let metrics_addr = SocketAddr::from_str("127.0.0.1:38841").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .metrics_addr(metrics_addr)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    client
+        .send_to("hello".as_bytes(), forwarder_uri.addr)
+        .unwrap();
+    let mut buffer = [0u8; 100];
+    let (_, peer_addr) = remote.recv_from(&mut buffer).unwrap();
+    remote.send_to("hi".as_bytes(), peer_addr).unwrap();
+    client.recv(&mut buffer).unwrap();
+
+    let mut stream = TcpStream::connect(metrics_addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("forwarder_packets_total{direction=\"client_to_remote\"} 1\n"));
+    assert!(response.contains("forwarder_bytes_total{direction=\"client_to_remote\"} 5\n"));
+    assert!(response.contains("forwarder_bytes_total{direction=\"remote_to_client\"} 2\n"));
+    assert!(response.contains("forwarder_active_peers 1\n"));
+    assert!(response.contains("forwarder_peers_created_total 1\n"));
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_workers_serve_many_clients() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38842/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38843/udp").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .workers(4)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    // echo server
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 100];
+        while let Ok((size, from_addr)) = remote.recv_from(&mut buffer) {
+            remote.send_to(&buffer[..size], from_addr).unwrap();
+        }
+    });
+
+    let clients: Vec<UdpSocket> = (0..16)
+        .map(|_| {
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            client.connect(forwarder_uri.addr).unwrap();
+            client
+        })
+        .collect();
+    for (index, client) in clients.iter().enumerate() {
+        client.send(&[index as u8]).unwrap();
+    }
+    for (index, client) in clients.iter().enumerate() {
+        let mut buffer = [0u8; 100];
+        let size = client.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &[index as u8]);
+    }
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_udp_offload_double_forwarder_back_and_forth() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38844/udp").unwrap();
+    let second_forwarder_uri = Uri::from_str("127.0.0.1:38845/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38846/udp").unwrap();
+    let socket_options = SocketOptions {
+        udp_offload: true,
+        ..Default::default()
+    };
+    for (listen_uri, remote_uri) in [
+        (forwarder_uri, second_forwarder_uri),
+        (second_forwarder_uri, remote_uri),
+    ] {
+        let config = ForwarderConfig::builder(listen_uri, remote_uri)
+            .socket_options(socket_options)
+            .build()
+            .unwrap();
+        forwarder::start(config).unwrap();
+    }
+
+    // echo server
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 1500];
+        while let Ok((size, from_addr)) = remote.recv_from(&mut buffer) {
+            remote.send_to(&buffer[..size], from_addr).unwrap();
+        }
+    });
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    // same sized packets in a burst are the ones that kernel coalesces
+    for index in 0..20u8 {
+        client.send(&[index; 1000]).unwrap();
+    }
+    let mut buffer = [0u8; 1500];
+    for index in 0..20u8 {
+        let size = client.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &[index; 1000]);
+    }
+}
+
+#[test]
+fn test_denied_clients_are_dropped() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38847/udp").unwrap();
+    let remote_uri = Uri::from_str("127.0.0.1:38848/udp").unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .allow(Cidr::from_str("127.0.0.0/8").unwrap())
+        .deny(Cidr::from_str("127.0.0.2").unwrap())
+        .log_denied(true)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let remote = UdpSocket::bind(remote_uri.addr).unwrap();
+    remote
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let denied_client = UdpSocket::bind("127.0.0.2:0").unwrap();
+    denied_client
+        .send_to(b"denied", forwarder_uri.addr)
+        .unwrap();
+    let allowed_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    allowed_client
+        .send_to(b"allowed", forwarder_uri.addr)
+        .unwrap();
+
+    let mut buffer = [0u8; 100];
+    let (size, _) = remote.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"allowed");
+    assert!(
+        remote.recv_from(&mut buffer).is_err(),
+        "packet of denied client got forwarded"
+    );
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
+/// resolves every hostname to the address that it holds
+#[derive(Debug)]
+struct StubResolver(Mutex<SocketAddr>);
+
+impl Resolver for StubResolver {
+    fn resolve(&self, _host: &str, _port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        Ok(vec![*self.0.lock().unwrap()])
+    }
+}
+
+#[test]
+fn test_remote_hostname_gets_re_resolved() {
+    let forwarder_uri = Uri::from_str("127.0.0.1:38833/udp").unwrap();
+    let old_remote_addr = SocketAddr::from_str("127.0.0.1:38834").unwrap();
+    let new_remote_addr = SocketAddr::from_str("127.0.0.1:38835").unwrap();
+    let resolver = Arc::new(StubResolver(Mutex::new(old_remote_addr)));
+    let remote_uri = Uri::parse("remote.test:38834/udp", resolver.as_ref()).unwrap();
+    let config = ForwarderConfig::builder(forwarder_uri, remote_uri)
+        .resolver(resolver.clone())
+        .resolve_interval(Duration::from_millis(100))
+        .reconnect_peers(true)
+        .build()
+        .unwrap();
+    let handle = forwarder::start(config).unwrap();
+
+    let bind_remote = |addr| {
+        let remote = UdpSocket::bind(addr).unwrap();
+        remote
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        remote
+    };
+    let (old_remote, new_remote) = (bind_remote(old_remote_addr), bind_remote(new_remote_addr));
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(forwarder_uri.addr).unwrap();
+    let mut buffer = [0u8; 100];
+
+    client.send("hello".as_bytes()).unwrap();
+    assert!(old_remote.recv(&mut buffer).is_ok());
+
+    *resolver.0.lock().unwrap() = new_remote_addr;
+    std::thread::sleep(Duration::from_millis(500));
+    // existing peer of client should be moved to the new address
+    client.send("hello".as_bytes()).unwrap();
+    assert!(new_remote.recv(&mut buffer).is_ok());
+
+    handle.shutdown();
+    handle.join().unwrap();
+}
+
 fn spawn_double_forwarder_and_test_connection(
     forwarder_uri: Uri,
     second_forwarder_uri: Uri,